@@ -13,6 +13,10 @@ pub(crate) enum Commands {
     ///
     /// terraform will be invoked in the specified workspace's directory with the remaining arguments passed as-is,
     Terraform(Terraform),
+    /// commands for managing the terraform state stored on a running http server
+    State(State),
+    /// print the hash of a password or API token read from stdin, for use in a credentials file
+    HashCredential(HashCredential),
 }
 
 #[derive(clap::Args, Debug)]
@@ -28,11 +32,96 @@ pub(crate) struct Server {
 #[derive(clap::Subcommand, Debug)]
 pub(crate) enum ServerCommands {
     /// start the server in http mode
-    Http,
+    Http(Box<Http>),
     /// start the server in ssh mode
     Ssh,
 }
 
+#[derive(clap::Args, Debug)]
+pub(crate) struct Http {
+    /// where terraform state is stored
+    #[arg(long, value_enum, default_value_t = StateProvider::File)]
+    pub state_provider: StateProvider,
+    /// the bare repository to store terraform state in, relative to the data directory.
+    ///
+    /// required when using the git state provider, e.g. `owner/repo`
+    #[arg(long, required_if_eq("state_provider", "git"))]
+    pub state_repository: Option<std::path::PathBuf>,
+    /// the sqlite database to store terraform state in when using the sqlite state provider,
+    /// relative to the data directory
+    #[cfg(feature = "sqlite")]
+    #[arg(long, default_value = "tf-state.sqlite3")]
+    pub state_database: std::path::PathBuf,
+    /// the bucket to store terraform state in when using the s3 state provider.
+    ///
+    /// credentials are read from the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` environment variables
+    #[cfg(feature = "s3")]
+    #[arg(long, required_if_eq("state_provider", "s3"))]
+    pub s3_bucket: Option<String>,
+    /// the url of an S3-compatible service to use instead of AWS, e.g. `http://localhost:9000` for minio
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    pub s3_endpoint: Option<String>,
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    pub s3_region: Option<String>,
+    /// the path inside of the bucket to store states under
+    #[cfg(feature = "s3")]
+    #[arg(long)]
+    pub s3_prefix: Option<String>,
+    /// only allow terraform states whose id matches one of these glob patterns, e.g. `prod-*`.
+    ///
+    /// all ids are allowed when no pattern is given
+    #[arg(long = "allowed-state", value_name = "PATTERN")]
+    pub allowed_states: Vec<String>,
+    /// release state locks automatically after they have been held for this many seconds
+    #[arg(long)]
+    pub lock_ttl: Option<u64>,
+    /// the bearer token required to use the `/admin` routes. they are disabled when unset
+    #[arg(long, env = "THOENIX_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// a file of keys used to encrypt terraform state at rest, one `<key id> <base64 key>` per line.
+    ///
+    /// the first key encrypts new data, the others are only used to decrypt existing data
+    #[arg(long, conflicts_with = "state_keys")]
+    pub state_key_file: Option<std::path::PathBuf>,
+    /// the encryption keys given inline, in the same format as `--state-key-file`
+    #[arg(long, env = "THOENIX_STATE_KEYS", hide_env_values = true)]
+    pub state_keys: Option<String>,
+    /// a file of users allowed to use the terraform routes, one `<name> password <argon2 hash>`
+    /// or `<name> token <sha256 hex>` per line, see `thoenix hash-credential`.
+    ///
+    /// the terraform routes are open to anyone when unset
+    #[arg(long)]
+    pub credentials_file: Option<std::path::PathBuf>,
+    /// a JSON file of rules granting users `read`, `write`, `lock` and `unlock` on states, e.g.
+    /// `{"rules": [{"users": ["dashboard"], "states": ["*"], "permissions": ["read"]}]}`.
+    ///
+    /// anything not granted is denied. every user may do anything when unset
+    #[arg(long)]
+    pub policy_file: Option<std::path::PathBuf>,
+    /// reject git pushes that send more than this many bytes. pushes of any size are accepted
+    /// when unset
+    #[arg(long, value_name = "BYTES")]
+    pub max_push_size: Option<u64>,
+}
+
+#[derive(clap::ValueEnum, Clone, Debug)]
+pub(crate) enum StateProvider {
+    /// keep state in memory, it will be lost when the server stops
+    Memory,
+    /// store state as files inside of the data directory
+    File,
+    /// store state as commits inside of a bare git repository
+    Git,
+    /// store state in a sqlite database inside of the data directory
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    /// store state in an S3-compatible bucket
+    #[cfg(feature = "s3")]
+    S3,
+}
+
 #[derive(clap::Args, Debug)]
 pub(crate) struct Terraform {
     #[arg()]
@@ -45,3 +134,98 @@ pub(crate) struct Terraform {
     #[arg(long, short, default_value = "tofu")]
     pub command: String,
 }
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct State {
+    #[clap(subcommand)]
+    pub command: StateCommands,
+
+    /// the url of the thoenix http server
+    #[arg(long, env = "THOENIX_URL", default_value = "http://localhost:3000")]
+    pub url: String,
+    /// the token configured with `--admin-token` on the server
+    #[arg(long, env = "THOENIX_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// the user to authenticate as when the server requires credentials
+    #[arg(long, env = "THOENIX_USERNAME")]
+    pub username: Option<String>,
+    /// the password or API token of `--username`
+    #[arg(
+        long,
+        env = "THOENIX_PASSWORD",
+        hide_env_values = true,
+        requires = "username"
+    )]
+    pub password: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct HashCredential {
+    /// hash an API token instead of a password
+    #[arg(long)]
+    pub token: bool,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub(crate) enum StateCommands {
+    /// list every state that is currently locked and who holds the lock
+    Locks,
+    /// release the lock on a state regardless of who holds it
+    ForceUnlock {
+        /// the id of the state to unlock
+        id: String,
+        /// who is releasing the lock, recorded in the server's audit log. defaults to `$USER`
+        #[arg(long)]
+        who: Option<String>,
+    },
+    /// re-encrypt every state with the server's primary encryption key.
+    ///
+    /// older versions keep the key they were written with, so the keys still in use are printed
+    RotateKeys,
+    /// print the root module outputs of a state.
+    ///
+    /// sensitive outputs are only available to users granted the `sensitive` permission, or with
+    /// the admin token
+    Output {
+        /// the id of the state
+        id: String,
+        /// only print this output. strings are printed without quotes
+        name: Option<String>,
+        /// print the outputs as json, including their types
+        #[arg(long)]
+        json: bool,
+    },
+    /// show the resources and outputs that changed between two versions of a state.
+    ///
+    /// sensitive values are masked
+    Diff {
+        /// the id of the state
+        id: String,
+        /// the older version
+        from: u64,
+        /// the newer version
+        to: u64,
+        /// print the diff as json
+        #[arg(long)]
+        json: bool,
+    },
+    /// copy every state from one state provider to another.
+    ///
+    /// this works on the storage directly rather than through the http server. providers are
+    /// given as `file:<dir>`, `git:<repo>`, `sqlite:<db>` or `s3:<bucket>[/<prefix>]`.
+    /// locked states and states that already exist in the destination are skipped
+    Migrate {
+        /// the provider to copy states from
+        #[arg(long)]
+        from: crate::provider::ProviderConfig,
+        /// the provider to copy states to
+        #[arg(long)]
+        to: crate::provider::ProviderConfig,
+        /// copy every version of each state instead of only the current one
+        #[arg(long)]
+        history: bool,
+        /// only report what would be copied
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
@@ -0,0 +1,21 @@
+use crate::{commands::HashCredential, error::AppResult};
+use thoenix_http::auth::{hash_password, hash_token};
+use tokio::io::AsyncBufReadExt;
+
+impl HashCredential {
+    pub async fn run(self) -> AppResult<()> {
+        let mut secret = String::new();
+        tokio::io::BufReader::new(tokio::io::stdin())
+            .read_line(&mut secret)
+            .await?;
+        let secret = secret.trim_end_matches(['\r', '\n']);
+
+        if self.token {
+            println!("token {}", hash_token(secret));
+        } else {
+            println!("password {}", hash_password(secret)?);
+        }
+
+        Ok(())
+    }
+}
@@ -17,17 +17,29 @@ pub enum AppError {
     #[error(transparent)]
     Utf8(#[from] std::str::Utf8Error),
     #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
     ProjectBaseDirectory(#[from] project_base_directory::error::Error),
 
     #[error(transparent)]
     SshError(#[from] thoenix_ssh::error::Error),
     #[error(transparent)]
     HttpError(#[from] thoenix_http::error::Error),
+    #[error(transparent)]
+    TofuError(#[from] thoenix_tofu::error::Error),
 
     #[error("terraform error: {0}")]
     TerraformError(i32),
     #[error("failed to execute nix: {0}")]
     Nix(i32),
+    #[error("invalid state provider {0}, expected `memory`, `file:<dir>`, `git:<repo>`, `sqlite:<db>` or `s3:<bucket>[/<prefix>]`")]
+    InvalidProviderConfig(String),
+    #[error("failed to migrate {0} states")]
+    MigrationFailed(usize),
+    #[error("server responded with {0}: {1}")]
+    Server(reqwest::StatusCode, String),
 }
 
 pub type AppResult<T> = Result<T, AppError>;
@@ -3,8 +3,11 @@ use std::error::Error;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod commands;
+mod credentials;
 mod error;
+mod provider;
 mod server;
+mod state;
 mod terraform;
 
 use commands::{Commands, ServerCommands};
@@ -16,7 +19,8 @@ async fn main() -> std::result::Result<(), Box<dyn Error>> {
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                "thoenix_http=debug,tower_http=debug,axum::rejection=trace".into()
+                "thoenix_http=debug,thoenix_tofu=debug,tower_http=debug,axum::rejection=trace"
+                    .into()
             }),
         )
         .with(tracing_subscriber::fmt::layer())
@@ -30,10 +34,12 @@ async fn main() -> std::result::Result<(), Box<dyn Error>> {
             let server = Server::new(server.data_dir);
 
             match cmd {
-                ServerCommands::Http => server.http_server().await?,
+                ServerCommands::Http(args) => server.http_server(*args).await?,
                 ServerCommands::Ssh => server.ssh_server().await?,
             }
         }
+        Commands::State(state) => state.run().await?,
+        Commands::HashCredential(hash) => hash.run().await?,
         Commands::Terraform(terraform) => {
             let mut terraform = terraform.spawn_command().await?;
             let status = terraform.wait().await?;
@@ -0,0 +1,83 @@
+use crate::error::{AppError, AppResult};
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+use thoenix_tofu::{FileState, GitState, InMemoryState, TerraformStateProvider};
+
+/// A state provider given on the command line as `<kind>:<location>`, e.g. `file:/var/lib/thoenix/tf-state`
+#[derive(Clone, Debug)]
+pub(crate) enum ProviderConfig {
+    Memory,
+    /// The directory holding the states
+    File(PathBuf),
+    /// The bare repository holding the states
+    Git(PathBuf),
+    /// The database file
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+    /// The bucket, optionally followed by `/<prefix>`.
+    /// The endpoint, region and credentials are read from the `AWS_*` environment variables
+    #[cfg(feature = "s3")]
+    S3 {
+        bucket: String,
+        prefix: Option<String>,
+    },
+}
+
+impl ProviderConfig {
+    pub(crate) fn open(self) -> thoenix_tofu::error::Result<Arc<dyn TerraformStateProvider>> {
+        let provider: Arc<dyn TerraformStateProvider> = match self {
+            ProviderConfig::Memory => Arc::new(InMemoryState::new()),
+            ProviderConfig::File(path) => Arc::new(FileState::new(path)),
+            ProviderConfig::Git(path) => Arc::new(GitState::new(path)),
+            #[cfg(feature = "sqlite")]
+            ProviderConfig::Sqlite(path) => Arc::new(thoenix_tofu::SqliteState::open(path)?),
+            #[cfg(feature = "s3")]
+            ProviderConfig::S3 { bucket, prefix } => {
+                let config = thoenix_tofu::s3::S3Config {
+                    bucket,
+                    prefix,
+                    ..Default::default()
+                };
+                Arc::new(thoenix_tofu::S3State::connect(config)?)
+            }
+        };
+
+        Ok(provider)
+    }
+}
+
+impl FromStr for ProviderConfig {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> AppResult<Self> {
+        let (kind, location) = s.split_once(':').unwrap_or((s, ""));
+        let invalid = || AppError::InvalidProviderConfig(s.to_string());
+
+        if kind == "memory" {
+            return Ok(ProviderConfig::Memory);
+        }
+        if location.is_empty() {
+            return Err(invalid());
+        }
+
+        match kind {
+            "file" => Ok(ProviderConfig::File(location.into())),
+            "git" => Ok(ProviderConfig::Git(location.into())),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(ProviderConfig::Sqlite(location.into())),
+            #[cfg(feature = "s3")]
+            "s3" => {
+                let location = location.trim_start_matches("//");
+                let (bucket, prefix) = match location.split_once('/') {
+                    Some((bucket, prefix)) => (bucket, Some(prefix.to_string())),
+                    None => (location, None),
+                };
+
+                Ok(ProviderConfig::S3 {
+                    bucket: bucket.to_string(),
+                    prefix,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use crate::error::AppResult;
+use crate::{
+    commands::{Http, StateProvider},
+    error::AppResult,
+};
 use russh::server::Server as RusshServer;
 use russh_keys::PublicKeyBase64;
 use std::{path::PathBuf, sync::Arc};
-use tracing::info;
+use thoenix_tofu::{FileState, GitState, InMemoryState, Keyring, TerraformStateProvider};
+use tracing::{info, warn};
 
 pub(crate) struct Server {
     data_dir: PathBuf,
@@ -48,8 +52,78 @@ impl Server {
         Ok(())
     }
 
-    pub(crate) async fn http_server(self) -> AppResult<()> {
-        let server = thoenix_http::Server::new(self.data_dir);
+    pub(crate) async fn http_server(self, args: Http) -> AppResult<()> {
+        let lock_ttl = args.lock_ttl.map(std::time::Duration::from_secs);
+        let tf_state: Arc<dyn TerraformStateProvider> = match args.state_provider {
+            StateProvider::Memory => Arc::new(InMemoryState::new().with_lock_ttl(lock_ttl)),
+            StateProvider::File => {
+                Arc::new(FileState::new(self.data_dir.join("tf-state")).with_lock_ttl(lock_ttl))
+            }
+            StateProvider::Git => {
+                let repository = args
+                    .state_repository
+                    .expect("clap requires a repository for the git provider");
+                Arc::new(GitState::new(self.data_dir.join(repository)).with_lock_ttl(lock_ttl))
+            }
+            #[cfg(feature = "sqlite")]
+            StateProvider::Sqlite => Arc::new(
+                thoenix_tofu::SqliteState::open(self.data_dir.join(args.state_database))?
+                    .with_lock_ttl(lock_ttl),
+            ),
+            #[cfg(feature = "s3")]
+            StateProvider::S3 => {
+                let config = thoenix_tofu::s3::S3Config {
+                    bucket: args
+                        .s3_bucket
+                        .expect("clap requires a bucket for the s3 provider"),
+                    endpoint: args.s3_endpoint,
+                    region: args.s3_region,
+                    prefix: args.s3_prefix,
+                };
+                Arc::new(thoenix_tofu::S3State::connect(config)?.with_lock_ttl(lock_ttl))
+            }
+        };
+        info!(provider = ?args.state_provider, "using terraform state provider");
+
+        let state_keys = match (args.state_key_file, args.state_keys) {
+            (Some(path), _) => Some(Keyring::from_file(&path).await?),
+            (None, Some(keys)) => Some(Keyring::parse(&keys)?),
+            (None, None) => None,
+        };
+        if let Some(keys) = &state_keys {
+            info!(
+                primary = keys.primary(),
+                "encrypting terraform state at rest"
+            );
+        }
+
+        let state_ids = thoenix_http::state_ids::StateIdFilter::new(&args.allowed_states)?;
+
+        let credentials = match args.credentials_file {
+            Some(path) => Some(thoenix_http::auth::Credentials::from_file(&path).await?),
+            None => None,
+        };
+        match &credentials {
+            Some(credentials) => info!(?credentials, "requiring credentials for terraform routes"),
+            None => warn!("terraform routes are open to anyone, see --credentials-file"),
+        }
+
+        let policy = match args.policy_file {
+            Some(path) => {
+                info!(?path, "restricting access to terraform states");
+                thoenix_http::policy::Policy::from_file(&path).await?
+            }
+            None => thoenix_http::policy::Policy::default(),
+        };
+
+        let server = thoenix_http::Server::new(self.data_dir)
+            .with_state_provider(tf_state)
+            .with_state_ids(state_ids)
+            .with_admin_token(args.admin_token)
+            .with_state_keys(state_keys)
+            .with_credentials(credentials)
+            .with_policy(policy)
+            .with_max_push_size(args.max_push_size);
 
         let port = std::env::var("PORT")
             .unwrap_or_else(|_| "3000".to_string())
@@ -0,0 +1,229 @@
+use crate::{
+    commands::{State, StateCommands},
+    error::{AppError, AppResult},
+};
+use std::collections::BTreeMap;
+use thoenix_http::audit::ForceUnlock;
+use thoenix_tofu::{
+    diff::{StateDiff, ValueChange, SENSITIVE_VALUE},
+    encryption::KeyRotation,
+    migration::{FailedState, MigratedState, MigrationOptions},
+    outputs::StateOutput,
+    LockedState,
+};
+
+impl State {
+    pub async fn run(self) -> AppResult<()> {
+        let credentials = self.username.zip(self.password);
+        let client = StateClient::new(self.url, self.admin_token, credentials);
+
+        match self.command {
+            StateCommands::Locks => {
+                let locks: Vec<LockedState> = client.get_json("/admin/tf/locks").await?;
+
+                for LockedState { id, lock } in locks {
+                    let acquired = lock.acquired.map(|c| c.to_rfc3339()).unwrap_or_default();
+                    println!(
+                        "{id}\t{}\t{}\t{}\t{acquired}",
+                        lock.who, lock.operation, lock.id
+                    );
+                }
+            }
+            StateCommands::ForceUnlock { id, who } => {
+                let who = who
+                    .or_else(|| std::env::var("USER").ok())
+                    .unwrap_or_else(|| "unknown".to_string());
+
+                let request = client
+                    .request(reqwest::Method::DELETE, &format!("/admin/tf/lock/{id}"))
+                    .json(&serde_json::json!({ "who": who }));
+                let record: ForceUnlock = StateClient::send_json(request).await?;
+
+                println!(
+                    "released lock {} held by {} on {} ({})",
+                    record.lock.id, record.lock.who, record.state, record.lock.operation
+                );
+            }
+            StateCommands::RotateKeys => {
+                let request = client.request(reqwest::Method::POST, "/admin/tf/rotate-keys");
+                let rotation: KeyRotation = StateClient::send_json(request).await?;
+
+                for id in &rotation.rotated {
+                    println!("re-encrypted {id}");
+                }
+                for id in &rotation.locked {
+                    eprintln!("skipped {id}, it is locked");
+                }
+                for (key_id, count) in &rotation.usage.keys {
+                    println!("key {key_id} is used by {count} states and versions");
+                }
+                if rotation.usage.plaintext > 0 {
+                    eprintln!(
+                        "{} states and versions are stored unencrypted",
+                        rotation.usage.plaintext
+                    );
+                }
+            }
+            StateCommands::Output { id, name, json } => match name {
+                Some(name) => {
+                    let output: StateOutput = client
+                        .get_json(&format!("/tf/state/{id}/outputs/{name}"))
+                        .await?;
+
+                    match output.value {
+                        serde_json::Value::String(value) if !json => println!("{value}"),
+                        _ if json => println!("{}", serde_json::to_string_pretty(&output)?),
+                        value => println!("{}", serde_json::to_string_pretty(&value)?),
+                    }
+                }
+                None => {
+                    let outputs: BTreeMap<String, StateOutput> =
+                        client.get_json(&format!("/tf/state/{id}/outputs")).await?;
+
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&outputs)?);
+                    } else {
+                        for (name, output) in outputs {
+                            println!("{name} = {}", output.value);
+                        }
+                    }
+                }
+            },
+            StateCommands::Diff { id, from, to, json } => {
+                let diff: StateDiff = client
+                    .get_json(&format!("/tf/state/{id}/diff/{from}/{to}"))
+                    .await?;
+
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&diff)?);
+                } else {
+                    print_diff(&diff);
+                }
+            }
+            StateCommands::Migrate {
+                from,
+                to,
+                history,
+                dry_run,
+            } => {
+                let options = MigrationOptions { history, dry_run };
+                let report =
+                    thoenix_tofu::migration::migrate(&*from.open()?, &*to.open()?, options).await?;
+
+                let action = if dry_run { "would copy" } else { "copied" };
+                for MigratedState { id, versions } in &report.migrated {
+                    println!("{action} {id} ({versions} versions)");
+                }
+                for id in &report.locked {
+                    eprintln!("skipped {id}, it is locked");
+                }
+                for id in &report.existing {
+                    eprintln!("skipped {id}, it already exists in the destination");
+                }
+                for FailedState { id, error } in &report.failed {
+                    eprintln!("failed to copy {id}: {error}");
+                }
+                if !report.failed.is_empty() {
+                    return Err(AppError::MigrationFailed(report.failed.len()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn print_diff(diff: &StateDiff) {
+    if diff.is_empty() {
+        println!("no changes");
+        return;
+    }
+
+    for resource in &diff.added {
+        println!("+ {}", resource.address);
+    }
+    for resource in &diff.removed {
+        println!("- {}", resource.address);
+    }
+    for resource in &diff.changed {
+        println!("~ {}", resource.address);
+        for change in &resource.attributes {
+            println!("    {}", format_change(change));
+        }
+    }
+    for change in &diff.outputs {
+        println!("~ output {}", format_change(change));
+    }
+}
+
+fn format_change(change: &ValueChange) -> String {
+    let format = |value: &Option<serde_json::Value>| match value {
+        Some(value) if value == SENSITIVE_VALUE => SENSITIVE_VALUE.to_string(),
+        Some(value) => value.to_string(),
+        None => "(none)".to_string(),
+    };
+
+    format!(
+        "{}: {} -> {}",
+        change.path,
+        format(&change.before),
+        format(&change.after)
+    )
+}
+
+/// A small client for the state routes of a thoenix http server
+pub(crate) struct StateClient {
+    client: reqwest::Client,
+    url: String,
+    admin_token: Option<String>,
+    /// The username and password used when there is no admin token
+    credentials: Option<(String, String)>,
+}
+
+impl StateClient {
+    pub(crate) fn new(
+        url: String,
+        admin_token: Option<String>,
+        credentials: Option<(String, String)>,
+    ) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            admin_token,
+            credentials,
+        }
+    }
+
+    pub(crate) fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, format!("{}{path}", self.url));
+
+        // the server accepts the admin token in place of credentials
+        match (&self.admin_token, &self.credentials) {
+            (Some(token), _) => request.bearer_auth(token),
+            (None, Some((username, password))) => request.basic_auth(username, Some(password)),
+            (None, None) => request,
+        }
+    }
+
+    pub(crate) async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> AppResult<T> {
+        Self::send_json(self.request(reqwest::Method::GET, path)).await
+    }
+
+    /// Send a request, turning unsuccessful responses into an error containing the response body
+    pub(crate) async fn send_json<T: serde::de::DeserializeOwned>(
+        request: reqwest::RequestBuilder,
+    ) -> AppResult<T> {
+        let response = request.send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(AppError::Server(status, body));
+        }
+
+        Ok(response.json().await?)
+    }
+}
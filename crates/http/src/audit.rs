@@ -0,0 +1,35 @@
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thoenix_tofu::TerraformLock;
+use tokio::io::AsyncWriteExt;
+
+/// The file inside of the data directory that administrative actions are appended to
+pub const AUDIT_LOG: &str = "audit.log";
+
+/// A lock that was released by an administrator instead of the client that held it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForceUnlock {
+    pub state: String,
+    pub lock: TerraformLock,
+    /// The administrator that released the lock
+    pub by: String,
+    pub at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Append a record to the audit log as a single line of JSON
+pub(crate) async fn record<T: Serialize>(data_dir: &Path, record: &T) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    tokio::fs::create_dir_all(data_dir).await?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(data_dir.join(AUDIT_LOG))
+        .await?;
+    file.write_all(&line).await?;
+    file.sync_data().await?;
+
+    Ok(())
+}
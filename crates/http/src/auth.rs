@@ -0,0 +1,220 @@
+use crate::{
+    error::{Error, Result},
+    handlers::admin::{check_admin_token, constant_time_eq},
+    ServerState,
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+/// How a user proves who they are
+#[derive(Clone)]
+enum Secret {
+    /// An argon2 hash of a password in the PHC string format
+    Password(String),
+    /// The sha256 digest of an API token. Tokens are random enough that a fast hash is enough,
+    /// and they can be checked on every request without slowing terraform down.
+    Token([u8; 32]),
+}
+
+/// The users allowed to use the terraform routes.
+///
+/// Users are written one per line as `<name> password <argon2 hash>` or
+/// `<name> token <hex encoded sha256 of the token>`, see [`hash_password`] and [`hash_token`].
+/// Empty lines and lines starting with `#` are ignored.
+///
+/// Terraform's http backend sends `username` and `password` using basic authentication. Tokens
+/// can be used either as the password of their user or on their own as a bearer token.
+#[derive(Clone, Default)]
+pub struct Credentials {
+    users: HashMap<String, Secret>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = self.users.keys().collect::<Vec<_>>();
+        names.sort();
+
+        f.debug_struct("Credentials")
+            .field("users", &names)
+            .finish()
+    }
+}
+
+/// The user a request was authenticated as, available to handlers as a request extension
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Identity {
+    pub name: String,
+    /// Whether the request carried the admin token instead of a user's credentials
+    pub admin: bool,
+}
+
+impl Identity {
+    pub fn user(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            admin: false,
+        }
+    }
+
+    fn admin() -> Self {
+        Self {
+            name: "admin".to_string(),
+            admin: true,
+        }
+    }
+}
+
+impl Credentials {
+    pub fn parse(credentials: &str) -> Result<Self> {
+        let mut users = HashMap::new();
+
+        for (number, line) in credentials.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid =
+                |reason: &str| Error::InvalidCredentials(format!("line {}: {reason}", number + 1));
+
+            let mut parts = line.split_whitespace();
+            let (Some(name), Some(kind), Some(secret), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid("expected `<name> password|token <hash>`"));
+            };
+            if name.contains(':') {
+                return Err(invalid("names can't contain `:`"));
+            }
+
+            let secret = match kind {
+                "password" => {
+                    PasswordHash::new(secret).map_err(|e| invalid(&e.to_string()))?;
+                    Secret::Password(secret.to_string())
+                }
+                "token" => {
+                    let mut digest = [0; 32];
+                    hex::decode_to_slice(secret, &mut digest)
+                        .map_err(|_| invalid("expected a hex encoded sha256 digest"))?;
+                    Secret::Token(digest)
+                }
+                kind => return Err(invalid(&format!("unknown kind {kind}"))),
+            };
+            if users.insert(name.to_string(), secret).is_some() {
+                return Err(invalid(&format!("user {name} is defined twice")));
+            }
+        }
+
+        Ok(Self { users })
+    }
+
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let credentials = tokio::fs::read_to_string(path).await?;
+
+        Self::parse(&credentials)
+    }
+
+    /// Determine who sent a request from its `Authorization` header
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Identity> {
+        let authorization = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(Error::Unauthenticated)?;
+
+        if let Some(token) = authorization.strip_prefix("Bearer ") {
+            return self.authenticate_token(token);
+        }
+
+        let (name, password) = authorization
+            .strip_prefix("Basic ")
+            .and_then(|encoded| BASE64.decode(encoded).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let (name, password) = decoded.split_once(':')?;
+                Some((name.to_string(), password.to_string()))
+            })
+            .ok_or(Error::Unauthenticated)?;
+
+        let verified = match self.users.get(&name) {
+            Some(Secret::Password(hash)) => {
+                // parsing can't fail, the hash was validated when it was loaded
+                let hash = PasswordHash::new(hash).map_err(|_| Error::Unauthenticated)?;
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }
+            Some(Secret::Token(digest)) => constant_time_eq(&Sha256::digest(&password), digest),
+            None => false,
+        };
+        if !verified {
+            return Err(Error::Unauthenticated);
+        }
+
+        Ok(Identity::user(&name))
+    }
+
+    fn authenticate_token(&self, token: &str) -> Result<Identity> {
+        let presented = Sha256::digest(token);
+
+        self.users
+            .iter()
+            .find(|(_, secret)| {
+                matches!(secret, Secret::Token(digest) if constant_time_eq(&presented, digest))
+            })
+            .map(|(name, _)| Identity::user(name))
+            .ok_or(Error::Unauthenticated)
+    }
+}
+
+/// Hash a password for use in a credentials file
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| Error::InvalidCredentials(e.to_string()))?;
+
+    Ok(hash.to_string())
+}
+
+/// Hash an API token for use in a credentials file
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token))
+}
+
+/// Only allow requests from users in the configured credentials, or that carry the admin token,
+/// and record who made the request as an [`Identity`] extension.
+///
+/// Every request is allowed when no credentials are configured.
+pub(crate) async fn require_credentials<B>(
+    State(app_state): State<Arc<ServerState>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    let identity = if check_admin_token(&app_state, request.headers()).is_ok() {
+        Some(Identity::admin())
+    } else if let Some(credentials) = app_state.credentials.clone() {
+        // verifying a password is deliberately slow, so it is kept off of the async workers
+        let headers = request.headers().clone();
+        let identity = tokio::task::spawn_blocking(move || credentials.authenticate(&headers))
+            .await
+            .map_err(|e| Error::Io(e.into()))??;
+
+        Some(identity)
+    } else {
+        None
+    };
+    if let Some(identity) = identity {
+        request.extensions_mut().insert(identity);
+    }
+
+    Ok(next.run(request).await)
+}
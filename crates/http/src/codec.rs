@@ -2,12 +2,16 @@ use crate::error::{Error, Result};
 use bytes::{Buf, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-pub(crate) struct PktLineCodec;
+pub struct PktLineCodec;
 
-#[derive(Clone, Debug)]
-pub(crate) enum PktLineMessage {
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PktLineMessage {
     Data(Vec<u8>),
     Flush,
+    /// Separates the sections of a protocol v2 message, `0001`
+    Delim,
+    /// Marks the end of a protocol v2 response, `0002`
+    ResponseEnd,
 }
 
 impl Encoder<PktLineMessage> for PktLineCodec {
@@ -24,6 +28,12 @@ impl Encoder<PktLineMessage> for PktLineCodec {
             PktLineMessage::Flush => {
                 buf.extend_from_slice(b"0000");
             }
+            PktLineMessage::Delim => {
+                buf.extend_from_slice(b"0001");
+            }
+            PktLineMessage::ResponseEnd => {
+                buf.extend_from_slice(b"0002");
+            }
         }
 
         Ok(())
@@ -51,8 +61,18 @@ impl Decoder for PktLineCodec {
                 buf.advance(4);
                 Ok(Some(PktLineMessage::Flush))
             }
+            Ok(1) => {
+                buf.advance(4);
+                Ok(Some(PktLineMessage::Delim))
+            }
+            Ok(2) => {
+                buf.advance(4);
+                Ok(Some(PktLineMessage::ResponseEnd))
+            }
+            // the length includes the four bytes of the length itself
+            Ok(len) if len < 4 => Err(Error::ParseLengthBytes),
             Ok(len) => {
-                if buf.len() < len + 4 {
+                if buf.len() < len {
                     return Ok(None);
                 }
                 buf.advance(4);
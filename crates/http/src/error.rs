@@ -10,15 +10,53 @@ pub enum Error {
     Tofu(#[from] thoenix_tofu::error::Error),
     #[error(transparent)]
     Utf8(#[from] std::string::FromUtf8Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Pattern(#[from] glob::PatternError),
 
     #[error("Missing service")]
     MissingService,
+    #[error("unknown service {0}")]
+    UnknownService(String),
+    #[error("invalid git request: {0}")]
+    Protocol(String),
     #[error("Unable to parse length bytes")]
     ParseLengthBytes,
     #[error("not found")]
     NotFound,
+    #[error("invalid repository {0}")]
+    InvalidRepository(String),
+    #[error("repository {0} not found")]
+    RepositoryNotFound(String),
     #[error("state is locked")]
     StateLocked,
+    #[error("state id {0} is not allowed")]
+    StateIdNotAllowed(String),
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("missing or invalid credentials")]
+    Unauthenticated,
+    #[error("invalid credentials file: {0}")]
+    InvalidCredentials(String),
+    #[error("{user} may not {permission} state {state}")]
+    Forbidden {
+        user: String,
+        permission: crate::policy::Permission,
+        state: String,
+    },
+    #[error("admin routes are disabled, no admin token is configured")]
+    AdminDisabled,
+    #[error("output {0} is sensitive")]
+    SensitiveOutput(String),
+    #[error("state encryption is not configured")]
+    EncryptionDisabled,
+    #[error("body does not match the Content-MD5 header")]
+    ChecksumMismatch,
+    #[error("push exceeds the maximum size of {0} bytes")]
+    PushTooLarge(u64),
+    #[error("unsupported content encoding {0}")]
+    UnsupportedEncoding(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -29,13 +67,61 @@ impl axum::response::IntoResponse for Error {
             Error::Hyper(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
             Error::Io(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
             Error::Git(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            Error::Tofu(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Tofu(ref e) => match e {
+                thoenix_tofu::error::Error::NotFound
+                | thoenix_tofu::error::Error::VersionNotFound(_) => {
+                    axum::http::StatusCode::NOT_FOUND
+                }
+                thoenix_tofu::error::Error::StateLocked
+                | thoenix_tofu::error::Error::NotLocked
+                | thoenix_tofu::error::Error::LineageMismatch { .. }
+                | thoenix_tofu::error::Error::StaleSerial { .. } => {
+                    axum::http::StatusCode::CONFLICT
+                }
+                thoenix_tofu::error::Error::InvalidId(_)
+                | thoenix_tofu::error::Error::InvalidData(_) => axum::http::StatusCode::BAD_REQUEST,
+                // usually means the key a state was written with has been removed from the keyring
+                thoenix_tofu::error::Error::UnknownKey(_) => {
+                    axum::http::StatusCode::SERVICE_UNAVAILABLE
+                }
+                _ => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            },
             Error::Utf8(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Json(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Pattern(_) => axum::http::StatusCode::BAD_REQUEST,
 
             Error::MissingService => axum::http::StatusCode::BAD_REQUEST,
+            Error::UnknownService(_) => axum::http::StatusCode::FORBIDDEN,
+            Error::Protocol(_) => axum::http::StatusCode::BAD_REQUEST,
             Error::ParseLengthBytes => axum::http::StatusCode::BAD_REQUEST,
             Error::NotFound => axum::http::StatusCode::NOT_FOUND,
+            Error::InvalidRepository(_) => axum::http::StatusCode::BAD_REQUEST,
+            Error::RepositoryNotFound(_) => axum::http::StatusCode::NOT_FOUND,
             Error::StateLocked => axum::http::StatusCode::CONFLICT,
+            Error::StateIdNotAllowed(_) => axum::http::StatusCode::FORBIDDEN,
+            // the admin token can't be negotiated like credentials, so it is refused the same way
+            // as the disabled admin routes
+            Error::Unauthorized => axum::http::StatusCode::FORBIDDEN,
+            // lets terraform and browsers know to retry with basic authentication
+            Error::Unauthenticated => {
+                return (
+                    axum::http::StatusCode::UNAUTHORIZED,
+                    [(
+                        axum::http::header::WWW_AUTHENTICATE,
+                        r#"Basic realm="thoenix""#,
+                    )],
+                    self.to_string(),
+                )
+                    .into_response()
+            }
+            Error::InvalidCredentials(_) => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Forbidden { .. } => axum::http::StatusCode::FORBIDDEN,
+            Error::AdminDisabled => axum::http::StatusCode::FORBIDDEN,
+            Error::SensitiveOutput(_) => axum::http::StatusCode::FORBIDDEN,
+            Error::EncryptionDisabled => axum::http::StatusCode::BAD_REQUEST,
+            Error::ChecksumMismatch => axum::http::StatusCode::BAD_REQUEST,
+            Error::PushTooLarge(_) => axum::http::StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UnsupportedEncoding(_) => axum::http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
         };
 
         (status, self.to_string()).into_response()
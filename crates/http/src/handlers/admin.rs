@@ -0,0 +1,102 @@
+use crate::{
+    audit::{self, ForceUnlock},
+    error::{Error, Result},
+    ServerState,
+};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Only allow requests that present the configured admin token as a bearer token
+pub(crate) async fn require_admin<B>(
+    State(app_state): State<Arc<ServerState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    check_admin_token(&app_state, request.headers())?;
+
+    Ok(next.run(request).await)
+}
+
+/// Ensure that the request carries the configured admin token as a bearer token
+pub(crate) fn check_admin_token(app_state: &ServerState, headers: &HeaderMap) -> Result<()> {
+    let expected = app_state.admin_token.as_ref().ok_or(Error::AdminDisabled)?;
+
+    let token = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+
+    if !constant_time_eq(token.as_bytes(), expected.as_bytes()) {
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub async fn list_tf_locks(State(app_state): State<Arc<ServerState>>) -> Result<impl IntoResponse> {
+    info!("Received request to list tf state locks");
+
+    let state = &app_state.tf_state;
+    let locks = state.list_locks().await?;
+
+    Ok(Json(locks))
+}
+
+/// The administrator requesting a force unlock
+#[derive(Debug, serde::Deserialize)]
+pub struct ForceUnlockRequest {
+    pub who: String,
+}
+
+pub async fn force_unlock_tf_state(
+    Path(id): Path<String>,
+    State(app_state): State<Arc<ServerState>>,
+    Json(body): Json<ForceUnlockRequest>,
+) -> Result<impl IntoResponse> {
+    info!(?body, "Received request to force unlock tf state {}", id);
+
+    let state = &app_state.tf_state;
+    let lock = state.force_unlock_state(&id).await?;
+
+    let record = ForceUnlock {
+        state: id,
+        lock,
+        by: body.who,
+        at: chrono::Utc::now(),
+    };
+    warn!(?record, "Force unlocked tf state");
+    audit::record(&app_state.repo_path, &record).await?;
+
+    Ok(Json(record))
+}
+
+/// Re-encrypt every state with the primary encryption key
+pub async fn rotate_state_keys(
+    State(app_state): State<Arc<ServerState>>,
+) -> Result<impl IntoResponse> {
+    info!("Received request to rotate tf state encryption keys");
+
+    let encryption = app_state
+        .encryption
+        .as_ref()
+        .ok_or(Error::EncryptionDisabled)?;
+    let rotation = encryption.rotate_keys().await?;
+
+    if !rotation.locked.is_empty() {
+        warn!(locked = ?rotation.locked, "Skipped locked tf states while rotating keys");
+    }
+
+    Ok(Json(rotation))
+}
@@ -1,173 +1,309 @@
 use crate::{
     error::{self, Error, Result},
-    message::{self},
+    message::{GitMessage, GitService},
+    receive_pack::{Push, PushLimit},
     GitCodec, ServerState,
 };
 use axum::{
-    body::Bytes,
-    extract::{Path, Query, State},
+    body::{Bytes, StreamBody},
+    extract::{BodyStream, Path, Query, State},
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_LENGTH},
+        HeaderMap,
+    },
 };
-use std::{collections::HashMap, sync::Arc};
-use tokio::io::AsyncWriteExt;
+use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    path::{Component, Path as FsPath, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::{mpsc, oneshot};
 use tokio_util::codec::Encoder;
 use tracing::{debug, info};
 
-// Generate a response for git-receive-pack using the `git` executable
+/// The most an upload-pack request may grow to once it is decompressed
+const MAX_UPLOAD_PACK_REQUEST: u64 = 10 * 1024 * 1024;
+
+/// How the client compressed a request body
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum ContentEncoding {
+    Identity,
+    Gzip,
+}
+
+impl ContentEncoding {
+    /// Read the `Content-Encoding` header, rejecting encodings that can't be decoded
+    fn from_headers(headers: &HeaderMap) -> Result<Self> {
+        let Some(value) = headers.get(CONTENT_ENCODING) else {
+            return Ok(Self::Identity);
+        };
+        let value = value
+            .to_str()
+            .map_err(|_| Error::UnsupportedEncoding(format!("{value:?}")))?;
+
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(Self::Identity),
+            "gzip" | "x-gzip" => Ok(Self::Gzip),
+            encoding => Err(Error::UnsupportedEncoding(encoding.to_string())),
+        }
+    }
+
+    fn decode<'a>(self, body: impl Read + Send + 'a) -> Box<dyn Read + Send + 'a> {
+        match self {
+            Self::Identity => Box::new(body),
+            Self::Gzip => Box::new(flate2::read::GzDecoder::new(body)),
+        }
+    }
+}
+
+/// The protocol version requested in the `Git-Protocol` header, 0 when there is none
+fn protocol_version(headers: &HeaderMap) -> u8 {
+    headers
+        .get("git-protocol")
+        .and_then(|value| value.to_str().ok())
+        .into_iter()
+        .flat_map(|value| value.split(':'))
+        .filter_map(|parameter| parameter.strip_prefix("version="))
+        .filter_map(|version| version.parse().ok())
+        .max()
+        .unwrap_or(0)
+}
+
+/// The path of a repository served from `root`, rejecting names that would escape it
+fn repo_path(root: &FsPath, owner: &str, repo: &str) -> Result<PathBuf> {
+    for segment in [owner, repo] {
+        let mut components = FsPath::new(segment).components();
+        let single = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        );
+        if !single || segment.contains(['/', '\\', '\0']) {
+            return Err(Error::InvalidRepository(format!("{owner}/{repo}")));
+        }
+    }
+
+    Ok(root.join(owner).join(repo))
+}
+
+/// Open a repository served from `root`. Errors name the repository as it was requested, so
+/// that the location of the data directory isn't revealed.
+fn open_repo(root: &FsPath, owner: &str, repo: &str) -> Result<git2::Repository> {
+    match git2::Repository::open_bare(repo_path(root, owner, repo)?) {
+        Ok(repository) => Ok(repository),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            Err(Error::RepositoryNotFound(format!("{owner}/{repo}")))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Generate the ref advertisement for the requested service using git2
 #[allow(dead_code)]
-pub(crate) async fn list_refs_child(
+pub(crate) async fn list_refs(
     State(app_state): State<Arc<ServerState>>,
     Path((owner, repo)): Path<(String, String)>,
     Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
 ) -> Result<impl axum::response::IntoResponse> {
-    info!("Received data for {}/{}", owner, repo);
-
-    let service = query.get("service").ok_or(error::Error::MissingService)?;
-    info!(?service);
+    debug!("Received data for {}/{}", owner, repo);
 
-    // determine the path to the repo
-    let repo_path = app_state.repo_path.join(owner).join(repo);
+    let service = query
+        .get("service")
+        .ok_or(error::Error::MissingService)?
+        .parse::<GitService>()?;
+    debug!(?service);
 
-    // Call `git receive-pack --http-backend-info-refs` to get the data
-    let child = tokio::process::Command::new("git")
-        .arg("receive-pack")
-        .arg("--http-backend-info-refs")
-        .arg(&repo_path)
-        .output()
-        .await?;
+    let repo = open_repo(&app_state.repo_path, &owner, &repo)?;
 
-    let mut codec = GitCodec;
     let mut buf = bytes::BytesMut::new();
+    if service == GitService::UploadPack && protocol_version(&headers) == 2 {
+        // v2 clients get the capabilities without a service header and ask for refs separately
+        crate::upload_pack::advertise_capabilities(&mut buf)?;
+    } else {
+        GitCodec.encode(GitMessage::ServiceHeader(service), &mut buf)?;
+        GitCodec.encode(GitMessage::Flush, &mut buf)?;
+        match service {
+            GitService::ReceivePack => crate::receive_pack::advertise_refs(&repo, &mut buf)?,
+            GitService::UploadPack => crate::upload_pack::advertise_refs(&repo, &mut buf)?,
+        }
+    }
 
-    codec.encode(
-        message::GitMessage::ServiceHeader(message::GitService::ReceivePack),
-        &mut buf,
-    )?;
-    codec.encode(message::GitMessage::Flush, &mut buf)?;
-
-    // With the header finished, just return all the data from the child process
-    buf.extend_from_slice(&child.stdout);
-
-    debug!(?buf, "Encoded data");
+    debug!(path = ?repo.path(), ?buf);
 
+    let content_type = format!("application/x-{}-advertisement", service.name());
     Ok((
         [
-            (
-                axum::http::header::CONTENT_TYPE,
-                "application/x-git-receive-pack-advertisement",
-            ),
-            (axum::http::header::CACHE_CONTROL, "no-cache"),
+            (axum::http::header::CONTENT_TYPE, content_type),
+            (axum::http::header::CACHE_CONTROL, "no-cache".to_string()),
         ],
         buf.freeze(),
     ))
 }
 
-/// Generate a response for git-receive-pack using git2
-#[allow(dead_code)]
-pub(crate) async fn list_refs(
+/// Store a push and update the references it asks for.
+///
+/// The body is read as it arrives, so the pack never has to fit into memory, and the response
+/// is streamed back so the client sees the progress of indexing its pack.
+pub(crate) async fn receive_pack(
     State(app_state): State<Arc<ServerState>>,
     Path((owner, repo)): Path<(String, String)>,
-    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: BodyStream,
 ) -> Result<impl axum::response::IntoResponse> {
-    debug!("Received data for {}/{}", owner, repo);
+    info!(%owner, %repo, "Received send-pack data");
 
-    let service = query.get("service").ok_or(error::Error::MissingService)?;
-    debug!(?service);
+    let len = headers.get(CONTENT_LENGTH);
+    info!(?len);
 
-    // determine the path to the repo
-    let repo_path = app_state.repo_path.join(owner).join(repo);
-    let repo = git2::Repository::open_bare(&repo_path)?;
+    let encoding = ContentEncoding::from_headers(&headers)?;
 
-    // generate the ref response the client needs
-    let mut buf = bytes::BytesMut::new();
-    let mut codec = GitCodec;
+    let (started_tx, started_rx) = oneshot::channel();
+    let (response_tx, response_rx) = mpsc::channel::<Bytes>(16);
 
-    let refs = repo.references()?;
-    // format the references how git wants them - the reference hash and the reference name
-    let mut refs = refs
-        .into_iter()
-        .filter_map(|r| r.ok())
-        .map(|r| {
-            let target = r.target().unwrap().to_string();
-            let name = r.name().unwrap();
-
-            let mut buf = bytes::BytesMut::new();
-            buf.extend_from_slice(target.as_ref());
-            buf.extend_from_slice(b" ");
-            buf.extend_from_slice(name.as_ref());
-
-            buf
-        })
-        .collect::<Vec<_>>();
-
-    codec.encode(
-        message::GitMessage::ServiceHeader(message::GitService::ReceivePack),
-        &mut buf,
-    )?;
-    codec.encode(message::GitMessage::Flush, &mut buf)?;
-
-    // We need to attach capabilities to the first ref
-    let capabilities =
-        b"\0report-status report-status-v2 delete-refs side-band-64k quiet atomic ofs-delta object-format=sha1 agent=git/thoenix";
-    // If there are no refs, we need to add a dummy ref representing a "null sha1"
-    let empty_sha = b"0000000000000000000000000000000000000000";
-
-    if refs.is_empty() {
-        let mut data = bytes::BytesMut::new();
-        data.extend_from_slice(empty_sha);
-        data.extend_from_slice(capabilities);
-        codec.encode(message::GitMessage::Data(data.split().to_vec()), &mut buf)?;
-    } else {
-        refs.iter_mut().enumerate().try_for_each(|(i, r)| {
-            if i == 0 {
-                r.extend_from_slice(capabilities);
+    // indexing the pack is cpu bound and git2 is blocking
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        // the limit is enforced while reading so that the client is told why its push failed in
+        // the report, even when it sent a `Content-Length`. it applies to the decompressed body
+        // so that a small compressed push can't expand without bound.
+        let mut body = BodyReader::new(body, handle);
+        let mut request = PushLimit::new(encoding.decode(&mut body), app_state.max_push_size);
+        let started = open_repo(&app_state.repo_path, &owner, &repo)
+            .and_then(|repo| Ok((repo, Push::read(&mut request)?)));
+        let (repo, push) = match started {
+            Ok(started) => {
+                let _ = started_tx.send(Ok(()));
+                started
             }
+            Err(e) => {
+                let _ = started_tx.send(Err(e));
+                return;
+            }
+        };
 
-            codec.encode(message::GitMessage::Data(r.to_vec()), &mut buf)?;
+        // once the response has started the status can't change, so failures are only logged
+        let output = {
+            let response_tx = response_tx.clone();
+            move |chunk| {
+                let _ = response_tx.blocking_send(chunk);
+            }
+        };
+        if let Err(e) = push.apply(&repo, &mut request, output) {
+            tracing::error!(%e, "Failed to receive pack");
+        }
 
-            Ok::<(), Error>(())
-        })?;
-    }
-    codec.encode(message::GitMessage::Flush, &mut buf).unwrap();
+        // a push that went over the limit is never read to the end. dropping the body makes hyper
+        // close the connection once the report is sent instead of receiving the rest of a push
+        // that was already refused, so a client still sending may only see the connection close
+        drop(request);
+        drop(body);
+        drop(response_tx);
+    });
+    started_rx
+        .await
+        .map_err(|e| Error::Io(io::Error::other(e)))??;
 
-    debug!(?refs, ?repo_path, ?buf);
+    let response = futures::stream::unfold(response_rx, |mut response_rx| async move {
+        let chunk = response_rx.recv().await?;
+        Some((Ok::<_, io::Error>(chunk), response_rx))
+    });
 
     Ok((
         [
             (
                 axum::http::header::CONTENT_TYPE,
-                "application/x-git-receive-pack-advertisement",
+                "application/x-git-receive-pack-result",
             ),
             (axum::http::header::CACHE_CONTROL, "no-cache"),
         ],
-        buf.freeze(),
+        StreamBody::new(response),
     ))
 }
 
-pub(crate) async fn receive_pack(
+/// Reads a streamed request body from blocking code
+struct BodyReader {
+    body: BodyStream,
+    handle: tokio::runtime::Handle,
+    chunk: Bytes,
+}
+
+impl BodyReader {
+    fn new(body: BodyStream, handle: tokio::runtime::Handle) -> Self {
+        Self {
+            body,
+            handle,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            let Some(chunk) = self.handle.block_on(self.body.next()) else {
+                return Ok(0);
+            };
+            self.chunk = chunk.map_err(io::Error::other)?;
+        }
+
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+
+        Ok(len)
+    }
+}
+
+/// Answer a round of fetch negotiation from a client cloning or fetching, or a protocol v2
+/// command
+pub(crate) async fn upload_pack(
     State(app_state): State<Arc<ServerState>>,
     Path((owner, repo)): Path<(String, String)>,
+    headers: HeaderMap,
     payload: Bytes,
-) -> Result<Bytes> {
-    info!(%owner, %repo, "Received send-pack data");
-    let len = payload.len();
-    info!(?len);
-
-    let repo_path = app_state.repo_path.join(owner).join(repo);
+) -> Result<impl axum::response::IntoResponse> {
+    info!(%owner, %repo, "Received upload-pack request");
 
-    // invoke git-receive-pack and pipe the payload to it
-    let mut child = tokio::process::Command::new("git")
-        .arg("receive-pack")
-        .arg(&repo_path)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .spawn()?;
+    let version = protocol_version(&headers);
+    let encoding = ContentEncoding::from_headers(&headers)?;
 
-    let mut stdin = child.stdin.take().unwrap();
-    stdin.write_all(&payload).await?;
+    // packing is cpu bound and git2 is blocking
+    let response = tokio::task::spawn_blocking(move || {
+        let payload = match encoding {
+            ContentEncoding::Identity => payload,
+            encoding => {
+                let mut decoded = Vec::new();
+                encoding
+                    .decode(&payload[..])
+                    .take(MAX_UPLOAD_PACK_REQUEST + 1)
+                    .read_to_end(&mut decoded)
+                    .map_err(|e| Error::Protocol(format!("invalid compressed body: {e}")))?;
+                if decoded.len() as u64 > MAX_UPLOAD_PACK_REQUEST {
+                    return Err(Error::Protocol("request is too large".to_string()));
+                }
 
-    let output = child.wait_with_output().await?;
+                Bytes::from(decoded)
+            }
+        };
 
-    info!(?output);
+        let repo = open_repo(&app_state.repo_path, &owner, &repo)?;
+        match version {
+            2 => crate::upload_pack::run_command(&repo, &payload),
+            _ => crate::upload_pack::upload_pack(&repo, &payload),
+        }
+    })
+    .await
+    .map_err(|e| Error::Io(e.into()))??;
 
-    Ok(bytes::Bytes::from(output.stdout))
+    Ok((
+        [
+            (
+                axum::http::header::CONTENT_TYPE,
+                "application/x-git-upload-pack-result",
+            ),
+            (axum::http::header::CACHE_CONTROL, "no-cache"),
+        ],
+        response.freeze(),
+    ))
 }
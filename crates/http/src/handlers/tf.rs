@@ -1,72 +1,438 @@
 use crate::{
+    auth::Identity,
     error::{Error, Result},
+    policy::Permission,
     ServerState,
 };
 use axum::{
-    extract::{Path, Query, State},
+    extract::{Extension, Path, Query, State},
+    http::{header::HeaderName, HeaderMap},
     response::IntoResponse,
     Json,
 };
-use std::sync::Arc;
-use thoenix_tofu::{TerraformLock, TerraformLockQuery, TerraformStateProvider};
-use tracing::info;
+use std::{collections::BTreeMap, sync::Arc};
+use thoenix_tofu::{
+    content_md5,
+    diff::diff_states,
+    outputs::{parse_outputs, StateOutput},
+    resources::{parse_resources, StateResource},
+    TerraformLock, TerraformLockQuery,
+};
+use tracing::{info, warn};
+
+/// The base64 encoded md5 digest of a request or response body, as described in RFC 1864
+static CONTENT_MD5: HeaderName = HeaderName::from_static("content-md5");
 
 pub async fn get_tf_state(
     Path(id): Path<String>,
     State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
 ) -> Result<impl IntoResponse> {
     info!("Received request for tf state {}", id);
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Read)?;
+
+    // terraform treats an empty response as a state that has not been written yet
+    let response = match app_state.tf_state.get_state(&id).await? {
+        Some(state) if !state.data.is_empty() => {
+            state.verify()?;
+            let md5 = content_md5(state.data.as_bytes());
 
-    // create the state if it doesn't exist
-    let mut state = app_state.tf_state.lock().await;
-    let state = match state.get_state(&id).await? {
-        Some(state) => state,
-        None => {
-            state.create_state(id.clone()).await?;
-            state.get_state(&id).await?.ok_or(Error::NotFound)?
+            (
+                axum::http::StatusCode::OK,
+                [(CONTENT_MD5.clone(), md5)],
+                state.data,
+            )
+                .into_response()
         }
+        _ => axum::http::StatusCode::NO_CONTENT.into_response(),
     };
 
-    Ok((axum::http::StatusCode::OK, state.data.clone()))
+    Ok(response)
 }
 
 pub async fn update_tf_state(
     Path(id): Path<String>,
     State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
     Query(lock_query): Query<TerraformLockQuery>,
+    Query(update_query): Query<UpdateQuery>,
+    headers: HeaderMap,
     payload: String,
 ) -> Result<impl IntoResponse> {
     info!("Received request to update tf state {}", id);
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Write)?;
+
+    // catches bodies that were truncated or altered on the way
+    if let Some(expected) = headers.get(&CONTENT_MD5) {
+        if expected.as_bytes() != content_md5(payload.as_bytes()).as_bytes() {
+            return Err(Error::ChecksumMismatch);
+        }
+    }
+
+    if update_query.force {
+        warn!("Forcing update of tf state {}", id);
+    }
 
-    let mut state = app_state.tf_state.lock().await;
-    state.update_state(&id, &lock_query.id, payload).await?;
+    let state = &app_state.tf_state;
+    state
+        .update_state(&id, &lock_query.id, payload, update_query.force)
+        .await?;
 
     Ok(axum::http::StatusCode::OK)
 }
 
+pub async fn delete_tf_state(
+    Path(id): Path<String>,
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+    Query(lock_query): Query<OptionalLockQuery>,
+) -> Result<impl IntoResponse> {
+    info!("Received request to delete tf state {}", id);
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Write)?;
+
+    let lock_id = lock_query.id.unwrap_or_default();
+    app_state.tf_state.delete_state(&id, &lock_id).await?;
+
+    Ok(axum::http::StatusCode::OK)
+}
+
+/// List the states the caller may read
+pub async fn list_tf_states(
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+) -> Result<impl IntoResponse> {
+    info!("Received request to list tf states");
+
+    let states = app_state
+        .tf_state
+        .list_states()
+        .await?
+        .into_iter()
+        .filter(|state| {
+            app_state.state_ids.is_allowed(&state.id)
+                && app_state
+                    .policy
+                    .allows(identity.as_deref(), &state.id, Permission::Read)
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Json(states))
+}
+
 pub async fn lock_tf_state(
     Path(id): Path<String>,
     State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
     // Json(body): Json<TerraformLock>,
     Json(body): Json<TerraformLock>,
 ) -> Result<impl IntoResponse> {
     info!(?body, "Received request to lock tf state {}", id);
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Lock)?;
 
-    let mut state = app_state.tf_state.lock().await;
-    state.lock_state(&id, body).await?;
+    let state = &app_state.tf_state;
+    match state.lock_state(&id, body).await {
+        Ok(()) => Ok(axum::http::StatusCode::OK.into_response()),
+        // terraform reads the current holder from the body to report who holds the lock
+        Err(thoenix_tofu::error::Error::StateLocked) => {
+            let holder = state
+                .get_state(&id)
+                .await?
+                .and_then(|state| state.lock_info().cloned());
 
-    Ok(axum::http::StatusCode::OK)
+            Ok((axum::http::StatusCode::LOCKED, Json(holder)).into_response())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn get_tf_lock(
+    Path(id): Path<String>,
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+) -> Result<impl IntoResponse> {
+    info!("Received request for lock of tf state {}", id);
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Read)?;
+
+    let state = &app_state.tf_state;
+    let state = state.get_state(&id).await?.ok_or(Error::NotFound)?;
+
+    let response = match state.lock_info() {
+        Some(lock) => Json(lock.clone()).into_response(),
+        None => axum::http::StatusCode::NO_CONTENT.into_response(),
+    };
+
+    Ok(response)
 }
 
 pub async fn unlock_tf_state(
     Path(id): Path<String>,
     State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
     Json(body): Json<TerraformLock>,
 ) -> Result<impl IntoResponse> {
     info!(?body, "Received request to unlock tf state {}", id);
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Unlock)?;
 
-    let mut state = app_state.tf_state.lock().await;
+    let state = &app_state.tf_state;
     state.unlock_state(&id, &body).await?;
 
     Ok(axum::http::StatusCode::OK)
 }
+
+pub async fn list_tf_state_versions(
+    Path(id): Path<String>,
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+) -> Result<impl IntoResponse> {
+    info!("Received request to list versions of tf state {}", id);
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Read)?;
+
+    let state = &app_state.tf_state;
+    let versions = state.list_versions(&id).await?;
+
+    Ok(Json(versions))
+}
+
+pub async fn get_tf_state_version(
+    Path((id, version)): Path<(String, u64)>,
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+) -> Result<impl IntoResponse> {
+    info!(
+        "Received request for version {} of tf state {}",
+        version, id
+    );
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Read)?;
+
+    let state = &app_state.tf_state;
+    let version = state
+        .get_version(&id, version)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let md5 = content_md5(version.data.as_bytes());
+
+    Ok((
+        axum::http::StatusCode::OK,
+        [(CONTENT_MD5.clone(), md5)],
+        version.data,
+    ))
+}
+
+/// Compare two versions of a state, masking sensitive values
+pub async fn diff_tf_state_versions(
+    Path((id, from, to)): Path<(String, u64, u64)>,
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+) -> Result<impl IntoResponse> {
+    info!(
+        "Received request to diff versions {} and {} of tf state {}",
+        from, to, id
+    );
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Read)?;
+
+    let state = &app_state.tf_state;
+    let mut versions = Vec::with_capacity(2);
+    for version in [from, to] {
+        let data = state
+            .get_version(&id, version)
+            .await?
+            .ok_or(thoenix_tofu::error::Error::VersionNotFound(version))?
+            .data;
+        versions.push(data);
+    }
+
+    Ok(Json(diff_states(&versions[0], &versions[1])?))
+}
+
+pub async fn restore_tf_state_version(
+    Path((id, version)): Path<(String, u64)>,
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+    Query(lock_query): Query<OptionalLockQuery>,
+) -> Result<impl IntoResponse> {
+    info!(
+        "Received request to restore tf state {} to version {}",
+        id, version
+    );
+    app_state.state_ids.check(&id)?;
+    app_state
+        .policy
+        .check(identity.as_deref(), &id, Permission::Write)?;
+
+    let lock_id = lock_query.id.unwrap_or_default();
+    let state = &app_state.tf_state;
+    state.restore_version(&id, &lock_id, version).await?;
+
+    Ok(axum::http::StatusCode::OK)
+}
+
+/// Read the root module outputs of a state, along with whether the caller may see sensitive ones
+async fn read_outputs(
+    app_state: &ServerState,
+    id: &str,
+    identity: Option<&Identity>,
+) -> Result<(BTreeMap<String, StateOutput>, bool)> {
+    app_state.state_ids.check(id)?;
+    app_state.policy.check(identity, id, Permission::Read)?;
+
+    let state = app_state
+        .tf_state
+        .get_state(id)
+        .await?
+        .ok_or(Error::NotFound)?;
+    let outputs = parse_outputs(&state.data)?;
+    let authorised = app_state.policy.allows(identity, id, Permission::Sensitive);
+
+    Ok((outputs, authorised))
+}
+
+/// Return the root module outputs of a state. Sensitive outputs are left out unless the policy
+/// grants the `sensitive` permission on the state.
+pub async fn get_tf_state_outputs(
+    Path(id): Path<String>,
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+) -> Result<impl IntoResponse> {
+    info!("Received request for outputs of tf state {}", id);
+
+    let (mut outputs, authorised) = read_outputs(&app_state, &id, identity.as_deref()).await?;
+    if !authorised {
+        outputs.retain(|_, output| !output.sensitive);
+    }
+
+    Ok(Json(outputs))
+}
+
+pub async fn get_tf_state_output(
+    Path((id, name)): Path<(String, String)>,
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+) -> Result<impl IntoResponse> {
+    info!("Received request for output {} of tf state {}", name, id);
+
+    let (mut outputs, authorised) = read_outputs(&app_state, &id, identity.as_deref()).await?;
+    let output = outputs.remove(&name).ok_or(Error::NotFound)?;
+    if output.sensitive && !authorised {
+        return Err(Error::SensitiveOutput(name));
+    }
+
+    Ok(Json(output))
+}
+
+/// A resource instance together with the state it is managed by
+#[derive(Debug, serde::Serialize)]
+pub struct InventoryEntry {
+    /// The id of the state, which is named after the configuration that manages the resource
+    pub state: String,
+    #[serde(flatten)]
+    pub resource: StateResource,
+}
+
+/// Filters for the resource inventory
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct InventoryQuery {
+    /// Only include resources of this type, e.g. `aws_instance`
+    #[serde(rename = "type")]
+    pub resource_type: Option<String>,
+    /// Only include states whose id matches this glob pattern
+    pub configuration: Option<String>,
+}
+
+/// List the resource instances of every state the caller may read. States that can't be parsed
+/// are skipped.
+pub async fn list_tf_resources(
+    State(app_state): State<Arc<ServerState>>,
+    identity: Option<Extension<Identity>>,
+    Query(query): Query<InventoryQuery>,
+) -> Result<impl IntoResponse> {
+    info!(?query, "Received request for the tf resource inventory");
+
+    let configuration = query
+        .configuration
+        .as_deref()
+        .map(glob::Pattern::new)
+        .transpose()?;
+
+    let mut inventory = Vec::new();
+    for summary in app_state.tf_state.list_states().await? {
+        let id = summary.id;
+        if !app_state.state_ids.is_allowed(&id)
+            || !app_state
+                .policy
+                .allows(identity.as_deref(), &id, Permission::Read)
+            || configuration.as_ref().is_some_and(|p| !p.matches(&id))
+        {
+            continue;
+        }
+
+        let Some(state) = app_state.tf_state.get_state(&id).await? else {
+            continue;
+        };
+        let resources = match parse_resources(&state.data) {
+            Ok(resources) => resources,
+            Err(e) => {
+                warn!(?e, "Skipping tf state {} in the resource inventory", id);
+                continue;
+            }
+        };
+
+        inventory.extend(
+            resources
+                .into_iter()
+                .filter(|r| {
+                    query
+                        .resource_type
+                        .as_ref()
+                        .is_none_or(|t| &r.resource_type == t)
+                })
+                .map(|resource| InventoryEntry {
+                    state: id.clone(),
+                    resource,
+                }),
+        );
+    }
+
+    Ok(Json(inventory))
+}
+
+/// The lock held by the client, which may be omitted when the state is not locked
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct OptionalLockQuery {
+    #[serde(rename = "ID")]
+    pub id: Option<String>,
+}
+
+/// Options for updating a state
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct UpdateQuery {
+    /// Skip the serial and lineage checks, replacing the state regardless of its contents
+    #[serde(default)]
+    pub force: bool,
+}
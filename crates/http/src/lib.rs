@@ -1,43 +1,146 @@
 use axum::{
     extract::MatchedPath,
     http::Request,
-    routing::{get, post, put},
+    routing::{delete, get, post},
     Router,
 };
 #[allow(unused_imports)]
 use handlers::{
-    git::{list_refs, list_refs_child, receive_pack},
-    tf::{get_tf_state, lock_tf_state, unlock_tf_state, update_tf_state},
+    admin::{force_unlock_tf_state, list_tf_locks, require_admin, rotate_state_keys},
+    git::{list_refs, receive_pack, upload_pack},
+    tf::{
+        delete_tf_state, diff_tf_state_versions, get_tf_lock, get_tf_state, get_tf_state_output,
+        get_tf_state_outputs, get_tf_state_version, list_tf_resources, list_tf_state_versions,
+        list_tf_states, lock_tf_state, restore_tf_state_version, unlock_tf_state, update_tf_state,
+    },
 };
 use std::{net::SocketAddr, path::PathBuf, sync::Arc};
-use thoenix_tofu::InMemoryState;
-use tracing::{info_span, Span};
+use thoenix_tofu::{EncryptedState, FileState, Keyring, TerraformStateProvider};
+use tracing::{info_span, warn, Span};
 
+pub mod audit;
+pub mod auth;
 pub mod codec;
 pub mod error;
 pub mod handlers;
 pub mod message;
+pub mod policy;
+pub mod receive_pack;
+pub mod state_ids;
+pub mod upload_pack;
 
+use auth::{require_credentials, Credentials};
 use error::Result;
 use message::GitCodec;
+use policy::Policy;
+use state_ids::StateIdFilter;
 
 pub struct ServerState {
     pub repo_path: PathBuf,
 
-    pub tf_state: tokio::sync::Mutex<InMemoryState>,
+    pub tf_state: Arc<dyn TerraformStateProvider>,
+    /// The state ids that may be used with the terraform routes
+    pub state_ids: StateIdFilter,
+
+    /// The bearer token required by the `/admin` routes, which are disabled when unset
+    pub admin_token: Option<String>,
+
+    /// The encryption wrapping `tf_state`, when state is encrypted at rest
+    pub encryption: Option<Arc<EncryptedState>>,
+
+    /// The users allowed to use the terraform routes, which are open to anyone when unset
+    pub credentials: Option<Arc<Credentials>>,
+    /// Who may do what with which states
+    pub policy: Policy,
+
+    /// The most bytes a single push may send, unlimited when unset
+    pub max_push_size: Option<u64>,
 }
 
 pub struct Server {
-    #[allow(dead_code)]
     data_dir: PathBuf,
+    tf_state: Arc<dyn TerraformStateProvider>,
+    state_ids: StateIdFilter,
+    admin_token: Option<String>,
+    state_keys: Option<Keyring>,
+    credentials: Option<Credentials>,
+    policy: Policy,
+    max_push_size: Option<u64>,
 }
 
 impl Server {
+    /// Create a server that stores terraform state as files inside of `data_dir`
     pub fn new(data_dir: PathBuf) -> Self {
-        Self { data_dir }
+        let tf_state = Arc::new(FileState::new(data_dir.join("tf-state")));
+
+        Self {
+            data_dir,
+            tf_state,
+            state_ids: StateIdFilter::default(),
+            admin_token: None,
+            state_keys: None,
+            credentials: None,
+            policy: Policy::default(),
+            max_push_size: None,
+        }
+    }
+
+    /// Use a different provider for terraform state
+    pub fn with_state_provider(mut self, tf_state: Arc<dyn TerraformStateProvider>) -> Self {
+        self.tf_state = tf_state;
+        self
+    }
+
+    /// Only allow state ids that match one of the given filter's patterns
+    pub fn with_state_ids(mut self, state_ids: StateIdFilter) -> Self {
+        self.state_ids = state_ids;
+        self
+    }
+
+    /// Enable the `/admin` routes, requiring `token` as a bearer token
+    pub fn with_admin_token(mut self, token: Option<String>) -> Self {
+        self.admin_token = token;
+        self
+    }
+
+    /// Encrypt terraform state at rest using the given keys, wrapping the state provider
+    pub fn with_state_keys(mut self, keys: Option<Keyring>) -> Self {
+        self.state_keys = keys;
+        self
+    }
+
+    /// Require one of the given users to authenticate before using the terraform routes
+    pub fn with_credentials(mut self, credentials: Option<Credentials>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Restrict what users can do with each state
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Reject pushes that send more than `bytes`
+    pub fn with_max_push_size(mut self, bytes: Option<u64>) -> Self {
+        self.max_push_size = bytes;
+        self
     }
 
-    pub async fn run(&self, port: u16) -> Result<()> {
+    pub async fn run(self, port: u16) -> Result<()> {
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let app = self.router().await?;
+
+        println!("Listening on {addr}");
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .await?;
+
+        Ok(())
+    }
+
+    /// Build the routes of the server without binding to a port
+    pub async fn router(self) -> Result<Router> {
         let cors = tower_http::cors::CorsLayer::permissive();
         let tracing_layer = tower_http::trace::TraceLayer::new_for_http()
             .make_span_with(|request: &Request<_>| {
@@ -58,35 +161,89 @@ impl Server {
                 span.record("request_id", tracing::field::display(id));
             });
 
-        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let encryption = self
+            .state_keys
+            .map(|keys| Arc::new(EncryptedState::new(self.tf_state.clone(), keys)));
+        let tf_state = match &encryption {
+            Some(encryption) => {
+                // refuse to start rather than serve history that can no longer be decrypted
+                let usage = encryption.check_keys().await?;
+                if usage.plaintext > 0 {
+                    warn!(
+                        versions = usage.plaintext,
+                        "Some tf states have unencrypted versions from before encryption was enabled"
+                    );
+                }
+
+                encryption.clone()
+            }
+            None => self.tf_state,
+        };
 
         let app_state = Arc::new(ServerState {
             repo_path: self.data_dir.clone(),
-            tf_state: tokio::sync::Mutex::new(InMemoryState::new()),
+            tf_state,
+            state_ids: self.state_ids,
+            admin_token: self.admin_token,
+            encryption,
+            credentials: self.credentials.map(Arc::new),
+            policy: self.policy,
+            max_push_size: self.max_push_size,
         });
 
-        // TODO: Attempt to implement the `git-receive-pack` route but without calling into
-        // git. This could potentially be done using `git_pack::data::entry::Entry` to parse
-        // the file and `git_odn` to write? The method to implement is not clear yet.
+        let admin = Router::new()
+            .route("/tf/locks", get(list_tf_locks))
+            .route("/tf/lock/:id", delete(force_unlock_tf_state))
+            .route("/tf/rotate-keys", post(rotate_state_keys))
+            .route_layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                require_admin,
+            ));
+
+        let tf = Router::new()
+            .route("/tf/states", get(list_tf_states))
+            .route("/tf/resources", get(list_tf_resources))
+            .route(
+                "/tf/state/:id",
+                get(get_tf_state)
+                    .post(update_tf_state)
+                    .delete(delete_tf_state),
+            )
+            .route("/tf/state/:id/outputs", get(get_tf_state_outputs))
+            .route("/tf/state/:id/outputs/:name", get(get_tf_state_output))
+            .route("/tf/state/:id/diff/:from/:to", get(diff_tf_state_versions))
+            .route("/tf/state/:id/versions", get(list_tf_state_versions))
+            .route("/tf/state/:id/versions/:version", get(get_tf_state_version))
+            .route(
+                "/tf/state/:id/versions/:version/restore",
+                post(restore_tf_state_version),
+            )
+            .route(
+                "/tf/lock/:id",
+                get(get_tf_lock).put(lock_tf_state).delete(unlock_tf_state),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(
+                app_state.clone(),
+                require_credentials,
+            ));
+
         let app = Router::new()
-            // .route("/configs/:owner/:repo.git/info/refs", get(list_refs_child))
             .route("/configs/:owner/:repo.git/info/refs", get(list_refs))
             .route(
                 "/configs/:owner/:repo.git/git-receive-pack",
                 post(receive_pack),
             )
-            .route("/tf/state/:id", get(get_tf_state).post(update_tf_state))
-            .route("/tf/lock/:id", put(lock_tf_state).delete(unlock_tf_state))
+            .route(
+                "/configs/:owner/:repo.git/git-upload-pack",
+                post(upload_pack),
+            )
+            .merge(tf)
+            .nest("/admin", admin)
             .with_state(app_state)
             .layer(tracing_layer)
             .layer(cors)
             .fallback(get(|| async { "Hello, World!" }));
 
-        println!("Listening on {addr}");
-        axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .await?;
-
-        Ok(())
+        Ok(app)
     }
 }
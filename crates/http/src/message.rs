@@ -3,19 +3,46 @@ use crate::{
     error::Result,
 };
 
-#[derive(Debug)]
-pub(crate) enum GitService {
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GitService {
     ReceivePack,
+    UploadPack,
 }
 
-#[derive(Debug)]
-pub(crate) enum GitMessage {
+impl GitService {
+    /// The name of the service, as used in urls and the service header
+    pub fn name(&self) -> &'static str {
+        match self {
+            GitService::ReceivePack => "git-receive-pack",
+            GitService::UploadPack => "git-upload-pack",
+        }
+    }
+}
+
+impl std::str::FromStr for GitService {
+    type Err = crate::error::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "git-receive-pack" => Ok(GitService::ReceivePack),
+            "git-upload-pack" => Ok(GitService::UploadPack),
+            service => Err(crate::error::Error::UnknownService(service.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum GitMessage {
     ServiceHeader(GitService),
     Data(Vec<u8>),
     Flush,
+    /// Separates the sections of a protocol v2 message
+    Delim,
+    /// Marks the end of a protocol v2 response
+    ResponseEnd,
 }
 
-pub(crate) struct GitCodec;
+pub struct GitCodec;
 
 impl tokio_util::codec::Encoder<GitMessage> for GitCodec {
     type Error = crate::error::Error;
@@ -32,6 +59,12 @@ impl tokio_util::codec::Encoder<GitMessage> for GitCodec {
             GitMessage::Flush => {
                 buf.extend_from_slice(b"0000");
             }
+            GitMessage::Delim => {
+                PktLineCodec.encode(PktLineMessage::Delim, buf)?;
+            }
+            GitMessage::ResponseEnd => {
+                PktLineCodec.encode(PktLineMessage::ResponseEnd, buf)?;
+            }
         }
 
         Ok(())
@@ -43,12 +76,8 @@ impl tokio_util::codec::Encoder<GitService> for GitCodec {
     type Error = crate::error::Error;
 
     fn encode(&mut self, item: GitService, buf: &mut bytes::BytesMut) -> Result<()> {
-        match item {
-            GitService::ReceivePack => {
-                let line = PktLineMessage::Data(b"# service=git-receive-pack\n".to_vec());
-                PktLineCodec.encode(line, buf)?;
-            }
-        }
+        let line = format!("# service={}\n", item.name());
+        PktLineCodec.encode(PktLineMessage::Data(line.into_bytes()), buf)?;
 
         Ok(())
     }
@@ -67,13 +96,18 @@ impl tokio_util::codec::Decoder for GitCodec {
             None => Ok(None),
 
             Some(PktLineMessage::Data(data)) => match data.as_slice() {
-                b"#service git-receive-pack" => {
+                b"# service=git-receive-pack\n" => {
                     Ok(Some(GitMessage::ServiceHeader(GitService::ReceivePack)))
                 }
+                b"# service=git-upload-pack\n" => {
+                    Ok(Some(GitMessage::ServiceHeader(GitService::UploadPack)))
+                }
                 data => Ok(Some(GitMessage::Data(data.to_vec()))),
             },
 
             Some(PktLineMessage::Flush) => Ok(Some(GitMessage::Flush)),
+            Some(PktLineMessage::Delim) => Ok(Some(GitMessage::Delim)),
+            Some(PktLineMessage::ResponseEnd) => Ok(Some(GitMessage::ResponseEnd)),
         }
     }
 }
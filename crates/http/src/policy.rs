@@ -0,0 +1,159 @@
+use crate::{
+    auth::Identity,
+    error::{Error, Result},
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Something a user can do with a state
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    /// Read the state, its lock, versions and outputs
+    Read,
+    /// Update, restore or delete the state
+    Write,
+    Lock,
+    Unlock,
+    /// Read the values of sensitive outputs. Unlike the other permissions, it is only granted by
+    /// a rule, never by the default policy
+    Sensitive,
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let permission = match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Lock => "lock",
+            Permission::Unlock => "unlock",
+            Permission::Sensitive => "sensitive",
+        };
+
+        f.write_str(permission)
+    }
+}
+
+/// Grants permissions on the states matching a set of patterns to a set of users
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    /// The names of the users the rule applies to, or `*` for everyone including anonymous
+    /// requests when no credentials are configured
+    pub users: Vec<String>,
+    /// Glob patterns of the state ids the rule applies to, e.g. `prod-*`
+    pub states: Vec<String>,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PolicyFile {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct CompiledRule {
+    users: Vec<String>,
+    states: Vec<glob::Pattern>,
+    permissions: Vec<Permission>,
+}
+
+impl CompiledRule {
+    fn allows(&self, user: Option<&str>, id: &str, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+            && self
+                .users
+                .iter()
+                .any(|u| u == "*" || Some(u.as_str()) == user)
+            && self.states.iter().any(|p| p.matches(id))
+    }
+}
+
+/// Decides who may do what with which states.
+///
+/// A policy is a JSON document with a list of rules, each granting permissions to some users on
+/// some states. Anything not granted by a rule is denied, so a read-only token for a dashboard is
+/// a token user with a single `read` rule:
+///
+/// ```json
+/// {
+///   "rules": [
+///     { "users": ["deploy"], "states": ["prod-*"], "permissions": ["read", "write", "lock", "unlock"] },
+///     { "users": ["alice", "bob"], "states": ["dev-*"], "permissions": ["read", "write", "lock", "unlock"] },
+///     { "users": ["dashboard"], "states": ["*"], "permissions": ["read"] },
+///     { "users": ["deploy"], "states": ["prod-*"], "permissions": ["sensitive"] }
+///   ]
+/// }
+/// ```
+///
+/// The default policy allows everything except reading sensitive outputs, and requests made with
+/// the admin token are always allowed.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    /// `None` when no policy is configured
+    rules: Option<Vec<CompiledRule>>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<Rule>) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|rule| {
+                let states = rule
+                    .states
+                    .iter()
+                    .map(|p| glob::Pattern::new(p))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+
+                Ok(CompiledRule {
+                    users: rule.users,
+                    states,
+                    permissions: rule.permissions,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { rules: Some(rules) })
+    }
+
+    pub fn parse(policy: &str) -> Result<Self> {
+        let file: PolicyFile = serde_json::from_str(policy)?;
+
+        Self::new(file.rules)
+    }
+
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let policy = tokio::fs::read_to_string(path).await?;
+
+        Self::parse(&policy)
+    }
+
+    pub fn allows(&self, identity: Option<&Identity>, id: &str, permission: Permission) -> bool {
+        if identity.is_some_and(|identity| identity.admin) {
+            return true;
+        }
+        let Some(rules) = &self.rules else {
+            return permission != Permission::Sensitive;
+        };
+
+        let user = identity.map(|identity| identity.name.as_str());
+        rules.iter().any(|rule| rule.allows(user, id, permission))
+    }
+
+    /// Reject requests that aren't allowed to use `permission` on a state
+    pub fn check(
+        &self,
+        identity: Option<&Identity>,
+        id: &str,
+        permission: Permission,
+    ) -> Result<()> {
+        if !self.allows(identity, id, permission) {
+            return Err(Error::Forbidden {
+                user: identity.map_or_else(|| "anonymous".to_string(), |i| i.name.clone()),
+                permission,
+                state: id.to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
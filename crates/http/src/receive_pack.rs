@@ -0,0 +1,475 @@
+//! The server side of `git-receive-pack`, which clients use to push.
+//!
+//! A push is a single request with the reference updates the client wants to make, followed by
+//! a pack with the objects the server is missing. The pack is indexed and stored with libgit2,
+//! the new objects are checked to be complete, and the references are then updated under their
+//! locks so that concurrent pushes can't overwrite each other. No `git` executable is needed.
+use crate::{
+    error::{Error, Result},
+    message::{GitCodec, GitMessage},
+    upload_pack::{is_hidden_ref, list_refs, parse_line, parse_oid, write_sideband},
+};
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The capabilities advertised to clients pushing to the server
+const CAPABILITIES: &str = "report-status report-status-v2 delete-refs side-band-64k quiet atomic ofs-delta object-format=sha1 agent=git/thoenix";
+
+/// The side-band channel carrying progress messages
+const PROGRESS_BAND: u8 = 2;
+
+/// A reference update requested by the client
+#[derive(Debug, Clone)]
+struct Command {
+    old: git2::Oid,
+    new: git2::Oid,
+    name: String,
+}
+
+impl Command {
+    fn is_delete(&self) -> bool {
+        self.new.is_zero()
+    }
+}
+
+/// Write the references and capabilities that start a push
+pub fn advertise_refs(repo: &git2::Repository, buf: &mut BytesMut) -> Result<()> {
+    // unlike a fetch, `HEAD` and peeled tags aren't advertised as they can't be pushed to
+    let refs = list_refs(repo)?
+        .into_iter()
+        .filter(|r| r.symref_target.is_none())
+        .collect::<Vec<_>>();
+
+    // without any refs the capabilities are sent on a placeholder
+    if refs.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{CAPABILITIES}\n", git2::Oid::zero());
+        GitCodec.encode(GitMessage::Data(line.into_bytes()), buf)?;
+    }
+
+    for (i, r) in refs.iter().enumerate() {
+        let line = match i {
+            0 => format!("{} {}\0{CAPABILITIES}\n", r.target, r.name),
+            _ => format!("{} {}\n", r.target, r.name),
+        };
+        GitCodec.encode(GitMessage::Data(line.into_bytes()), buf)?;
+    }
+    GitCodec.encode(GitMessage::Flush, buf)?;
+
+    Ok(())
+}
+
+/// The reference updates of a push, read before its pack
+#[derive(Debug)]
+pub struct Push {
+    commands: Vec<Command>,
+    capabilities: HashSet<String>,
+}
+
+impl Push {
+    /// Read the commands at the start of a push, with the capabilities the client uses after the
+    /// first one
+    pub fn read(request: &mut impl Read) -> Result<Self> {
+        let mut commands = Vec::new();
+        let mut capabilities = HashSet::new();
+        loop {
+            let data = match read_message(request)? {
+                GitMessage::Data(data) => data,
+                GitMessage::Flush => break,
+                message => return Err(Error::Protocol(format!("unexpected {message:?}"))),
+            };
+            let line = parse_line(&data)?;
+
+            let line = match line.split_once('\0') {
+                Some((line, requested)) => {
+                    capabilities.extend(requested.split(' ').map(str::to_string));
+                    line
+                }
+                None => line,
+            };
+            let mut parts = line.splitn(3, ' ');
+            let (Some(old), Some(new), Some(name)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(Error::Protocol(format!("expected a command, got {line}")));
+            };
+            commands.push(Command {
+                old: parse_oid(old)?,
+                new: parse_oid(new)?,
+                name: name.to_string(),
+            });
+        }
+
+        Ok(Self {
+            commands,
+            capabilities,
+        })
+    }
+
+    /// Store the pack that follows the commands, update the references and report the outcome
+    /// of every update.
+    ///
+    /// The response is passed to `output` as it is produced, starting with the progress of
+    /// indexing the pack while it is still being received. Problems with the pack or with
+    /// individual updates are reported to the client using `report-status`. As references are
+    /// never rewritten by the server, the `report-status-v2` report is the same as the original.
+    pub fn apply(
+        self,
+        repo: &git2::Repository,
+        request: &mut impl Read,
+        mut output: impl FnMut(Bytes) + 'static,
+    ) -> Result<()> {
+        let Push {
+            commands,
+            capabilities,
+        } = self;
+
+        // a client with nothing to update only sends a flush
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        let mut sideband = Sideband {
+            enabled: capabilities.contains("side-band-64k"),
+            quiet: capabilities.contains("quiet"),
+            output: &mut output,
+        };
+
+        // deletions don't need any objects, so a push that only deletes comes without a pack
+        let unpacked = match commands.iter().all(Command::is_delete) {
+            true => Ok(()),
+            false => store_pack(repo, request, &mut sideband),
+        };
+
+        let mut results = commands
+            .iter()
+            .map(|command| match &unpacked {
+                Ok(()) => check_command(repo, command),
+                Err(_) => Err("unpacker error".to_string()),
+            })
+            .collect::<Vec<_>>();
+
+        if capabilities.contains("atomic") {
+            if results.iter().all(|result| result.is_ok()) {
+                let all = commands.iter().collect::<Vec<_>>();
+                if let Err(reason) = update_refs(repo, &all) {
+                    results.fill(Err(reason));
+                }
+            } else {
+                for result in results.iter_mut().filter(|result| result.is_ok()) {
+                    *result = Err("atomic push failure".to_string());
+                }
+            }
+        } else {
+            for (command, result) in commands.iter().zip(results.iter_mut()) {
+                if result.is_ok() {
+                    *result = update_refs(repo, &[command]);
+                }
+            }
+        }
+
+        if capabilities.contains("report-status") || capabilities.contains("report-status-v2") {
+            let mut report = BytesMut::new();
+            let unpack = match &unpacked {
+                Ok(()) => "unpack ok\n".to_string(),
+                Err(e) => format!("unpack {e}\n"),
+            };
+            GitCodec.encode(GitMessage::Data(unpack.into_bytes()), &mut report)?;
+            for (command, result) in commands.iter().zip(&results) {
+                let line = match result {
+                    Ok(()) => format!("ok {}\n", command.name),
+                    Err(reason) => format!("ng {} {reason}\n", command.name),
+                };
+                GitCodec.encode(GitMessage::Data(line.into_bytes()), &mut report)?;
+            }
+            GitCodec.encode(GitMessage::Flush, &mut report)?;
+
+            sideband.data(&report)?;
+        }
+        sideband.finish()?;
+
+        Ok(())
+    }
+}
+
+/// Fails reads once a push grows past the maximum push size
+pub struct PushLimit<R> {
+    inner: R,
+    received: u64,
+    max_size: Option<u64>,
+}
+
+impl<R> PushLimit<R> {
+    /// Limit `inner` to `max_size` bytes, or pass everything through without one
+    pub fn new(inner: R, max_size: Option<u64>) -> Self {
+        Self {
+            inner,
+            received: 0,
+            max_size,
+        }
+    }
+}
+
+impl<R: Read> Read for PushLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+
+        self.received += len as u64;
+        if let Some(max) = self.max_size.filter(|max| self.received > *max) {
+            return Err(std::io::Error::other(Error::PushTooLarge(max)));
+        }
+
+        Ok(len)
+    }
+}
+
+/// Read one pkt-line from a streamed request
+fn read_message(request: &mut impl Read) -> Result<GitMessage> {
+    let truncated = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::Protocol("truncated request".to_string()),
+        // a compressed body that can't be decoded
+        std::io::ErrorKind::InvalidData | std::io::ErrorKind::InvalidInput => {
+            Error::Protocol(format!("invalid compressed body: {e}"))
+        }
+        _ => match e.get_ref().and_then(|inner| inner.downcast_ref::<Error>()) {
+            Some(Error::PushTooLarge(max)) => Error::PushTooLarge(*max),
+            _ => Error::Io(e),
+        },
+    };
+
+    let mut buf = BytesMut::zeroed(4);
+    request.read_exact(&mut buf).map_err(truncated)?;
+    let len = std::str::from_utf8(&buf)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .ok_or(Error::ParseLengthBytes)?;
+    if len > 4 {
+        buf.resize(len, 0);
+        request.read_exact(&mut buf[4..]).map_err(truncated)?;
+    }
+
+    GitCodec
+        .decode(&mut buf)?
+        .ok_or_else(|| Error::Protocol("truncated request".to_string()))
+}
+
+/// Sends the response of a push, multiplexed with progress messages when the client asked for
+/// side-band-64k
+struct Sideband<'a, F> {
+    enabled: bool,
+    quiet: bool,
+    output: &'a mut F,
+}
+
+impl<F: FnMut(Bytes)> Sideband<'_, F> {
+    fn data(&mut self, data: &[u8]) -> Result<()> {
+        let mut buf = BytesMut::new();
+        match self.enabled {
+            true => write_sideband(data, &mut buf)?,
+            false => buf.extend_from_slice(data),
+        }
+        (self.output)(buf.freeze());
+
+        Ok(())
+    }
+
+    fn progress(&mut self, message: &str) -> Result<()> {
+        if !self.enabled || self.quiet {
+            return Ok(());
+        }
+
+        let mut data = Vec::with_capacity(message.len() + 1);
+        data.push(PROGRESS_BAND);
+        data.extend_from_slice(message.as_bytes());
+        let mut buf = BytesMut::new();
+        GitCodec.encode(GitMessage::Data(data), &mut buf)?;
+        (self.output)(buf.freeze());
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.enabled {
+            let mut buf = BytesMut::new();
+            GitCodec.encode(GitMessage::Flush, &mut buf)?;
+            (self.output)(buf.freeze());
+        }
+
+        Ok(())
+    }
+}
+
+/// Index the pack as it is received and add it to the repository, reporting progress the way
+/// `git index-pack` does
+fn store_pack<F: FnMut(Bytes) + 'static>(
+    repo: &git2::Repository,
+    request: &mut impl Read,
+    sideband: &mut Sideband<'_, F>,
+) -> std::result::Result<(), String> {
+    let mut signature = [0; 4];
+    request
+        .read_exact(&mut signature)
+        .map_err(|_| "missing pack".to_string())?;
+    if &signature != b"PACK" {
+        return Err("missing pack".to_string());
+    }
+
+    let odb = repo.odb().map_err(|e| e.message().to_string())?;
+    let mut writer = odb.packwriter().map_err(|e| e.message().to_string())?;
+
+    // only send a message when the percentage changes, the callback runs for every object
+    let mut last = None;
+    writer.progress(move |progress| {
+        let receiving =
+            progress.received_objects() < progress.total_objects() || progress.total_deltas() == 0;
+        let (title, done, total) = match receiving {
+            true => (
+                "Receiving objects",
+                progress.received_objects(),
+                progress.total_objects(),
+            ),
+            false => (
+                "Resolving deltas",
+                progress.indexed_deltas(),
+                progress.total_deltas(),
+            ),
+        };
+        if total == 0 {
+            return true;
+        }
+
+        let percent = done * 100 / total;
+        if last != Some((title, percent)) {
+            last = Some((title, percent));
+            let end = if done == total { ", done.\n" } else { "\r" };
+            let message = format!("{title}: {percent:3}% ({done}/{total}){end}");
+            // progress is best effort, a failure to encode it shouldn't fail the push
+            let _ = sideband.progress(&message);
+        }
+
+        true
+    });
+
+    writer.write_all(&signature).map_err(|e| e.to_string())?;
+    std::io::copy(request, &mut writer).map_err(|e| e.to_string())?;
+    writer.commit().map_err(|e| e.message().to_string())?;
+
+    Ok(())
+}
+
+/// Check that a command names a valid reference and that everything it points to was received
+fn check_command(repo: &git2::Repository, command: &Command) -> std::result::Result<(), String> {
+    if !command.name.starts_with("refs/") || !git2::Reference::is_valid_name(&command.name) {
+        return Err("funny refname".to_string());
+    }
+    // like git with `receive.hideRefs`, refs that aren't advertised can't be pushed to either
+    if is_hidden_ref(&command.name) {
+        return Err("deny updating a hidden ref".to_string());
+    }
+    if command.is_delete() {
+        return Ok(());
+    }
+
+    check_connected(repo, command.new).map_err(|_| "missing necessary objects".to_string())
+}
+
+/// Make sure that every object reachable from `new` is in the repository.
+///
+/// Only commits that aren't reachable from an existing reference are checked, as everything
+/// reachable from a reference was complete when it was received.
+fn check_connected(repo: &git2::Repository, new: git2::Oid) -> Result<()> {
+    let mut object = repo.find_object(new, None)?;
+    while let Some(tag) = object.as_tag() {
+        object = repo.find_object(tag.target_id(), None)?;
+    }
+
+    let odb = repo.odb()?;
+    let mut seen = HashSet::new();
+    match object.kind() {
+        Some(git2::ObjectType::Commit) => {
+            let mut walk = repo.revwalk()?;
+            walk.push(object.id())?;
+            for r in repo.references()? {
+                if let Some(target) = r?.target() {
+                    // tags of trees and blobs can't be hidden, and don't need to be
+                    let _ = walk.hide(target);
+                }
+            }
+
+            // the walk fails when a parent is missing
+            for commit in walk {
+                let commit = repo.find_commit(commit?)?;
+                check_tree(repo, &odb, commit.tree_id(), &mut seen)?;
+            }
+        }
+        Some(git2::ObjectType::Tree) => check_tree(repo, &odb, object.id(), &mut seen)?,
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn check_tree(
+    repo: &git2::Repository,
+    odb: &git2::Odb,
+    id: git2::Oid,
+    seen: &mut HashSet<git2::Oid>,
+) -> Result<()> {
+    if !seen.insert(id) {
+        return Ok(());
+    }
+
+    for entry in repo.find_tree(id)?.iter() {
+        match entry.kind() {
+            Some(git2::ObjectType::Tree) => check_tree(repo, odb, entry.id(), seen)?,
+            // submodules point at commits in other repositories
+            Some(git2::ObjectType::Commit) => {}
+            _ => {
+                if !odb.exists(entry.id()) {
+                    return Err(Error::Protocol(format!("missing object {}", entry.id())));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Update references in a single transaction, as long as they still point where the client
+/// expects them to
+fn update_refs(repo: &git2::Repository, commands: &[&Command]) -> std::result::Result<(), String> {
+    let failed = |e: git2::Error| format!("failed to update ref: {}", e.message());
+
+    let mut transaction = repo.transaction().map_err(failed)?;
+    for command in commands {
+        transaction.lock_ref(&command.name).map_err(failed)?;
+    }
+
+    // the references can't change once they are locked
+    for command in commands {
+        let current = match repo.find_reference(&command.name) {
+            Ok(reference) => reference.target().unwrap_or_else(git2::Oid::zero),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => git2::Oid::zero(),
+            Err(e) => return Err(failed(e)),
+        };
+        if current != command.old {
+            return Err(format!(
+                "failed to update ref: {} is at {current} but expected {}",
+                command.name, command.old
+            ));
+        }
+    }
+
+    for command in commands {
+        if command.is_delete() {
+            transaction.remove(&command.name).map_err(failed)?;
+        } else {
+            transaction
+                .set_target(&command.name, command.new, None, "push")
+                .map_err(failed)?;
+        }
+    }
+    transaction.commit().map_err(failed)
+}
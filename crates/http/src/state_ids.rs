@@ -0,0 +1,37 @@
+use crate::error::{Error, Result};
+
+/// Restricts which state ids may be used with the terraform routes.
+///
+/// Each pattern is a glob such as `prod-*`. When no patterns are configured every id is allowed.
+#[derive(Debug, Default, Clone)]
+pub struct StateIdFilter {
+    patterns: Vec<glob::Pattern>,
+}
+
+impl StateIdFilter {
+    pub fn new<I, S>(patterns: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let patterns = patterns
+            .into_iter()
+            .map(|p| glob::Pattern::new(p.as_ref()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(Self { patterns })
+    }
+
+    pub fn is_allowed(&self, id: &str) -> bool {
+        self.patterns.is_empty() || self.patterns.iter().any(|p| p.matches(id))
+    }
+
+    /// Reject ids that do not match any pattern
+    pub fn check(&self, id: &str) -> Result<()> {
+        if !self.is_allowed(id) {
+            return Err(Error::StateIdNotAllowed(id.to_string()));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,511 @@
+//! The server side of `git-upload-pack`, which clients use to clone and fetch.
+//!
+//! Requests are handled statelessly as described in `gitprotocol-http(5)`: every request carries
+//! all of the client's wants and the haves it knows to be common, so no state is kept between
+//! the rounds of a negotiation.
+//!
+//! Clients that send `Git-Protocol: version=2` use the `ls-refs` and `fetch` commands of
+//! `gitprotocol-v2(5)` instead, everyone else gets protocol v0.
+use crate::{
+    error::{Error, Result},
+    message::{GitCodec, GitMessage},
+};
+use bytes::BytesMut;
+use std::collections::HashSet;
+use thoenix_tofu::git::REF_NAMESPACE;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// The capabilities advertised to clients fetching from the server
+const CAPABILITIES: &str =
+    "multi_ack_detailed side-band-64k ofs-delta no-progress object-format=sha1 agent=git/thoenix";
+
+/// The capabilities advertised to clients using protocol v2
+const V2_CAPABILITIES: &[&str] = &[
+    "agent=git/thoenix",
+    "ls-refs",
+    "fetch",
+    "object-format=sha1",
+];
+
+/// The most pack data that fits into one side-band-64k packet, after the length and band
+const MAX_SIDEBAND_DATA: usize = 65515;
+
+/// The side-band channel carrying pack data
+const PACK_BAND: u8 = 1;
+
+/// A reference as it is advertised to clients
+#[derive(Debug, Clone)]
+pub struct AdvertisedRef {
+    pub name: String,
+    pub target: git2::Oid,
+    /// The object an annotated tag points to
+    pub peeled: Option<git2::Oid>,
+    /// The reference a symbolic reference such as `HEAD` points to
+    pub symref_target: Option<String>,
+}
+
+/// Whether a reference is hidden from clients, like git's `transfer.hideRefs`. The refs holding
+/// terraform state and locks must not be fetched or pushed to, so mirrors made through the server
+/// leave them out. See [`thoenix_tofu::GitState`] for how to back state up instead.
+pub fn is_hidden_ref(name: &str) -> bool {
+    name.starts_with(REF_NAMESPACE)
+}
+
+/// Every reference that can be fetched, `HEAD` first and the rest ordered by name.
+///
+/// Symbolic references other than `HEAD`, `HEAD` on an unborn branch and hidden references are
+/// left out.
+pub fn list_refs(repo: &git2::Repository) -> Result<Vec<AdvertisedRef>> {
+    let mut refs = Vec::new();
+
+    if let Ok(head) = repo.find_reference("HEAD") {
+        if let Ok(resolved) = head.resolve() {
+            if let (Some(target), false) = (
+                resolved.target(),
+                resolved.name().is_some_and(is_hidden_ref),
+            ) {
+                refs.push(AdvertisedRef {
+                    name: "HEAD".to_string(),
+                    target,
+                    peeled: None,
+                    symref_target: head.symbolic_target().map(str::to_string),
+                });
+            }
+        }
+    }
+
+    let mut named = Vec::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        let (Some(name), Some(target)) = (reference.name(), reference.target()) else {
+            continue;
+        };
+        if is_hidden_ref(name) {
+            continue;
+        }
+
+        let peeled = match repo.find_object(target, None)?.kind() {
+            Some(git2::ObjectType::Tag) => Some(reference.peel(git2::ObjectType::Any)?.id()),
+            _ => None,
+        };
+        named.push(AdvertisedRef {
+            name: name.to_string(),
+            target,
+            peeled,
+            symref_target: None,
+        });
+    }
+    named.sort_by(|a, b| a.name.cmp(&b.name));
+    refs.extend(named);
+
+    Ok(refs)
+}
+
+/// Write the reference advertisement that starts a fetch using protocol v0 or v1
+pub fn advertise_refs(repo: &git2::Repository, buf: &mut BytesMut) -> Result<()> {
+    let refs = list_refs(repo)?;
+
+    let mut capabilities = CAPABILITIES.to_string();
+    for r in &refs {
+        if let Some(target) = &r.symref_target {
+            capabilities.push_str(&format!(" symref={}:{target}", r.name));
+        }
+    }
+
+    // without any refs the capabilities are sent on a placeholder
+    if refs.is_empty() {
+        let line = format!("{} capabilities^{{}}\0{capabilities}\n", git2::Oid::zero());
+        GitCodec.encode(GitMessage::Data(line.into_bytes()), buf)?;
+    }
+
+    for (i, r) in refs.iter().enumerate() {
+        let line = match i {
+            0 => format!("{} {}\0{capabilities}\n", r.target, r.name),
+            _ => format!("{} {}\n", r.target, r.name),
+        };
+        GitCodec.encode(GitMessage::Data(line.into_bytes()), buf)?;
+
+        if let Some(peeled) = r.peeled {
+            let line = format!("{peeled} {}^{{}}\n", r.name);
+            GitCodec.encode(GitMessage::Data(line.into_bytes()), buf)?;
+        }
+    }
+    GitCodec.encode(GitMessage::Flush, buf)?;
+
+    Ok(())
+}
+
+/// Answer one round of a fetch negotiation, sending the pack once the client is done
+pub fn upload_pack(repo: &git2::Repository, request: &[u8]) -> Result<BytesMut> {
+    let mut request = BytesMut::from(request);
+    let mut lines = Vec::new();
+    while let Some(message) = GitCodec.decode(&mut request)? {
+        lines.push(message);
+    }
+    if !request.is_empty() {
+        return Err(Error::Protocol("truncated request".to_string()));
+    }
+    let mut lines = lines.into_iter();
+
+    // the wants come first, with the capabilities the client uses after the first one
+    let mut wants = Vec::new();
+    let mut capabilities = HashSet::new();
+    for line in lines.by_ref() {
+        let GitMessage::Data(data) = line else {
+            break;
+        };
+        let line = parse_line(&data)?;
+        let Some(want) = line.strip_prefix("want ") else {
+            return Err(Error::Protocol(format!("expected a want, got {line}")));
+        };
+
+        let mut parts = want.split(' ');
+        wants.push(parse_oid(parts.next().unwrap_or_default())?);
+        capabilities.extend(parts.map(str::to_string));
+    }
+    if wants.is_empty() {
+        return Err(Error::Protocol("no objects were requested".to_string()));
+    }
+    check_wants(repo, &wants)?;
+
+    // without multi_ack_detailed a stateless client expects the pack right after `done` once it
+    // has seen an ACK, but it doesn't repeat the common have, so every client that can should
+    // use multi_ack_detailed
+    let multi_ack = capabilities.contains("multi_ack_detailed");
+
+    let mut response = BytesMut::new();
+    let mut common = Vec::new();
+    let mut done = false;
+    for line in lines {
+        let data = match line {
+            GitMessage::Data(data) => data,
+            // the end of a round without `done`, the client will send another request. `ready`
+            // is never sent, so the client decides when it has sent enough haves.
+            _ => {
+                if multi_ack || common.is_empty() {
+                    GitCodec.encode(GitMessage::Data(b"NAK\n".to_vec()), &mut response)?;
+                }
+                return Ok(response);
+            }
+        };
+
+        match parse_line(&data)? {
+            "done" => {
+                done = true;
+                break;
+            }
+            line => {
+                let Some(have) = line.strip_prefix("have ") else {
+                    return Err(Error::Protocol(format!("expected a have, got {line}")));
+                };
+                let have = parse_oid(have)?;
+
+                // without multi_ack only the first common object is acknowledged
+                if repo.odb()?.exists(have) {
+                    common.push(have);
+                    let ack = match multi_ack {
+                        true => Some(format!("ACK {have} common\n")),
+                        false => (common.len() == 1).then(|| format!("ACK {have}\n")),
+                    };
+                    if let Some(ack) = ack {
+                        GitCodec.encode(GitMessage::Data(ack.into_bytes()), &mut response)?;
+                    }
+                }
+            }
+        }
+    }
+    if !done {
+        return Err(Error::Protocol("request ended before `done`".to_string()));
+    }
+    match common.last() {
+        None => GitCodec.encode(GitMessage::Data(b"NAK\n".to_vec()), &mut response)?,
+        Some(last) if multi_ack => {
+            let ack = format!("ACK {last}\n");
+            GitCodec.encode(GitMessage::Data(ack.into_bytes()), &mut response)?;
+        }
+        Some(_) => {}
+    }
+
+    let pack = build_pack(repo, &wants, &common, false)?;
+    if capabilities.contains("side-band-64k") {
+        write_sideband(&pack, &mut response)?;
+        GitCodec.encode(GitMessage::Flush, &mut response)?;
+    } else {
+        response.extend_from_slice(&pack);
+    }
+
+    Ok(response)
+}
+
+/// Write the capability advertisement that starts every protocol v2 conversation
+pub fn advertise_capabilities(buf: &mut BytesMut) -> Result<()> {
+    GitCodec.encode(GitMessage::Data(b"version 2\n".to_vec()), buf)?;
+    for capability in V2_CAPABILITIES {
+        let line = format!("{capability}\n");
+        GitCodec.encode(GitMessage::Data(line.into_bytes()), buf)?;
+    }
+    GitCodec.encode(GitMessage::Flush, buf)?;
+
+    Ok(())
+}
+
+/// A protocol v2 request
+#[derive(Debug, Default)]
+struct Command {
+    name: String,
+    capabilities: Vec<String>,
+    arguments: Vec<String>,
+}
+
+impl Command {
+    /// Parse `command=<name>` and the capabilities, then the arguments after a delimiter
+    fn parse(request: &[u8]) -> Result<Self> {
+        let mut request = BytesMut::from(request);
+        let mut command = Command::default();
+        let mut in_arguments = false;
+
+        loop {
+            match GitCodec.decode(&mut request)? {
+                Some(GitMessage::Data(data)) => {
+                    let line = parse_line(&data)?.to_string();
+                    if in_arguments {
+                        command.arguments.push(line);
+                    } else if let Some(name) = line.strip_prefix("command=") {
+                        command.name = name.to_string();
+                    } else {
+                        command.capabilities.push(line);
+                    }
+                }
+                Some(GitMessage::Delim) if !in_arguments => in_arguments = true,
+                Some(GitMessage::Flush) => break,
+                Some(message) => {
+                    return Err(Error::Protocol(format!("unexpected {message:?}")));
+                }
+                None => return Err(Error::Protocol("truncated request".to_string())),
+            }
+        }
+        if command.name.is_empty() {
+            return Err(Error::Protocol("missing command".to_string()));
+        }
+
+        Ok(command)
+    }
+}
+
+/// Answer a protocol v2 command
+pub fn run_command(repo: &git2::Repository, request: &[u8]) -> Result<BytesMut> {
+    let command = Command::parse(request)?;
+
+    let object_format = command
+        .capabilities
+        .iter()
+        .find_map(|c| c.strip_prefix("object-format="));
+    if object_format.is_some_and(|format| format != "sha1") {
+        return Err(Error::Protocol(
+            "only sha1 repositories are supported".to_string(),
+        ));
+    }
+
+    match command.name.as_str() {
+        "ls-refs" => ls_refs(repo, &command.arguments),
+        "fetch" => fetch(repo, &command.arguments),
+        name => Err(Error::Protocol(format!("unknown command {name}"))),
+    }
+}
+
+/// List the refs matching the requested prefixes
+fn ls_refs(repo: &git2::Repository, arguments: &[String]) -> Result<BytesMut> {
+    let mut peel = false;
+    let mut symrefs = false;
+    let mut prefixes = Vec::new();
+    for argument in arguments {
+        match argument.as_str() {
+            "peel" => peel = true,
+            "symrefs" => symrefs = true,
+            // unborn refs aren't advertised, so clients only ask for them when they are unsure
+            "unborn" => {}
+            argument => match argument.strip_prefix("ref-prefix ") {
+                Some(prefix) => prefixes.push(prefix),
+                None => return Err(Error::Protocol(format!("unknown argument {argument}"))),
+            },
+        }
+    }
+
+    let mut response = BytesMut::new();
+    for r in list_refs(repo)? {
+        if !prefixes.is_empty() && !prefixes.iter().any(|p| r.name.starts_with(p)) {
+            continue;
+        }
+
+        let mut line = format!("{} {}", r.target, r.name);
+        if let Some(target) = r.symref_target.as_ref().filter(|_| symrefs) {
+            line.push_str(&format!(" symref-target:{target}"));
+        }
+        if let Some(peeled) = r.peeled.filter(|_| peel) {
+            line.push_str(&format!(" peeled:{peeled}"));
+        }
+        line.push('\n');
+        GitCodec.encode(GitMessage::Data(line.into_bytes()), &mut response)?;
+    }
+    GitCodec.encode(GitMessage::Flush, &mut response)?;
+
+    Ok(response)
+}
+
+/// Acknowledge the client's haves, or send the pack once the client is done
+fn fetch(repo: &git2::Repository, arguments: &[String]) -> Result<BytesMut> {
+    let mut wants = Vec::new();
+    let mut haves = Vec::new();
+    let mut done = false;
+    let mut include_tags = false;
+    for argument in arguments {
+        match argument.as_str() {
+            "done" => done = true,
+            "include-tag" => include_tags = true,
+            // packs are always complete, use offset deltas and come without progress
+            "thin-pack" | "ofs-delta" | "no-progress" => {}
+            argument => {
+                if let Some(want) = argument.strip_prefix("want ") {
+                    wants.push(parse_oid(want)?);
+                } else if let Some(have) = argument.strip_prefix("have ") {
+                    haves.push(parse_oid(have)?);
+                } else {
+                    return Err(Error::Protocol(format!("unsupported argument {argument}")));
+                }
+            }
+        }
+    }
+    if wants.is_empty() {
+        return Err(Error::Protocol("no objects were requested".to_string()));
+    }
+    check_wants(repo, &wants)?;
+
+    let odb = repo.odb()?;
+    let common = haves
+        .into_iter()
+        .filter(|have| odb.exists(*have))
+        .collect::<Vec<_>>();
+
+    let mut response = BytesMut::new();
+    if !done {
+        GitCodec.encode(
+            GitMessage::Data(b"acknowledgments\n".to_vec()),
+            &mut response,
+        )?;
+        if common.is_empty() {
+            GitCodec.encode(GitMessage::Data(b"NAK\n".to_vec()), &mut response)?;
+        }
+        for have in &common {
+            let ack = format!("ACK {have}\n");
+            GitCodec.encode(GitMessage::Data(ack.into_bytes()), &mut response)?;
+        }
+        GitCodec.encode(GitMessage::Flush, &mut response)?;
+
+        return Ok(response);
+    }
+
+    let pack = build_pack(repo, &wants, &common, include_tags)?;
+    GitCodec.encode(GitMessage::Data(b"packfile\n".to_vec()), &mut response)?;
+    write_sideband(&pack, &mut response)?;
+    GitCodec.encode(GitMessage::Flush, &mut response)?;
+
+    Ok(response)
+}
+
+/// Write pack data as packets on the pack data band
+pub(crate) fn write_sideband(pack: &[u8], response: &mut BytesMut) -> Result<()> {
+    for chunk in pack.chunks(MAX_SIDEBAND_DATA) {
+        let mut data = Vec::with_capacity(chunk.len() + 1);
+        data.push(PACK_BAND);
+        data.extend_from_slice(chunk);
+        GitCodec.encode(GitMessage::Data(data), response)?;
+    }
+
+    Ok(())
+}
+
+/// Only allow fetching objects that are the tip of an advertised ref
+pub(crate) fn check_wants(repo: &git2::Repository, wants: &[git2::Oid]) -> Result<()> {
+    let tips = list_refs(repo)?
+        .into_iter()
+        .flat_map(|r| [Some(r.target), r.peeled])
+        .flatten()
+        .collect::<HashSet<_>>();
+
+    match wants.iter().find(|want| !tips.contains(want)) {
+        Some(want) => Err(Error::Protocol(format!("not our ref {want}"))),
+        None => Ok(()),
+    }
+}
+
+/// Pack every object reachable from `wants` that isn't reachable from `common`, along with the
+/// annotated tags pointing at packed commits when `include_tags` is set
+pub(crate) fn build_pack(
+    repo: &git2::Repository,
+    wants: &[git2::Oid],
+    common: &[git2::Oid],
+    include_tags: bool,
+) -> Result<git2::Buf> {
+    let mut builder = repo.packbuilder()?;
+    let mut walk = repo.revwalk()?;
+
+    for &want in wants {
+        let object = repo.find_object(want, None)?;
+        match object.kind() {
+            Some(git2::ObjectType::Commit) => walk.push(want)?,
+            Some(git2::ObjectType::Tag) => {
+                builder.insert_object(want, None)?;
+                match object.peel(git2::ObjectType::Commit) {
+                    Ok(commit) => walk.push(commit.id())?,
+                    Err(_) => {
+                        builder.insert_recursive(object.peel(git2::ObjectType::Any)?.id(), None)?
+                    }
+                }
+            }
+            _ => builder.insert_recursive(want, None)?,
+        }
+    }
+    for &have in common {
+        // haves that aren't commits can't limit the walk
+        if repo.find_commit(have).is_ok() {
+            walk.hide(have)?;
+        }
+    }
+    builder.insert_walk(&mut walk)?;
+
+    if include_tags {
+        let reachable = |from: &[git2::Oid], commit: git2::Oid| {
+            from.iter()
+                .any(|&tip| tip == commit || repo.graph_descendant_of(tip, commit).unwrap_or(false))
+        };
+        for r in list_refs(repo)? {
+            let Some(peeled) = r.peeled else {
+                continue;
+            };
+            if reachable(wants, peeled) && !reachable(common, peeled) {
+                builder.insert_object(r.target, None)?;
+            }
+        }
+    }
+
+    let mut pack = git2::Buf::new();
+    builder.write_buf(&mut pack)?;
+
+    Ok(pack)
+}
+
+pub(crate) fn parse_line(data: &[u8]) -> Result<&str> {
+    let line =
+        std::str::from_utf8(data).map_err(|_| Error::Protocol("invalid utf-8".to_string()))?;
+
+    Ok(line.strip_suffix('\n').unwrap_or(line))
+}
+
+pub(crate) fn parse_oid(oid: &str) -> Result<git2::Oid> {
+    // libgit2 would accept and pad abbreviated ids
+    match oid.len() {
+        40 => git2::Oid::from_str(oid)
+            .map_err(|_| Error::Protocol(format!("invalid object id {oid}"))),
+        _ => Err(Error::Protocol(format!("invalid object id {oid}"))),
+    }
+}
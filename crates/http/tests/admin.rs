@@ -0,0 +1,131 @@
+//! Requests against the admin routes of the server
+mod common;
+
+use axum::{
+    http::{Method, StatusCode},
+    Router,
+};
+use common::{authorized, request, send, tf_lock, tf_state};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use thoenix_http::{audit::AUDIT_LOG, Server};
+
+const TOKEN: &str = "admin-secret";
+
+async fn routes(token: Option<&str>) -> (TempDir, Router) {
+    let dir = tempfile::tempdir().unwrap();
+    let router = Server::new(dir.path().to_path_buf())
+        .with_admin_token(token.map(str::to_string))
+        .router()
+        .await
+        .unwrap();
+
+    (dir, router)
+}
+
+async fn admin(router: &Router, method: Method, uri: &str, body: &str) -> (StatusCode, Value) {
+    let request = authorized(
+        request(method, uri, body.to_string()),
+        &format!("Bearer {TOKEN}"),
+    );
+    let (status, _, body) = send(router, request).await;
+
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn rejects_requests_without_the_admin_token() {
+    let (_dir, router) = routes(Some(TOKEN)).await;
+
+    for authorization in [
+        None,
+        Some("Bearer admin-secrets"),
+        Some("Basic admin-secret"),
+    ] {
+        for (method, uri) in [
+            (Method::GET, "/admin/tf/locks"),
+            (Method::DELETE, "/admin/tf/lock/network"),
+        ] {
+            let mut request = request(method.clone(), uri, r#"{"who":"mallory"}"#);
+            if let Some(authorization) = authorization {
+                request = authorized(request, authorization);
+            }
+            let (status, _, _) = send(&router, request).await;
+
+            assert_eq!(
+                status,
+                StatusCode::FORBIDDEN,
+                "{method} {uri} {authorization:?}"
+            );
+        }
+    }
+
+    let (_dir, disabled) = routes(None).await;
+    let (status, _) = admin(&disabled, Method::GET, "/admin/tf/locks", "").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn lists_and_force_unlocks_locks() {
+    let (dir, router) = routes(Some(TOKEN)).await;
+    for id in ["network", "dns"] {
+        let uri = format!("/tf/lock/{id}");
+        let (status, _, _) = send(&router, request(Method::PUT, &uri, tf_lock(id))).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let (status, locks) = admin(&router, Method::GET, "/admin/tf/locks", "").await;
+    assert_eq!(status, StatusCode::OK);
+    let locks = locks
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| (l["id"].as_str().unwrap(), l["lock"]["ID"].as_str().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(locks, [("dns", "dns"), ("network", "network")]);
+
+    let (status, record) = admin(
+        &router,
+        Method::DELETE,
+        "/admin/tf/lock/network",
+        r#"{"who":"alice"}"#,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(record["state"], "network");
+    assert_eq!(record["lock"]["ID"], "network");
+    assert_eq!(record["by"], "alice");
+
+    let (_, locks) = admin(&router, Method::GET, "/admin/tf/locks", "").await;
+    assert_eq!(locks.as_array().unwrap().len(), 1);
+    assert_eq!(locks[0]["id"], "dns");
+
+    let log = std::fs::read_to_string(dir.path().join(AUDIT_LOG)).unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(serde_json::from_str::<Value>(lines[0]).unwrap(), record);
+}
+
+#[tokio::test]
+async fn force_unlocking_a_state_that_is_not_locked_fails() {
+    let (dir, router) = routes(Some(TOKEN)).await;
+    let body = json!({ "who": "alice" }).to_string();
+
+    let (status, _) = admin(&router, Method::DELETE, "/admin/tf/lock/network", &body).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _, _) = send(
+        &router,
+        request(
+            Method::POST,
+            "/tf/state/network?ID=",
+            tf_state(1, json!([])),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = admin(&router, Method::DELETE, "/admin/tf/lock/network", &body).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    assert!(!dir.path().join(AUDIT_LOG).exists());
+}
@@ -0,0 +1,89 @@
+use axum::http::{header::AUTHORIZATION, HeaderMap, HeaderValue};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use thoenix_http::{
+    auth::{hash_password, hash_token, Credentials, Identity},
+    error::Error,
+};
+
+fn credentials() -> Credentials {
+    let file = format!(
+        "# people\nalice password {}\n\nci token {}\n",
+        hash_password("hunter2").unwrap(),
+        hash_token("ci-secret"),
+    );
+
+    Credentials::parse(&file).unwrap()
+}
+
+fn authorization(value: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+    headers
+}
+
+fn basic(username: &str, password: &str) -> HeaderMap {
+    authorization(&format!(
+        "Basic {}",
+        BASE64.encode(format!("{username}:{password}"))
+    ))
+}
+
+#[test]
+fn passwords() {
+    let credentials = credentials();
+
+    assert_eq!(
+        credentials
+            .authenticate(&basic("alice", "hunter2"))
+            .unwrap(),
+        Identity::user("alice")
+    );
+    for headers in [
+        basic("alice", "hunter3"),
+        basic("bob", "hunter2"),
+        authorization("Bearer hunter2"),
+        HeaderMap::new(),
+    ] {
+        assert!(matches!(
+            credentials.authenticate(&headers),
+            Err(Error::Unauthenticated)
+        ));
+    }
+}
+
+#[test]
+fn tokens() {
+    let credentials = credentials();
+
+    assert_eq!(
+        credentials.authenticate(&basic("ci", "ci-secret")).unwrap(),
+        Identity::user("ci")
+    );
+    assert_eq!(
+        credentials
+            .authenticate(&authorization("Bearer ci-secret"))
+            .unwrap(),
+        Identity::user("ci")
+    );
+    assert!(matches!(
+        credentials.authenticate(&basic("alice", "ci-secret")),
+        Err(Error::Unauthenticated)
+    ));
+}
+
+#[test]
+fn invalid_files() {
+    for file in [
+        "alice",
+        "alice password not-a-hash",
+        "ci token abc",
+        "alice secret abc",
+        "a:b token 00",
+        &format!("ci token {0}\nci token {0}", hash_token("x")),
+    ] {
+        assert!(
+            matches!(Credentials::parse(file), Err(Error::InvalidCredentials(_))),
+            "{file}"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use bytes::BytesMut;
+use thoenix_http::{
+    codec::{PktLineCodec, PktLineMessage},
+    message::{GitCodec, GitMessage, GitService},
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[test]
+fn pkt_lines_round_trip() {
+    let messages = [
+        PktLineMessage::Data(b"command=ls-refs\n".to_vec()),
+        PktLineMessage::Delim,
+        PktLineMessage::Data(b"peel\n".to_vec()),
+        PktLineMessage::Flush,
+        PktLineMessage::ResponseEnd,
+    ];
+
+    let mut buf = BytesMut::new();
+    for message in messages.clone() {
+        PktLineCodec.encode(message, &mut buf).unwrap();
+    }
+    assert_eq!(
+        &buf[..],
+        b"0014command=ls-refs\n00010009peel\n00000002".as_slice()
+    );
+
+    let mut decoded = Vec::new();
+    while let Some(message) = PktLineCodec.decode(&mut buf).unwrap() {
+        decoded.push(message);
+    }
+    assert_eq!(decoded, messages);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn git_messages_round_trip() {
+    let messages = [
+        GitMessage::ServiceHeader(GitService::UploadPack),
+        GitMessage::ServiceHeader(GitService::ReceivePack),
+        GitMessage::Data(b"version 2\n".to_vec()),
+        GitMessage::Delim,
+        GitMessage::Flush,
+        GitMessage::ResponseEnd,
+    ];
+
+    let mut buf = BytesMut::new();
+    for message in messages {
+        GitCodec.encode(message, &mut buf).unwrap();
+    }
+
+    let mut decoded = Vec::new();
+    while let Some(message) = GitCodec.decode(&mut buf).unwrap() {
+        decoded.push(message);
+    }
+    assert_eq!(
+        decoded,
+        [
+            GitMessage::ServiceHeader(GitService::UploadPack),
+            GitMessage::ServiceHeader(GitService::ReceivePack),
+            GitMessage::Data(b"version 2\n".to_vec()),
+            GitMessage::Delim,
+            GitMessage::Flush,
+            GitMessage::ResponseEnd,
+        ]
+    );
+}
+
+#[test]
+fn waits_for_the_whole_pkt_line() {
+    let mut buf = BytesMut::from(b"000".as_slice());
+    assert_eq!(PktLineCodec.decode(&mut buf).unwrap(), None);
+
+    buf.extend_from_slice(b"8do");
+    assert_eq!(PktLineCodec.decode(&mut buf).unwrap(), None);
+
+    buf.extend_from_slice(b"ne");
+    assert_eq!(
+        PktLineCodec.decode(&mut buf).unwrap(),
+        Some(PktLineMessage::Data(b"done".to_vec()))
+    );
+}
+
+#[test]
+fn rejects_invalid_lengths() {
+    for line in [b"0003".as_slice(), b"zzzz"] {
+        let mut buf = BytesMut::from(line);
+        assert!(PktLineCodec.decode(&mut buf).is_err());
+    }
+}
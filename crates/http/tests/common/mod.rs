@@ -0,0 +1,225 @@
+//! Repositories, pkt-line and request helpers shared by the http tests.
+#![allow(dead_code)]
+
+use axum::{
+    body::Body,
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderMap, Method, Request, StatusCode,
+    },
+    Router,
+};
+use bytes::BytesMut;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use thoenix_http::message::{GitCodec, GitMessage};
+use tokio_util::codec::{Decoder, Encoder};
+use tower::ServiceExt;
+
+/// A bare repository at `<dir>/owner/configs.git` with two commits on `main`, an annotated tag
+/// of the first commit and a terraform state stored alongside them
+pub struct Fixture {
+    pub dir: TempDir,
+    pub repo: git2::Repository,
+    pub first: git2::Oid,
+    pub second: git2::Oid,
+    pub tag: git2::Oid,
+    pub state: git2::Oid,
+}
+
+pub fn fixture() -> Fixture {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_bare(dir.path().join("owner/configs.git")).unwrap();
+
+    let first = commit(&repo, "main.tf", b"# first\n", &[]);
+    let second = commit(&repo, "main.tf", b"# second\n", &[first]);
+    repo.reference("refs/heads/main", second, true, "test")
+        .unwrap();
+    repo.set_head("refs/heads/main").unwrap();
+
+    let signature = signature();
+    let tag = repo
+        .tag(
+            "v1",
+            &repo.find_object(first, None).unwrap(),
+            &signature,
+            "v1",
+            false,
+        )
+        .unwrap();
+
+    let state = commit(&repo, "terraform.tfstate", b"{}", &[]);
+    repo.reference("refs/thoenix/state/network", state, true, "test")
+        .unwrap();
+
+    Fixture {
+        dir,
+        repo,
+        first,
+        second,
+        tag,
+        state,
+    }
+}
+
+fn signature() -> git2::Signature<'static> {
+    git2::Signature::now("test", "test@localhost").unwrap()
+}
+
+/// Commit a tree holding a single file
+pub fn commit(
+    repo: &git2::Repository,
+    path: &str,
+    data: &[u8],
+    parents: &[git2::Oid],
+) -> git2::Oid {
+    let blob = repo.blob(data).unwrap();
+    let mut tree = repo.treebuilder(None).unwrap();
+    tree.insert(path, blob, git2::FileMode::Blob.into())
+        .unwrap();
+    let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+
+    let parents = parents
+        .iter()
+        .map(|parent| repo.find_commit(*parent).unwrap())
+        .collect::<Vec<_>>();
+    let signature = signature();
+    repo.commit(
+        None,
+        &signature,
+        &signature,
+        path,
+        &tree,
+        &parents.iter().collect::<Vec<_>>(),
+    )
+    .unwrap()
+}
+
+/// A pkt-line holding `line`
+pub fn data(line: &str) -> GitMessage {
+    GitMessage::Data(line.as_bytes().to_vec())
+}
+
+pub fn encode(messages: Vec<GitMessage>) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    for message in messages {
+        GitCodec.encode(message, &mut buf).unwrap();
+    }
+
+    buf.to_vec()
+}
+
+/// Decode a response that consists of nothing but pkt-lines
+pub fn decode(response: &[u8]) -> Vec<GitMessage> {
+    let mut buf = BytesMut::from(response);
+    let mut messages = Vec::new();
+    while let Some(message) = GitCodec.decode(&mut buf).unwrap() {
+        messages.push(message);
+    }
+    assert!(buf.is_empty(), "trailing data {buf:?}");
+
+    messages
+}
+
+/// The text of every data pkt-line in a response, without the trailing newline
+pub fn lines(response: &[u8]) -> Vec<String> {
+    decode(response)
+        .into_iter()
+        .filter_map(|message| match message {
+            GitMessage::Data(data) => Some(
+                String::from_utf8_lossy(&data)
+                    .trim_end_matches('\n')
+                    .to_string(),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Join the pack data sent on side-band 1
+pub fn sideband_pack(messages: &[GitMessage]) -> Vec<u8> {
+    messages
+        .iter()
+        .filter_map(|message| match message {
+            GitMessage::Data(data) if data.first() == Some(&1) => Some(&data[1..]),
+            _ => None,
+        })
+        .flatten()
+        .copied()
+        .collect()
+}
+
+/// The ids of every object in a pack
+pub fn pack_objects(pack: &[u8]) -> Vec<git2::Oid> {
+    assert_eq!(&pack[..4], b"PACK");
+
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_bare(dir.path()).unwrap();
+    let odb = repo.odb().unwrap();
+    let mut writer = odb.packwriter().unwrap();
+    std::io::Write::write_all(&mut writer, pack).unwrap();
+    writer.commit().unwrap();
+
+    let mut objects = Vec::new();
+    odb.foreach(|oid| {
+        objects.push(*oid);
+        true
+    })
+    .unwrap();
+
+    objects
+}
+
+/// Send a request to the routes of a server, returning the status, headers and body of the response
+pub async fn send(router: &Router, request: Request<Body>) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let response = router.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+    (status, headers, body.to_vec())
+}
+
+/// A request with a JSON body, as terraform sends them
+pub fn request(method: Method, uri: &str, body: impl Into<Body>) -> Request<Body> {
+    Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+/// `request` with the given `Authorization` header
+pub fn authorized(mut request: Request<Body>, authorization: &str) -> Request<Body> {
+    request
+        .headers_mut()
+        .insert(AUTHORIZATION, authorization.parse().unwrap());
+    request
+}
+
+/// A terraform state document holding `resources`
+pub fn tf_state(serial: u64, resources: Value) -> String {
+    json!({
+        "version": 4,
+        "terraform_version": "1.6.0",
+        "serial": serial,
+        "lineage": "test",
+        "outputs": {},
+        "resources": resources,
+    })
+    .to_string()
+}
+
+/// The lock document terraform sends to lock and unlock a state
+pub fn tf_lock(id: &str) -> String {
+    json!({
+        "ID": id,
+        "Operation": "OperationTypeApply",
+        "Info": "",
+        "Who": "test@localhost",
+        "Version": "1.6.0",
+        "Path": "",
+    })
+    .to_string()
+}
@@ -0,0 +1,202 @@
+//! Requests against the git routes of the server
+mod common;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+};
+use common::{data, decode, encode, fixture, lines};
+use std::io::Write;
+use thoenix_http::{
+    message::{GitMessage, GitService},
+    Server,
+};
+use tower::ServiceExt;
+
+async fn send(server: Server, request: Request<Body>) -> (StatusCode, Vec<u8>) {
+    let response = server
+        .router()
+        .await
+        .unwrap()
+        .oneshot(request)
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+    (status, body.to_vec())
+}
+
+fn get(uri: &str) -> Request<Body> {
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn post(uri: &str, encoding: &str, body: Vec<u8>) -> Request<Body> {
+    Request::post(uri)
+        .header("content-encoding", encoding)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn advertises_refs_without_state_refs() {
+    let fixture = fixture();
+    let server = Server::new(fixture.dir.path().to_path_buf());
+
+    let (status, body) = send(
+        server,
+        get("/configs/owner/configs.git/info/refs?service=git-upload-pack"),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(matches!(
+        decode(&body)[0],
+        GitMessage::ServiceHeader(GitService::UploadPack)
+    ));
+    let lines = lines(&body);
+    assert!(lines[0].starts_with(&format!("{} HEAD\0", fixture.second)));
+    assert!(lines.iter().all(|line| !line.contains("refs/thoenix/")));
+}
+
+#[tokio::test]
+async fn rejects_repositories_outside_of_the_data_dir() {
+    let fixture = fixture();
+
+    for uri in [
+        "/configs/%2e%2e/configs.git/info/refs?service=git-upload-pack",
+        "/configs/owner/%2e%2e%2fowner%2fconfigs.git/info/refs?service=git-upload-pack",
+        "/configs/%2fetc/configs.git/info/refs?service=git-upload-pack",
+    ] {
+        let server = Server::new(fixture.dir.path().join("owner"));
+        let (status, _) = send(server, get(uri)).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn missing_repositories_are_not_found() {
+    let fixture = fixture();
+    let server = Server::new(fixture.dir.path().to_path_buf());
+
+    let (status, body) = send(
+        server,
+        get("/configs/owner/missing.git/info/refs?service=git-upload-pack"),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.contains("owner/missing.git"));
+    assert!(!body.contains(&fixture.dir.path().display().to_string()));
+}
+
+#[tokio::test]
+async fn negotiates_gzip_compressed_fetches() {
+    let fixture = fixture();
+    let request = encode(vec![
+        data(&format!(
+            "want {} multi_ack_detailed side-band-64k\n",
+            fixture.second
+        )),
+        GitMessage::Flush,
+        data(&format!("have {}\n", fixture.first)),
+        GitMessage::Flush,
+    ]);
+
+    let (status, body) = send(
+        Server::new(fixture.dir.path().to_path_buf()),
+        post(
+            "/configs/owner/configs.git/git-upload-pack",
+            "gzip",
+            gzip(&request),
+        ),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        lines(&body),
+        [format!("ACK {} common", fixture.first), "NAK".to_string()]
+    );
+}
+
+#[tokio::test]
+async fn runs_gzip_compressed_v2_commands() {
+    let fixture = fixture();
+    let request = encode(vec![
+        data("command=ls-refs\n"),
+        GitMessage::Delim,
+        data("ref-prefix refs/heads/\n"),
+        GitMessage::Flush,
+    ]);
+
+    let mut request = post(
+        "/configs/owner/configs.git/git-upload-pack",
+        "gzip",
+        gzip(&request),
+    );
+    request
+        .headers_mut()
+        .insert("git-protocol", "version=2".parse().unwrap());
+    let (status, body) = send(Server::new(fixture.dir.path().to_path_buf()), request).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        lines(&body),
+        [format!("{} refs/heads/main", fixture.second)]
+    );
+}
+
+#[tokio::test]
+async fn receives_gzip_compressed_pushes() {
+    let fixture = fixture();
+    let tag = fixture.repo.find_reference("refs/tags/v1").unwrap();
+    let request = encode(vec![
+        data(&format!(
+            "{} {} refs/tags/v1\0report-status delete-refs\n",
+            tag.target().unwrap(),
+            git2::Oid::zero()
+        )),
+        GitMessage::Flush,
+    ]);
+
+    let (status, body) = send(
+        Server::new(fixture.dir.path().to_path_buf()),
+        post(
+            "/configs/owner/configs.git/git-receive-pack",
+            "gzip",
+            gzip(&request),
+        ),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(lines(&body), ["unpack ok", "ok refs/tags/v1"]);
+    assert!(fixture.repo.find_reference("refs/tags/v1").is_err());
+}
+
+#[tokio::test]
+async fn rejects_unsupported_encodings() {
+    let fixture = fixture();
+
+    for uri in [
+        "/configs/owner/configs.git/git-upload-pack",
+        "/configs/owner/configs.git/git-receive-pack",
+    ] {
+        let (status, _) = send(
+            Server::new(fixture.dir.path().to_path_buf()),
+            post(uri, "br", encode(vec![GitMessage::Flush])),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE, "{uri}");
+    }
+}
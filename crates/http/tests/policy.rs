@@ -0,0 +1,94 @@
+use thoenix_http::{
+    auth::Identity,
+    error::Error,
+    policy::{Permission, Policy},
+};
+
+const POLICY: &str = r#"{
+    "rules": [
+        { "users": ["deploy"], "states": ["prod-*"], "permissions": ["read", "write", "lock", "unlock"] },
+        { "users": ["alice"], "states": ["dev-*", "staging"], "permissions": ["read", "write", "lock", "unlock"] },
+        { "users": ["dashboard", "alice"], "states": ["*"], "permissions": ["read"] },
+        { "users": ["*"], "states": ["public"], "permissions": ["read"] },
+        { "users": ["deploy"], "states": ["prod-*"], "permissions": ["sensitive"] }
+    ]
+}"#;
+
+#[test]
+fn default_allows_everything() {
+    let policy = Policy::default();
+
+    assert!(policy.allows(None, "prod-network", Permission::Write));
+    assert!(policy.allows(Some(&Identity::user("anyone")), "x", Permission::Unlock));
+    assert!(!policy.allows(None, "x", Permission::Sensitive));
+}
+
+#[test]
+fn sensitive_outputs_need_a_rule() {
+    let policy = Policy::parse(POLICY).unwrap();
+
+    assert!(policy.allows(
+        Some(&Identity::user("deploy")),
+        "prod-network",
+        Permission::Sensitive
+    ));
+    assert!(!policy.allows(
+        Some(&Identity::user("deploy")),
+        "dev-network",
+        Permission::Sensitive
+    ));
+    assert!(!policy.allows(
+        Some(&Identity::user("alice")),
+        "staging",
+        Permission::Sensitive
+    ));
+}
+
+#[test]
+fn rules() {
+    let policy = Policy::parse(POLICY).unwrap();
+    let deploy = Identity::user("deploy");
+    let alice = Identity::user("alice");
+    let dashboard = Identity::user("dashboard");
+
+    assert!(policy.allows(Some(&deploy), "prod-network", Permission::Write));
+    assert!(!policy.allows(Some(&deploy), "dev-network", Permission::Read));
+
+    assert!(policy.allows(Some(&alice), "staging", Permission::Lock));
+    assert!(policy.allows(Some(&alice), "prod-network", Permission::Read));
+    assert!(!policy.allows(Some(&alice), "prod-network", Permission::Write));
+
+    for permission in [Permission::Write, Permission::Lock, Permission::Unlock] {
+        assert!(!policy.allows(Some(&dashboard), "dev-network", permission));
+    }
+    assert!(policy.allows(Some(&dashboard), "dev-network", Permission::Read));
+
+    assert!(policy.allows(None, "public", Permission::Read));
+    assert!(!policy.allows(None, "prod-network", Permission::Read));
+    assert!(matches!(
+        policy.check(Some(&dashboard), "prod-network", Permission::Write),
+        Err(Error::Forbidden {
+            permission: Permission::Write,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn empty_policy_denies_everything() {
+    let policy = Policy::parse(r#"{"rules": []}"#).unwrap();
+
+    assert!(!policy.allows(None, "x", Permission::Read));
+    assert!(!policy.allows(Some(&Identity::user("alice")), "x", Permission::Read));
+}
+
+#[test]
+fn invalid_policies() {
+    for policy in [
+        r#"{"rules": [{"users": ["a"], "states": ["["], "permissions": ["read"]}]}"#,
+        r#"{"rules": [{"users": ["a"], "states": ["*"], "permissions": ["delete"]}]}"#,
+        r#"{"rule": []}"#,
+    ] {
+        assert!(Policy::parse(policy).is_err(), "{policy}");
+    }
+}
@@ -0,0 +1,205 @@
+mod common;
+
+use bytes::BytesMut;
+use common::{data, decode, encode, fixture, lines, pack_objects, sideband_pack};
+use thoenix_http::{error::Error, message::GitMessage, upload_pack};
+
+fn command(name: &str, arguments: &[&str]) -> Vec<u8> {
+    let mut messages = vec![
+        data(&format!("command={name}\n")),
+        data("agent=git/2.45.0\n"),
+        data("object-format=sha1\n"),
+        GitMessage::Delim,
+    ];
+    messages.extend(
+        arguments
+            .iter()
+            .map(|argument| data(&format!("{argument}\n"))),
+    );
+    messages.push(GitMessage::Flush);
+
+    encode(messages)
+}
+
+#[test]
+fn advertises_capabilities() {
+    let mut buf = BytesMut::new();
+    upload_pack::advertise_capabilities(&mut buf).unwrap();
+
+    assert_eq!(
+        lines(&buf),
+        [
+            "version 2",
+            "agent=git/thoenix",
+            "ls-refs",
+            "fetch",
+            "object-format=sha1"
+        ]
+    );
+    assert_eq!(decode(&buf).last(), Some(&GitMessage::Flush));
+}
+
+#[test]
+fn lists_refs() {
+    let fixture = fixture();
+
+    let response = upload_pack::run_command(&fixture.repo, &command("ls-refs", &[])).unwrap();
+
+    let tag = fixture.repo.find_tag(fixture.tag).unwrap();
+    assert_eq!(
+        lines(&response),
+        [
+            format!("{} HEAD", fixture.second),
+            format!("{} refs/heads/main", fixture.second),
+            format!("{} refs/tags/v1", tag.id()),
+        ]
+    );
+    assert_eq!(decode(&response).last(), Some(&GitMessage::Flush));
+}
+
+#[test]
+fn lists_refs_with_symrefs_and_peeled_tags() {
+    let fixture = fixture();
+
+    let response = upload_pack::run_command(
+        &fixture.repo,
+        &command("ls-refs", &["peel", "symrefs", "unborn"]),
+    )
+    .unwrap();
+
+    let tag = fixture.repo.find_tag(fixture.tag).unwrap();
+    assert_eq!(
+        lines(&response),
+        [
+            format!("{} HEAD symref-target:refs/heads/main", fixture.second),
+            format!("{} refs/heads/main", fixture.second),
+            format!("{} refs/tags/v1 peeled:{}", tag.id(), fixture.first),
+        ]
+    );
+}
+
+#[test]
+fn lists_refs_matching_a_prefix() {
+    let fixture = fixture();
+
+    let response = upload_pack::run_command(
+        &fixture.repo,
+        &command(
+            "ls-refs",
+            &["ref-prefix refs/tags/", "ref-prefix refs/thoenix/"],
+        ),
+    )
+    .unwrap();
+
+    let tag = fixture.repo.find_tag(fixture.tag).unwrap();
+    assert_eq!(lines(&response), [format!("{} refs/tags/v1", tag.id())]);
+}
+
+#[test]
+fn acknowledges_common_haves() {
+    let fixture = fixture();
+    let unknown = "1234567890123456789012345678901234567890";
+
+    let response = upload_pack::run_command(
+        &fixture.repo,
+        &command(
+            "fetch",
+            &[
+                &format!("want {}", fixture.second),
+                &format!("have {unknown}"),
+                &format!("have {}", fixture.first),
+            ],
+        ),
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines(&response),
+        [
+            "acknowledgments".to_string(),
+            format!("ACK {}", fixture.first)
+        ]
+    );
+    assert_eq!(decode(&response).last(), Some(&GitMessage::Flush));
+}
+
+#[test]
+fn acknowledges_no_common_haves_with_a_nak() {
+    let fixture = fixture();
+
+    let response = upload_pack::run_command(
+        &fixture.repo,
+        &command(
+            "fetch",
+            &[
+                &format!("want {}", fixture.second),
+                "have 1234567890123456789012345678901234567890",
+            ],
+        ),
+    )
+    .unwrap();
+
+    assert_eq!(lines(&response), ["acknowledgments", "NAK"]);
+}
+
+#[test]
+fn sends_the_packfile_once_done() {
+    let fixture = fixture();
+
+    let response = upload_pack::run_command(
+        &fixture.repo,
+        &command(
+            "fetch",
+            &[
+                &format!("want {}", fixture.second),
+                &format!("have {}", fixture.first),
+                "ofs-delta",
+                "no-progress",
+                "done",
+            ],
+        ),
+    )
+    .unwrap();
+    let messages = decode(&response);
+
+    assert_eq!(messages[0], data("packfile\n"));
+    let objects = pack_objects(&sideband_pack(&messages[1..]));
+    // the second commit, its tree and blob, everything else is common
+    assert_eq!(objects.len(), 3);
+    assert!(objects.contains(&fixture.second));
+    assert_eq!(messages.last(), Some(&GitMessage::Flush));
+}
+
+#[test]
+fn includes_tags_pointing_at_sent_objects() {
+    let fixture = fixture();
+
+    let response = upload_pack::run_command(
+        &fixture.repo,
+        &command(
+            "fetch",
+            &[&format!("want {}", fixture.second), "include-tag", "done"],
+        ),
+    )
+    .unwrap();
+
+    let objects = pack_objects(&sideband_pack(&decode(&response)));
+    assert!(objects.contains(&fixture.tag));
+    assert!(objects.contains(&fixture.first));
+}
+
+#[test]
+fn rejects_unknown_commands_and_hidden_wants() {
+    let fixture = fixture();
+
+    let result = upload_pack::run_command(&fixture.repo, &command("bundle-uri", &[]));
+    assert!(
+        matches!(result, Err(Error::Protocol(message)) if message == "unknown command bundle-uri")
+    );
+
+    let result = upload_pack::run_command(
+        &fixture.repo,
+        &command("fetch", &[&format!("want {}", fixture.state), "done"]),
+    );
+    assert!(matches!(result, Err(Error::Protocol(message)) if message.starts_with("not our ref")));
+}
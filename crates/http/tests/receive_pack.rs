@@ -0,0 +1,447 @@
+mod common;
+
+use bytes::{Bytes, BytesMut};
+use common::{commit, data, decode, encode, fixture, lines, Fixture};
+use std::{cell::RefCell, io::Read, rc::Rc};
+use thoenix_http::{error::Error, message::GitMessage, receive_pack};
+
+const ZERO: &str = "0000000000000000000000000000000000000000";
+
+/// A pack of everything reachable from `new` that isn't reachable from `old`
+fn pack(repo: &git2::Repository, new: git2::Oid, old: Option<git2::Oid>) -> Vec<u8> {
+    let mut walk = repo.revwalk().unwrap();
+    walk.push(new).unwrap();
+    if let Some(old) = old {
+        walk.hide(old).unwrap();
+    }
+
+    let mut builder = repo.packbuilder().unwrap();
+    builder.insert_walk(&mut walk).unwrap();
+    let mut buf = git2::Buf::new();
+    builder.write_buf(&mut buf).unwrap();
+
+    buf.to_vec()
+}
+
+/// Commit on top of `main` in the fixture repository, without storing anything in it
+fn next_commit(fixture: &Fixture) -> (git2::Repository, git2::Oid) {
+    let dir = fixture.dir.path().join("client.git");
+    let client = git2::Repository::init_bare(dir).unwrap();
+    client
+        .remote_anonymous(fixture.repo.path().to_str().unwrap())
+        .unwrap()
+        .fetch(&["refs/heads/main:refs/heads/main"], None, None)
+        .unwrap();
+    let third = commit(&client, "main.tf", b"# third\n", &[fixture.second]);
+
+    (client, third)
+}
+
+/// Send a push and return everything the server wrote back
+fn push(repo: &git2::Repository, commands: &[String], pack: &[u8]) -> Result<Vec<u8>, Error> {
+    push_limited(repo, commands, pack, None)
+}
+
+fn push_limited(
+    repo: &git2::Repository,
+    commands: &[String],
+    pack: &[u8],
+    max_size: Option<u64>,
+) -> Result<Vec<u8>, Error> {
+    let mut messages = commands
+        .iter()
+        .map(|command| data(&format!("{command}\n")))
+        .collect::<Vec<_>>();
+    messages.push(GitMessage::Flush);
+    let mut request = encode(messages);
+    request.extend_from_slice(pack);
+
+    let response = Rc::new(RefCell::new(BytesMut::new()));
+    let output = response.clone();
+    let mut body = receive_pack::PushLimit::new(request.as_slice(), max_size);
+    receive_pack::Push::read(&mut body)?.apply(repo, &mut body, move |data: Bytes| {
+        output.borrow_mut().extend_from_slice(&data)
+    })?;
+
+    let response = response.borrow().to_vec();
+    Ok(response)
+}
+
+fn reference(repo: &git2::Repository, name: &str) -> Option<git2::Oid> {
+    repo.find_reference(name).ok().and_then(|r| r.target())
+}
+
+#[test]
+fn advertises_refs_without_head_and_state_refs() {
+    let fixture = fixture();
+
+    let mut buf = BytesMut::new();
+    receive_pack::advertise_refs(&fixture.repo, &mut buf).unwrap();
+    let lines = lines(&buf);
+
+    let (main, capabilities) = lines[0].split_once('\0').unwrap();
+    assert_eq!(main, format!("{} refs/heads/main", fixture.second));
+    assert!(capabilities.contains("report-status"));
+    assert!(capabilities.contains("atomic"));
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].ends_with(" refs/tags/v1"));
+}
+
+#[test]
+fn updates_refs_and_reports_their_status() {
+    let fixture = fixture();
+    let (client, third) = next_commit(&fixture);
+
+    let response = push(
+        &fixture.repo,
+        &[
+            format!("{} {third} refs/heads/main\0report-status", fixture.second),
+            format!("{ZERO} {} refs/heads/feature", fixture.second),
+        ],
+        &pack(&client, third, Some(fixture.second)),
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines(&response),
+        ["unpack ok", "ok refs/heads/main", "ok refs/heads/feature"]
+    );
+    assert_eq!(reference(&fixture.repo, "refs/heads/main"), Some(third));
+    assert_eq!(
+        reference(&fixture.repo, "refs/heads/feature"),
+        Some(fixture.second)
+    );
+}
+
+#[test]
+fn reports_nothing_without_report_status() {
+    let fixture = fixture();
+    let (client, third) = next_commit(&fixture);
+
+    let response = push(
+        &fixture.repo,
+        &[format!("{} {third} refs/heads/main", fixture.second)],
+        &pack(&client, third, Some(fixture.second)),
+    )
+    .unwrap();
+
+    assert!(response.is_empty());
+    assert_eq!(reference(&fixture.repo, "refs/heads/main"), Some(third));
+}
+
+#[test]
+fn deletes_refs_without_a_pack() {
+    let fixture = fixture();
+    let tag = reference(&fixture.repo, "refs/tags/v1").unwrap();
+
+    let response = push(
+        &fixture.repo,
+        &[format!(
+            "{tag} {ZERO} refs/tags/v1\0report-status delete-refs"
+        )],
+        &[],
+    )
+    .unwrap();
+
+    assert_eq!(lines(&response), ["unpack ok", "ok refs/tags/v1"]);
+    assert_eq!(reference(&fixture.repo, "refs/tags/v1"), None);
+}
+
+#[test]
+fn an_empty_push_does_nothing() {
+    let fixture = fixture();
+
+    let response = push(&fixture.repo, &[], &[]).unwrap();
+
+    assert!(response.is_empty());
+}
+
+#[test]
+fn rejects_malformed_commands() {
+    let fixture = fixture();
+
+    let result = push(&fixture.repo, &[format!("{ZERO} refs/heads/main")], &[]);
+    assert!(
+        matches!(result, Err(Error::Protocol(message)) if message.starts_with("expected a command"))
+    );
+
+    let result = push(
+        &fixture.repo,
+        &[format!("{ZERO} main refs/heads/main")],
+        &[],
+    );
+    assert!(result.is_err());
+}
+
+#[test]
+fn rejects_funny_and_hidden_refnames() {
+    let fixture = fixture();
+    let (client, third) = next_commit(&fixture);
+
+    let response = push(
+        &fixture.repo,
+        &[
+            format!("{ZERO} {third} main\0report-status"),
+            format!("{} {third} refs/thoenix/state/network", fixture.state),
+            format!("{ZERO} {third} refs/heads/a..b"),
+        ],
+        &pack(&client, third, Some(fixture.second)),
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines(&response),
+        [
+            "unpack ok",
+            "ng main funny refname",
+            "ng refs/thoenix/state/network deny updating a hidden ref",
+            "ng refs/heads/a..b funny refname",
+        ]
+    );
+    assert_eq!(
+        reference(&fixture.repo, "refs/thoenix/state/network"),
+        Some(fixture.state)
+    );
+}
+
+#[test]
+fn rejects_updates_missing_objects() {
+    let fixture = fixture();
+    let (client, third) = next_commit(&fixture);
+
+    // only the commit, without its tree and blob
+    let mut builder = client.packbuilder().unwrap();
+    builder.insert_object(third, None).unwrap();
+    let mut buf = git2::Buf::new();
+    builder.write_buf(&mut buf).unwrap();
+
+    let response = push(
+        &fixture.repo,
+        &[format!(
+            "{} {third} refs/heads/main\0report-status",
+            fixture.second
+        )],
+        &buf,
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines(&response),
+        ["unpack ok", "ng refs/heads/main missing necessary objects"]
+    );
+    assert_eq!(
+        reference(&fixture.repo, "refs/heads/main"),
+        Some(fixture.second)
+    );
+}
+
+#[test]
+fn reports_a_missing_pack() {
+    let fixture = fixture();
+    let (_, third) = next_commit(&fixture);
+
+    let response = push(
+        &fixture.repo,
+        &[format!(
+            "{} {third} refs/heads/main\0report-status",
+            fixture.second
+        )],
+        b"KCAP",
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines(&response),
+        ["unpack missing pack", "ng refs/heads/main unpacker error"]
+    );
+}
+
+/// Update `main` from an outdated commit and create `feature`
+fn stale_push(fixture: &Fixture, capabilities: &str) -> Vec<String> {
+    let (client, third) = next_commit(fixture);
+
+    let response = push(
+        &fixture.repo,
+        &[
+            format!("{} {third} refs/heads/main\0{capabilities}", fixture.first),
+            format!("{ZERO} {third} refs/heads/feature"),
+        ],
+        &pack(&client, third, Some(fixture.second)),
+    )
+    .unwrap();
+
+    lines(&response)
+}
+
+#[test]
+fn updates_the_other_refs_of_a_push_that_is_not_atomic() {
+    let fixture = fixture();
+
+    let lines = stale_push(&fixture, "report-status");
+
+    assert_eq!(lines[0], "unpack ok");
+    assert!(lines[1].starts_with("ng refs/heads/main failed to update ref"));
+    assert_eq!(lines[2], "ok refs/heads/feature");
+    assert_eq!(
+        reference(&fixture.repo, "refs/heads/main"),
+        Some(fixture.second)
+    );
+    assert!(reference(&fixture.repo, "refs/heads/feature").is_some());
+}
+
+#[test]
+fn updates_nothing_when_an_atomic_push_fails() {
+    let fixture = fixture();
+
+    let lines = stale_push(&fixture, "report-status atomic");
+
+    assert_eq!(lines[0], "unpack ok");
+    assert!(lines[1].starts_with("ng refs/heads/main failed to update ref"));
+    assert!(lines[2].starts_with("ng refs/heads/feature failed to update ref"));
+    assert_eq!(
+        reference(&fixture.repo, "refs/heads/main"),
+        Some(fixture.second)
+    );
+    assert_eq!(reference(&fixture.repo, "refs/heads/feature"), None);
+}
+
+#[test]
+fn rejects_an_atomic_push_with_an_invalid_command() {
+    let fixture = fixture();
+    let (client, third) = next_commit(&fixture);
+
+    let response = push(
+        &fixture.repo,
+        &[
+            format!(
+                "{} {third} refs/heads/main\0report-status atomic",
+                fixture.second
+            ),
+            format!("{ZERO} {third} feature"),
+        ],
+        &pack(&client, third, Some(fixture.second)),
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines(&response),
+        [
+            "unpack ok",
+            "ng refs/heads/main atomic push failure",
+            "ng feature funny refname",
+        ]
+    );
+    assert_eq!(
+        reference(&fixture.repo, "refs/heads/main"),
+        Some(fixture.second)
+    );
+    assert!(decode(&response).ends_with(&[GitMessage::Flush]));
+}
+
+#[test]
+fn limits_the_size_of_a_push() {
+    let mut limited = receive_pack::PushLimit::new(&[0u8; 10][..], Some(10));
+    let mut buf = Vec::new();
+    assert_eq!(limited.read_to_end(&mut buf).unwrap(), 10);
+
+    let mut limited = receive_pack::PushLimit::new(&[0u8; 11][..], Some(10));
+    let e = limited.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(e.to_string(), "push exceeds the maximum size of 10 bytes");
+
+    let mut unlimited = receive_pack::PushLimit::new(&[0u8; 11][..], None);
+    assert_eq!(unlimited.read_to_end(&mut Vec::new()).unwrap(), 11);
+}
+
+#[test]
+fn reports_a_push_over_the_limit() {
+    let fixture = fixture();
+    let (client, third) = next_commit(&fixture);
+    let commands = [format!(
+        "{} {third} refs/heads/main\0report-status",
+        fixture.second
+    )];
+
+    // the commands fit, the pack doesn't
+    let response = push_limited(
+        &fixture.repo,
+        &commands,
+        &pack(&client, third, None),
+        Some(200),
+    )
+    .unwrap();
+
+    assert_eq!(
+        lines(&response),
+        [
+            "unpack push exceeds the maximum size of 200 bytes",
+            "ng refs/heads/main unpacker error"
+        ]
+    );
+    assert_eq!(
+        reference(&fixture.repo, "refs/heads/main"),
+        Some(fixture.second)
+    );
+
+    let result = push_limited(&fixture.repo, &commands, &[], Some(10));
+    assert!(matches!(result, Err(Error::PushTooLarge(10))));
+}
+
+/// Split a side-band response into its progress messages and the report
+fn demultiplex(response: &[u8]) -> (String, Vec<u8>) {
+    let mut progress = String::new();
+    let mut report = Vec::new();
+    let messages = decode(response);
+    assert_eq!(messages.last(), Some(&GitMessage::Flush));
+    for message in messages {
+        match message {
+            GitMessage::Data(data) if data[0] == 1 => report.extend_from_slice(&data[1..]),
+            GitMessage::Data(data) if data[0] == 2 => {
+                progress.push_str(std::str::from_utf8(&data[1..]).unwrap())
+            }
+            GitMessage::Flush => {}
+            message => panic!("unexpected {message:?}"),
+        }
+    }
+
+    (progress, report)
+}
+
+#[test]
+fn sends_progress_on_the_side_band() {
+    let fixture = fixture();
+    let (client, third) = next_commit(&fixture);
+
+    let response = push(
+        &fixture.repo,
+        &[format!(
+            "{} {third} refs/heads/main\0report-status side-band-64k",
+            fixture.second
+        )],
+        &pack(&client, third, Some(fixture.second)),
+    )
+    .unwrap();
+    let (progress, report) = demultiplex(&response);
+
+    assert!(progress.ends_with("Receiving objects: 100% (3/3), done.\n"));
+    assert_eq!(lines(&report), ["unpack ok", "ok refs/heads/main"]);
+}
+
+#[test]
+fn quiet_clients_get_no_progress() {
+    let fixture = fixture();
+    let (client, third) = next_commit(&fixture);
+
+    let response = push(
+        &fixture.repo,
+        &[format!(
+            "{} {third} refs/heads/main\0report-status side-band-64k quiet",
+            fixture.second
+        )],
+        &pack(&client, third, Some(fixture.second)),
+    )
+    .unwrap();
+    let (progress, report) = demultiplex(&response);
+
+    assert!(progress.is_empty());
+    assert_eq!(lines(&report), ["unpack ok", "ok refs/heads/main"]);
+}
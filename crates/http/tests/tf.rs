@@ -0,0 +1,646 @@
+//! Requests against the terraform routes of the server
+mod common;
+
+use axum::http::header::WWW_AUTHENTICATE;
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use common::{authorized, request, send, tf_lock, tf_state};
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use thoenix_http::{
+    auth::{hash_password, hash_token, Credentials},
+    policy::Policy,
+    state_ids::StateIdFilter,
+    Server,
+};
+
+async fn router() -> (TempDir, Router) {
+    router_with(|server| server).await
+}
+
+/// The routes of a server configured by `configure`
+async fn router_with(configure: impl FnOnce(Server) -> Server) -> (TempDir, Router) {
+    let dir = tempfile::tempdir().unwrap();
+    let router = configure(Server::new(dir.path().to_path_buf()))
+        .router()
+        .await
+        .unwrap();
+
+    (dir, router)
+}
+
+/// Token users named after their tokens, allowed to do what `rules` grant them
+fn with_users(server: Server, users: &[&str], rules: Value) -> Server {
+    let credentials = users
+        .iter()
+        .map(|user| format!("{user} token {}\n", hash_token(user)))
+        .collect::<String>();
+
+    server
+        .with_credentials(Some(Credentials::parse(&credentials).unwrap()))
+        .with_policy(Policy::parse(&json!({ "rules": rules }).to_string()).unwrap())
+}
+
+/// An authenticated request from one of the users created by [`with_users`]
+fn request_as(user: &str, method: Method, uri: &str, body: impl Into<Body>) -> Request<Body> {
+    authorized(request(method, uri, body), &format!("Bearer {user}"))
+}
+
+async fn update(router: &Router, id: &str, data: String) {
+    let uri = format!("/tf/state/{id}?ID=");
+    let (status, _, body) = send(router, request(Method::POST, &uri, data)).await;
+    assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+}
+
+async fn get_json(router: &Router, uri: &str) -> Value {
+    let (status, _, body) = send(router, request(Method::GET, uri, "")).await;
+    assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+
+    serde_json::from_slice(&body).unwrap()
+}
+
+fn resource(module: Option<&str>, resource_type: &str, name: &str, instances: Value) -> Value {
+    json!({
+        "module": module,
+        "mode": "managed",
+        "type": resource_type,
+        "name": name,
+        "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+        "instances": instances,
+    })
+}
+
+#[tokio::test]
+async fn inventory_lists_and_filters_resources() {
+    let (_dir, router) = router().await;
+    update(
+        &router,
+        "prod-network",
+        tf_state(
+            1,
+            json!([
+                resource(
+                    None,
+                    "aws_vpc",
+                    "main",
+                    json!([{ "attributes": { "id": "vpc-1" } }])
+                ),
+                resource(
+                    Some("module.subnets"),
+                    "aws_subnet",
+                    "private",
+                    json!([
+                        { "index_key": 0, "attributes": { "id": "subnet-1" } },
+                        { "index_key": 1, "attributes": { "id": "subnet-2" } },
+                    ])
+                ),
+            ]),
+        ),
+    )
+    .await;
+    update(
+        &router,
+        "dev-network",
+        tf_state(
+            1,
+            json!([resource(
+                None,
+                "aws_subnet",
+                "public",
+                json!([{ "index_key": "a", "attributes": { "id": "subnet-3" } }])
+            )]),
+        ),
+    )
+    .await;
+
+    let inventory = get_json(&router, "/tf/resources").await;
+    let entries = inventory
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| (e["state"].as_str().unwrap(), e["address"].as_str().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        entries,
+        [
+            ("dev-network", "aws_subnet.public[\"a\"]"),
+            ("prod-network", "aws_vpc.main"),
+            ("prod-network", "module.subnets.aws_subnet.private[0]"),
+            ("prod-network", "module.subnets.aws_subnet.private[1]"),
+        ]
+    );
+    assert_eq!(inventory[2]["module"], "module.subnets");
+    assert_eq!(inventory[2]["type"], "aws_subnet");
+    assert_eq!(inventory[2]["id"], "subnet-1");
+
+    let subnets = get_json(&router, "/tf/resources?type=aws_subnet").await;
+    assert_eq!(subnets.as_array().unwrap().len(), 3);
+
+    let prod = get_json(&router, "/tf/resources?configuration=prod-*").await;
+    assert_eq!(prod.as_array().unwrap().len(), 3);
+
+    let prod_vpcs = get_json(&router, "/tf/resources?type=aws_vpc&configuration=prod-*").await;
+    assert_eq!(prod_vpcs.as_array().unwrap().len(), 1);
+    assert_eq!(prod_vpcs[0]["address"], "aws_vpc.main");
+
+    let (status, _, _) = send(
+        &router,
+        request(Method::GET, "/tf/resources?configuration=%5B", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn inventory_masks_sensitive_ids() {
+    let (_dir, router) = router().await;
+    update(
+        &router,
+        "database",
+        tf_state(
+            1,
+            json!([resource(
+                None,
+                "aws_db_instance",
+                "main",
+                json!([{
+                    "attributes": { "id": "db-secret" },
+                    "sensitive_attributes": [[{ "type": "get_attr", "value": "id" }]],
+                }])
+            )]),
+        ),
+    )
+    .await;
+
+    let inventory = get_json(&router, "/tf/resources").await;
+
+    assert_eq!(inventory[0]["id"], thoenix_tofu::diff::SENSITIVE_VALUE);
+}
+
+#[tokio::test]
+async fn outputs_hide_sensitive_values_without_the_sensitive_permission() {
+    let (_dir, router) = router_with(|server| {
+        with_users(
+            server,
+            &["deploy", "dashboard"],
+            json!([
+                { "users": ["deploy", "dashboard"], "states": ["*"], "permissions": ["read"] },
+                { "users": ["deploy"], "states": ["*"], "permissions": ["write", "sensitive"] },
+            ]),
+        )
+    })
+    .await;
+    let mut data: Value = serde_json::from_str(&tf_state(1, json!([]))).unwrap();
+    data["outputs"] = json!({
+        "vpc_id": { "value": "vpc-1", "type": "string" },
+        "db_password": { "value": "hunter2", "type": "string", "sensitive": true },
+    });
+    let (status, _, _) = send(
+        &router,
+        request_as(
+            "deploy",
+            Method::POST,
+            "/tf/state/network?ID=",
+            data.to_string(),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _, body) = send(
+        &router,
+        request_as("deploy", Method::GET, "/tf/state/network/outputs", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let outputs: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(outputs["vpc_id"]["value"], "vpc-1");
+    assert_eq!(outputs["db_password"]["value"], "hunter2");
+
+    let (status, _, body) = send(
+        &router,
+        request_as(
+            "deploy",
+            Method::GET,
+            "/tf/state/network/outputs/db_password",
+            "",
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::from_slice::<Value>(&body).unwrap()["value"],
+        "hunter2"
+    );
+
+    let (status, _, body) = send(
+        &router,
+        request_as("dashboard", Method::GET, "/tf/state/network/outputs", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let outputs: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        outputs,
+        json!({ "vpc_id": { "value": "vpc-1", "type": "string", "sensitive": false } })
+    );
+
+    let (status, _, _) = send(
+        &router,
+        request_as(
+            "dashboard",
+            Method::GET,
+            "/tf/state/network/outputs/db_password",
+            "",
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _, body) = send(
+        &router,
+        request_as(
+            "dashboard",
+            Method::GET,
+            "/tf/state/network/outputs/vpc_id",
+            "",
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::from_slice::<Value>(&body).unwrap()["value"],
+        "vpc-1"
+    );
+
+    let (status, _, _) = send(
+        &router,
+        request_as(
+            "dashboard",
+            Method::GET,
+            "/tf/state/network/outputs/missing",
+            "",
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn requires_credentials_on_every_terraform_route() {
+    let credentials = format!(
+        "alice password {}\nci token {}\n",
+        hash_password("hunter2").unwrap(),
+        hash_token("ci-secret"),
+    );
+    let (_dir, router) = router_with(|server| {
+        server
+            .with_credentials(Some(Credentials::parse(&credentials).unwrap()))
+            .with_admin_token(Some("admin-secret".to_string()))
+    })
+    .await;
+    let basic = |user: &str, password: &str| {
+        format!("Basic {}", BASE64.encode(format!("{user}:{password}")))
+    };
+
+    for (method, uri) in [
+        (Method::GET, "/tf/state/network"),
+        (Method::POST, "/tf/state/network?ID="),
+        (Method::PUT, "/tf/lock/network"),
+        (Method::GET, "/tf/states"),
+        (Method::GET, "/tf/resources"),
+    ] {
+        let (status, headers, _) = send(&router, request(method.clone(), uri, "")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{method} {uri}");
+        assert_eq!(headers[WWW_AUTHENTICATE], r#"Basic realm="thoenix""#);
+    }
+
+    for authorization in [
+        basic("alice", "hunter3"),
+        basic("mallory", "hunter2"),
+        "Bearer hunter2".to_string(),
+        "Bearer admin-secrets".to_string(),
+    ] {
+        let get = authorized(
+            request(Method::GET, "/tf/state/network", ""),
+            &authorization,
+        );
+        let (status, headers, _) = send(&router, get).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{authorization}");
+        assert_eq!(headers[WWW_AUTHENTICATE], r#"Basic realm="thoenix""#);
+    }
+
+    for authorization in [
+        basic("alice", "hunter2"),
+        basic("ci", "ci-secret"),
+        "Bearer ci-secret".to_string(),
+        "Bearer admin-secret".to_string(),
+    ] {
+        let get = authorized(
+            request(Method::GET, "/tf/state/network", ""),
+            &authorization,
+        );
+        let (status, _, _) = send(&router, get).await;
+        assert_eq!(status, StatusCode::NO_CONTENT, "{authorization}");
+
+        let list = authorized(request(Method::GET, "/tf/states", ""), &authorization);
+        let (status, _, _) = send(&router, list).await;
+        assert_eq!(status, StatusCode::OK, "{authorization}");
+    }
+}
+
+#[tokio::test]
+async fn policies_are_enforced_on_every_terraform_route() {
+    let permissions = ["read", "write", "lock", "unlock", "sensitive"];
+    let users = permissions
+        .iter()
+        .map(|p| format!("without-{p}"))
+        .chain(["deploy".to_string()])
+        .collect::<Vec<_>>();
+    let rules = users
+        .iter()
+        .map(|user| {
+            let granted = permissions
+                .iter()
+                .filter(|p| user.strip_prefix("without-") != Some(p))
+                .collect::<Vec<_>>();
+            json!({ "users": [user], "states": ["*"], "permissions": granted })
+        })
+        .collect::<Vec<_>>();
+    let (_dir, router) = router_with(|server| {
+        let users = users.iter().map(String::as_str).collect::<Vec<_>>();
+        with_users(server, &users, json!(rules))
+    })
+    .await;
+
+    let mut data: Value = serde_json::from_str(&tf_state(1, json!([]))).unwrap();
+    data["outputs"] = json!({ "password": { "value": "hunter2", "sensitive": true } });
+    for (method, uri, body) in [
+        (Method::POST, "/tf/state/network?ID=", data.to_string()),
+        (Method::PUT, "/tf/lock/network", tf_lock("deploy")),
+    ] {
+        let (status, _, _) = send(&router, request_as("deploy", method, uri, body)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    let denied = [
+        ("read", Method::GET, "/tf/state/network", String::new()),
+        ("read", Method::GET, "/tf/lock/network", String::new()),
+        (
+            "read",
+            Method::GET,
+            "/tf/state/network/versions",
+            String::new(),
+        ),
+        (
+            "read",
+            Method::GET,
+            "/tf/state/network/outputs",
+            String::new(),
+        ),
+        (
+            "write",
+            Method::POST,
+            "/tf/state/network?ID=deploy",
+            tf_state(2, json!([])),
+        ),
+        (
+            "write",
+            Method::DELETE,
+            "/tf/state/network?ID=deploy",
+            String::new(),
+        ),
+        (
+            "write",
+            Method::POST,
+            "/tf/state/network/versions/1/restore?ID=deploy",
+            String::new(),
+        ),
+        ("lock", Method::PUT, "/tf/lock/other", tf_lock("other")),
+        (
+            "unlock",
+            Method::DELETE,
+            "/tf/lock/network",
+            tf_lock("deploy"),
+        ),
+        (
+            "sensitive",
+            Method::GET,
+            "/tf/state/network/outputs/password",
+            String::new(),
+        ),
+    ];
+    for (permission, method, uri, body) in denied {
+        let user = format!("without-{permission}");
+        let (status, _, response) =
+            send(&router, request_as(&user, method.clone(), uri, body)).await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN, "{user} {method} {uri}");
+        let expected = match permission {
+            "sensitive" => "output password is sensitive".to_string(),
+            permission => format!("{user} may not {permission} state"),
+        };
+        assert!(String::from_utf8(response).unwrap().starts_with(&expected));
+    }
+
+    // none of the denied requests changed anything
+    let (status, _, body) = send(
+        &router,
+        request_as("deploy", Method::GET, "/tf/state/network", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(String::from_utf8(body).unwrap(), data.to_string());
+    let (status, _, body) = send(
+        &router,
+        request_as("deploy", Method::GET, "/tf/lock/network", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::from_slice::<Value>(&body).unwrap()["ID"],
+        "deploy"
+    );
+    let (status, _, _) = send(
+        &router,
+        request_as("deploy", Method::GET, "/tf/lock/other", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn unwritten_states_have_no_content() {
+    let (dir, router) = router().await;
+
+    let (status, _, body) = send(&router, request(Method::GET, "/tf/state/network", "")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(body.is_empty());
+    // reading a state doesn't create it
+    assert!(!dir.path().join("tf-state/network").exists());
+
+    let (status, _, _) = send(
+        &router,
+        request(Method::PUT, "/tf/lock/network", tf_lock("a")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = send(&router, request(Method::GET, "/tf/state/network", "")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(body.is_empty());
+
+    let (status, _, _) = send(
+        &router,
+        request(Method::DELETE, "/tf/lock/network", tf_lock("a")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, body) = send(&router, request(Method::GET, "/tf/states", "")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!([]));
+}
+
+#[tokio::test]
+async fn refuses_state_ids_outside_of_the_filter() {
+    let (dir, router) = router_with(|server| {
+        server.with_state_ids(StateIdFilter::new(["prod-*", "staging"]).unwrap())
+    })
+    .await;
+
+    for (method, uri, body) in [
+        (Method::GET, "/tf/state/typo", String::new()),
+        (Method::POST, "/tf/state/typo?ID=", tf_state(1, json!([]))),
+        (Method::PUT, "/tf/lock/typo", tf_lock("a")),
+        (Method::DELETE, "/tf/lock/typo", tf_lock("a")),
+        (Method::GET, "/tf/state/prod", String::new()),
+    ] {
+        let (status, _, _) = send(&router, request(method.clone(), uri, body)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{method} {uri}");
+    }
+    assert!(!dir.path().join("tf-state/typo").exists());
+
+    update(&router, "prod-network", tf_state(1, json!([]))).await;
+    let (status, _, _) = send(&router, request(Method::GET, "/tf/state/staging", "")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn verifies_the_content_md5_of_uploads() {
+    let (_dir, router) = router().await;
+    let data = tf_state(1, json!([]));
+    let md5 = thoenix_tofu::content_md5(data.as_bytes());
+
+    for (digest, expected) in [
+        (
+            thoenix_tofu::content_md5(b"truncated"),
+            StatusCode::BAD_REQUEST,
+        ),
+        ("not base64!".to_string(), StatusCode::BAD_REQUEST),
+        (md5.clone(), StatusCode::OK),
+    ] {
+        let mut upload = request(Method::POST, "/tf/state/network?ID=", data.clone());
+        upload
+            .headers_mut()
+            .insert("content-md5", digest.parse().unwrap());
+        let (status, _, _) = send(&router, upload).await;
+        assert_eq!(status, expected, "{digest}");
+
+        if expected != StatusCode::OK {
+            let (status, _, _) = send(&router, request(Method::GET, "/tf/state/network", "")).await;
+            assert_eq!(status, StatusCode::NO_CONTENT);
+        }
+    }
+
+    let (status, headers, body) =
+        send(&router, request(Method::GET, "/tf/state/network", "")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, data.as_bytes());
+    assert_eq!(headers["content-md5"], md5.as_str());
+
+    let (status, headers, _) = send(
+        &router,
+        request(Method::GET, "/tf/state/network/versions/1", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["content-md5"], md5.as_str());
+}
+
+#[tokio::test]
+async fn lists_states() {
+    let (_dir, router) = router().await;
+    let data = tf_state(3, json!([]));
+    update(&router, "network", data.clone()).await;
+    let (status, _, _) = send(&router, request(Method::PUT, "/tf/lock/dns", tf_lock("a"))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let states = get_json(&router, "/tf/states").await;
+
+    assert_eq!(states.as_array().unwrap().len(), 2);
+    assert_eq!(states[0]["id"], "dns");
+    assert_eq!(states[0]["size"], 0);
+    assert_eq!(states[0]["serial"], Value::Null);
+    assert_eq!(states[0]["lock"]["ID"], "a");
+    assert_eq!(states[1]["id"], "network");
+    assert_eq!(states[1]["size"], data.len());
+    assert_eq!(states[1]["serial"], 3);
+    assert_eq!(states[1]["lock"], Value::Null);
+    let last_modified = states[1]["last_modified"].as_str().unwrap();
+    let age = chrono::Utc::now()
+        - last_modified
+            .parse::<chrono::DateTime<chrono::Utc>>()
+            .unwrap();
+    assert!(age < chrono::Duration::minutes(1), "{last_modified}");
+}
+
+#[tokio::test]
+async fn deletes_states() {
+    let (_dir, router) = router().await;
+    update(&router, "network", tf_state(1, json!([]))).await;
+
+    let (status, _, _) = send(&router, request(Method::DELETE, "/tf/state/network", "")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, _) = send(&router, request(Method::GET, "/tf/state/network", "")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _, _) = send(
+        &router,
+        request(Method::GET, "/tf/state/network/versions", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(get_json(&router, "/tf/states").await, json!([]));
+
+    let (status, _, _) = send(&router, request(Method::DELETE, "/tf/state/network", "")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deleting_a_locked_state_requires_its_lock() {
+    let (_dir, router) = router().await;
+    update(&router, "network", tf_state(1, json!([]))).await;
+    let (status, _, _) = send(
+        &router,
+        request(Method::PUT, "/tf/lock/network", tf_lock("a")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    for uri in ["/tf/state/network", "/tf/state/network?ID=b"] {
+        let (status, _, _) = send(&router, request(Method::DELETE, uri, "")).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{uri}");
+    }
+    let (status, _, _) = send(&router, request(Method::GET, "/tf/state/network", "")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _, _) = send(
+        &router,
+        request(Method::DELETE, "/tf/state/network?ID=a", ""),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(get_json(&router, "/tf/states").await, json!([]));
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use bytes::BytesMut;
+use common::{data, decode, encode, fixture, lines, pack_objects, sideband_pack};
+use thoenix_http::{error::Error, message::GitMessage, upload_pack};
+
+const WANT_CAPABILITIES: &str = "multi_ack_detailed side-band-64k ofs-delta";
+
+#[test]
+fn advertises_refs_with_head_first() {
+    let fixture = fixture();
+
+    let mut buf = BytesMut::new();
+    upload_pack::advertise_refs(&fixture.repo, &mut buf).unwrap();
+    let lines = lines(&buf);
+
+    let (head, capabilities) = lines[0].split_once('\0').unwrap();
+    assert_eq!(head, format!("{} HEAD", fixture.second));
+    assert!(capabilities.contains("multi_ack_detailed"));
+    assert!(capabilities.contains("side-band-64k"));
+    assert!(capabilities.contains("symref=HEAD:refs/heads/main"));
+
+    let tag = fixture.repo.find_tag(fixture.tag).unwrap();
+    assert_eq!(
+        lines[1..],
+        [
+            format!("{} refs/heads/main", fixture.second),
+            format!("{} refs/tags/v1", tag.id()),
+            format!("{} refs/tags/v1^{{}}", fixture.first),
+        ]
+    );
+    assert!(matches!(decode(&buf).last(), Some(GitMessage::Flush)));
+}
+
+#[test]
+fn hides_state_refs() {
+    let fixture = fixture();
+
+    let refs = upload_pack::list_refs(&fixture.repo).unwrap();
+
+    assert!(refs.iter().all(|r| !r.name.starts_with("refs/thoenix/")));
+    assert!(upload_pack::is_hidden_ref("refs/thoenix/lock/network"));
+    assert!(!upload_pack::is_hidden_ref("refs/heads/thoenix"));
+}
+
+#[test]
+fn hides_head_pointing_at_a_state_ref() {
+    let fixture = fixture();
+    fixture
+        .repo
+        .reference_symbolic("HEAD", "refs/thoenix/state/network", true, "test")
+        .unwrap();
+
+    let refs = upload_pack::list_refs(&fixture.repo).unwrap();
+
+    assert_eq!(refs[0].name, "refs/heads/main");
+}
+
+#[test]
+fn advertises_capabilities_of_an_empty_repository() {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_bare(dir.path()).unwrap();
+
+    let mut buf = BytesMut::new();
+    upload_pack::advertise_refs(&repo, &mut buf).unwrap();
+    let lines = lines(&buf);
+
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with(&format!("{} capabilities^{{}}\0", git2::Oid::zero())));
+}
+
+#[test]
+fn acknowledges_common_haves_until_done() {
+    let fixture = fixture();
+    let unknown = git2::Oid::from_str("1234567890123456789012345678901234567890").unwrap();
+
+    let request = encode(vec![
+        data(&format!("want {} {WANT_CAPABILITIES}\n", fixture.second)),
+        GitMessage::Flush,
+        data(&format!("have {unknown}\n")),
+        data(&format!("have {}\n", fixture.first)),
+        GitMessage::Flush,
+    ]);
+    let response = upload_pack::upload_pack(&fixture.repo, &request).unwrap();
+
+    assert_eq!(
+        lines(&response),
+        [format!("ACK {} common", fixture.first), "NAK".to_string()]
+    );
+}
+
+#[test]
+fn sends_the_pack_once_done() {
+    let fixture = fixture();
+
+    let request = encode(vec![
+        data(&format!("want {} {WANT_CAPABILITIES}\n", fixture.second)),
+        GitMessage::Flush,
+        data(&format!("have {}\n", fixture.first)),
+        data("done\n"),
+    ]);
+    let response = upload_pack::upload_pack(&fixture.repo, &request).unwrap();
+    let messages = decode(&response);
+
+    assert_eq!(
+        lines(&response)[..2],
+        [
+            format!("ACK {} common", fixture.first),
+            format!("ACK {}", fixture.first)
+        ]
+    );
+    let objects = pack_objects(&sideband_pack(&messages));
+    // the second commit, its tree and blob, everything else is common
+    assert_eq!(objects.len(), 3);
+    assert!(objects.contains(&fixture.second));
+    assert!(!objects.contains(&fixture.first));
+    assert!(matches!(messages.last(), Some(GitMessage::Flush)));
+}
+
+#[test]
+fn sends_everything_to_a_clone() {
+    let fixture = fixture();
+
+    let request = encode(vec![
+        data(&format!("want {} {WANT_CAPABILITIES}\n", fixture.second)),
+        GitMessage::Flush,
+        data("done\n"),
+    ]);
+    let response = upload_pack::upload_pack(&fixture.repo, &request).unwrap();
+    let messages = decode(&response);
+
+    assert!(matches!(&messages[0], GitMessage::Data(line) if line == b"NAK\n"));
+    let objects = pack_objects(&sideband_pack(&messages));
+    assert!(objects.contains(&fixture.first));
+    assert!(objects.contains(&fixture.second));
+}
+
+#[test]
+fn refuses_wants_that_are_not_advertised() {
+    let fixture = fixture();
+    let unknown = git2::Oid::from_str("1234567890123456789012345678901234567890").unwrap();
+
+    for want in [fixture.state, unknown] {
+        let request = encode(vec![
+            data(&format!("want {want} {WANT_CAPABILITIES}\n")),
+            GitMessage::Flush,
+            data("done\n"),
+        ]);
+        let result = upload_pack::upload_pack(&fixture.repo, &request);
+
+        assert!(
+            matches!(&result, Err(Error::Protocol(message)) if message.starts_with("not our ref")),
+            "{result:?}"
+        );
+    }
+}
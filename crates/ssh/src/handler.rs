@@ -15,6 +15,7 @@ use tracing::info;
 
 /// A thin wrapper around tokio::process::Child that implements AsyncRead
 /// and AsyncWrite on top of the child's stdout and stdin.
+#[allow(dead_code)]
 struct ChildProcess {
     inner: tokio::process::Child,
 }
@@ -0,0 +1,517 @@
+//! A conformance suite for [`TerraformStateProvider`] implementations.
+//!
+//! Every check takes a provider without any states and panics if the provider doesn't behave
+//! the way the http server relies on. The [`conformance_tests`](crate::conformance_tests) macro
+//! generates a `#[tokio::test]` for each check:
+//!
+//! ```ignore
+//! mod memory {
+//!     thoenix_tofu::conformance_tests!(|ttl| {
+//!         ((), thoenix_tofu::InMemoryState::new().with_lock_ttl(ttl))
+//!     });
+//! }
+//! ```
+use crate::{content_md5, error::Error, TerraformLock, TerraformStateProvider};
+use std::time::Duration;
+
+/// The lock ttl of the provider given to [`lock_ttl`]
+pub const LOCK_TTL: Duration = Duration::from_secs(1);
+
+/// A lock as terraform sends it, with `id` as the lock id
+pub fn lock(id: &str) -> TerraformLock {
+    TerraformLock {
+        id: id.to_string(),
+        operation: "OperationTypeApply".to_string(),
+        info: String::new(),
+        who: format!("{id}@localhost"),
+        version: "1.6.0".to_string(),
+        created: None,
+        path: String::new(),
+        acquired: None,
+    }
+}
+
+/// A minimal state document
+pub fn state(lineage: &str, serial: u64) -> String {
+    format!(
+        r#"{{"version":4,"serial":{serial},"lineage":"{lineage}","outputs":{{}},"resources":[]}}"#
+    )
+}
+
+macro_rules! assert_err {
+    ($result:expr, $pattern:pat) => {
+        match $result {
+            Err($pattern) => {}
+            other => panic!("expected {}, got {other:?}", stringify!($pattern)),
+        }
+    };
+}
+
+/// Generate a test for every conformance check.
+///
+/// The argument is called once per test with the lock ttl the provider should use, and must
+/// produce a `(guard, provider)` pair, where `guard` is kept alive until the test ends, e.g. the
+/// temporary directory the provider uses. Only [`lock_ttl`] is given a ttl.
+#[macro_export]
+macro_rules! conformance_tests {
+    ($setup:expr) => {
+        #[tokio::test]
+        async fn lock_ttl() {
+            let (_guard, provider) = ($setup)(Some($crate::conformance::LOCK_TTL));
+            $crate::conformance::lock_ttl(&provider).await;
+        }
+
+        $crate::conformance_tests!(
+            @tests $setup;
+            missing_state,
+            lock_creates_state,
+            lock_conflicts,
+            update_requires_lock_owner,
+            unlock_requires_lock_owner,
+            force_unlock,
+            update_without_lock,
+            update_validation,
+            versions,
+            digests,
+            restore_version,
+            delete_state,
+            listing,
+            states_are_independent
+        );
+    };
+    (@tests $setup:expr; $($check:ident),*) => {
+        $(
+            #[tokio::test]
+            async fn $check() {
+                let (_guard, provider) = ($setup)(None);
+                $crate::conformance::$check(&provider).await;
+            }
+        )*
+    };
+}
+
+/// A state that was never locked or written doesn't exist
+pub async fn missing_state(provider: &dyn TerraformStateProvider) {
+    assert!(provider.get_state("missing").await.unwrap().is_none());
+    assert!(provider.get_version("missing", 1).await.unwrap().is_none());
+    assert_err!(provider.list_versions("missing").await, Error::NotFound);
+    assert_err!(
+        provider.unlock_state("missing", &lock("a")).await,
+        Error::NotFound
+    );
+    assert_err!(
+        provider.force_unlock_state("missing").await,
+        Error::NotFound
+    );
+    assert_err!(provider.delete_state("missing", "").await, Error::NotFound);
+    assert_err!(
+        provider.restore_version("missing", "", 1).await,
+        Error::VersionNotFound(1)
+    );
+
+    assert!(provider.list_states().await.unwrap().is_empty());
+    assert!(provider.list_locks().await.unwrap().is_empty());
+}
+
+/// Locking creates an empty state, which disappears again when it is unlocked without a write
+pub async fn lock_creates_state(provider: &dyn TerraformStateProvider) {
+    provider.lock_state("network", lock("a")).await.unwrap();
+
+    let state = provider.get_state("network").await.unwrap().unwrap();
+    assert!(state.data.is_empty());
+    let held = state.lock_info().unwrap();
+    assert_eq!(held.id, "a");
+    assert!(
+        held.created.is_some(),
+        "the lock creation time is filled in"
+    );
+    assert!(held.acquired.is_some());
+    assert!(provider.list_versions("network").await.unwrap().is_empty());
+
+    provider.unlock_state("network", &lock("a")).await.unwrap();
+    assert!(provider.get_state("network").await.unwrap().is_none());
+    assert!(provider.list_states().await.unwrap().is_empty());
+}
+
+/// A locked state can't be locked again, even by the holder of the lock
+pub async fn lock_conflicts(provider: &dyn TerraformStateProvider) {
+    provider.lock_state("network", lock("a")).await.unwrap();
+
+    assert_err!(
+        provider.lock_state("network", lock("b")).await,
+        Error::StateLocked
+    );
+    assert_err!(
+        provider.lock_state("network", lock("a")).await,
+        Error::StateLocked
+    );
+
+    let state = provider.get_state("network").await.unwrap().unwrap();
+    assert_eq!(state.lock_info().unwrap().id, "a");
+}
+
+/// Only the holder of the lock may write to a locked state
+pub async fn update_requires_lock_owner(provider: &dyn TerraformStateProvider) {
+    provider.lock_state("network", lock("a")).await.unwrap();
+
+    assert_err!(
+        provider
+            .update_state("network", "b", state("l", 1), false)
+            .await,
+        Error::StateLocked
+    );
+    assert_err!(
+        provider
+            .update_state("network", "", state("l", 1), false)
+            .await,
+        Error::StateLocked
+    );
+    assert_err!(
+        provider
+            .update_state("network", "b", state("l", 1), true)
+            .await,
+        Error::StateLocked
+    );
+
+    provider
+        .update_state("network", "a", state("l", 1), false)
+        .await
+        .unwrap();
+    let current = provider.get_state("network").await.unwrap().unwrap();
+    assert_eq!(current.data, state("l", 1));
+    assert_eq!(current.lock_info().unwrap().id, "a");
+}
+
+/// Only the holder of the lock may release it
+pub async fn unlock_requires_lock_owner(provider: &dyn TerraformStateProvider) {
+    provider.lock_state("network", lock("a")).await.unwrap();
+    provider
+        .update_state("network", "a", state("l", 1), false)
+        .await
+        .unwrap();
+
+    assert_err!(
+        provider.unlock_state("network", &lock("b")).await,
+        Error::StateLocked
+    );
+    assert!(provider
+        .get_state("network")
+        .await
+        .unwrap()
+        .unwrap()
+        .is_locked());
+
+    provider.unlock_state("network", &lock("a")).await.unwrap();
+    let state = provider.get_state("network").await.unwrap().unwrap();
+    assert!(!state.is_locked());
+    assert!(!state.data.is_empty(), "unlocking keeps written data");
+}
+
+/// Force unlocking releases any lock and returns it
+pub async fn force_unlock(provider: &dyn TerraformStateProvider) {
+    provider.lock_state("network", lock("a")).await.unwrap();
+    provider
+        .update_state("network", "a", state("l", 1), false)
+        .await
+        .unwrap();
+
+    let released = provider.force_unlock_state("network").await.unwrap();
+    assert_eq!(released.id, "a");
+    assert!(!provider
+        .get_state("network")
+        .await
+        .unwrap()
+        .unwrap()
+        .is_locked());
+    assert_err!(
+        provider.force_unlock_state("network").await,
+        Error::NotLocked
+    );
+
+    provider.lock_state("network", lock("b")).await.unwrap();
+}
+
+/// Locks are released once they have been held for longer than the ttl, counted from when the
+/// server granted them rather than from the times the client sends
+pub async fn lock_ttl(provider: &dyn TerraformStateProvider) {
+    let past = chrono::Utc::now() - chrono::Duration::days(1);
+    provider
+        .lock_state(
+            "network",
+            TerraformLock {
+                created: Some(past),
+                acquired: Some(past),
+                ..lock("a")
+            },
+        )
+        .await
+        .unwrap();
+
+    let current = provider.get_state("network").await.unwrap().unwrap();
+    let held = current
+        .lock_info()
+        .expect("the lock is kept within its ttl");
+    assert_eq!(
+        held.created,
+        Some(past),
+        "the client's creation time is kept"
+    );
+    assert!(held.acquired.is_some_and(|acquired| acquired > past));
+    assert_err!(
+        provider.lock_state("network", lock("b")).await,
+        Error::StateLocked
+    );
+
+    tokio::time::sleep(LOCK_TTL + Duration::from_millis(500)).await;
+
+    assert!(provider.list_locks().await.unwrap().is_empty());
+    provider.lock_state("network", lock("b")).await.unwrap();
+    let held = provider.get_state("network").await.unwrap().unwrap();
+    assert_eq!(held.lock_info().unwrap().id, "b");
+    assert_err!(
+        provider
+            .update_state("network", "a", state("n", 1), false)
+            .await,
+        Error::StateLocked
+    );
+}
+
+/// An unlocked state can be written without a lock id
+pub async fn update_without_lock(provider: &dyn TerraformStateProvider) {
+    provider
+        .update_state("network", "", state("l", 1), false)
+        .await
+        .unwrap();
+    provider
+        .update_state("network", "", state("l", 2), false)
+        .await
+        .unwrap();
+
+    let current = provider.get_state("network").await.unwrap().unwrap();
+    assert!(!current.is_locked());
+    assert_eq!(current.data, state("l", 2));
+}
+
+/// Updates must keep the lineage and increase the serial unless forced
+pub async fn update_validation(provider: &dyn TerraformStateProvider) {
+    provider
+        .update_state("network", "", state("l", 2), false)
+        .await
+        .unwrap();
+
+    assert_err!(
+        provider
+            .update_state("network", "", state("l", 1), false)
+            .await,
+        Error::StaleSerial {
+            current: 2,
+            found: 1
+        }
+    );
+    assert_err!(
+        provider
+            .update_state("network", "", state("other", 3), false)
+            .await,
+        Error::LineageMismatch { .. }
+    );
+    assert_err!(
+        provider
+            .update_state("network", "", "not json".to_string(), false)
+            .await,
+        Error::InvalidData(_)
+    );
+    assert_eq!(
+        provider.get_state("network").await.unwrap().unwrap().data,
+        state("l", 2)
+    );
+
+    // writing the same data again is how terraform retries an update
+    provider
+        .update_state("network", "", state("l", 2), false)
+        .await
+        .unwrap();
+
+    provider
+        .update_state("network", "", state("l", 1), true)
+        .await
+        .unwrap();
+    assert_eq!(
+        provider.get_state("network").await.unwrap().unwrap().data,
+        state("l", 1)
+    );
+}
+
+/// Every accepted update is kept as a numbered version
+pub async fn versions(provider: &dyn TerraformStateProvider) {
+    provider
+        .update_state("network", "", state("l", 1), false)
+        .await
+        .unwrap();
+    provider.lock_state("network", lock("a")).await.unwrap();
+    provider
+        .update_state("network", "a", state("l", 2), false)
+        .await
+        .unwrap();
+
+    let versions = provider.list_versions("network").await.unwrap();
+    assert_eq!(
+        versions.iter().map(|v| v.version).collect::<Vec<_>>(),
+        [1, 2]
+    );
+    assert_eq!(versions[0].lock_id, None);
+    assert_eq!(versions[1].lock_id.as_deref(), Some("a"));
+    assert_eq!(versions[1].who.as_deref(), Some("a@localhost"));
+    assert!(versions[0].created <= versions[1].created);
+
+    let first = provider.get_version("network", 1).await.unwrap().unwrap();
+    assert_eq!(first.info, versions[0]);
+    assert_eq!(first.data, state("l", 1));
+    assert!(provider.get_version("network", 3).await.unwrap().is_none());
+}
+
+/// States and versions that were read back match the digests recorded when they were written
+pub async fn digests(provider: &dyn TerraformStateProvider) {
+    provider.lock_state("network", lock("a")).await.unwrap();
+    let locked = provider.get_state("network").await.unwrap().unwrap();
+    locked.verify().unwrap();
+
+    provider
+        .update_state("network", "a", state("l", 1), false)
+        .await
+        .unwrap();
+
+    let current = provider.get_state("network").await.unwrap().unwrap();
+    assert_eq!(current.md5, Some(content_md5(state("l", 1).as_bytes())));
+    current.verify().unwrap();
+
+    let mut tampered = current.clone();
+    tampered.data = state("l", 2);
+    assert_err!(tampered.verify(), Error::DigestMismatch);
+
+    provider
+        .get_version("network", 1)
+        .await
+        .unwrap()
+        .unwrap()
+        .verify()
+        .unwrap();
+}
+
+/// Restoring a version writes its data as a new version, respecting the lock
+pub async fn restore_version(provider: &dyn TerraformStateProvider) {
+    provider
+        .update_state("network", "", state("l", 1), false)
+        .await
+        .unwrap();
+    provider
+        .update_state("network", "", state("l", 2), false)
+        .await
+        .unwrap();
+    provider.lock_state("network", lock("a")).await.unwrap();
+
+    assert_err!(
+        provider.restore_version("network", "b", 1).await,
+        Error::StateLocked
+    );
+    assert_err!(
+        provider.restore_version("network", "a", 5).await,
+        Error::VersionNotFound(5)
+    );
+
+    provider.restore_version("network", "a", 1).await.unwrap();
+    assert_eq!(
+        provider.get_state("network").await.unwrap().unwrap().data,
+        state("l", 1)
+    );
+
+    let versions = provider.list_versions("network").await.unwrap();
+    assert_eq!(versions.len(), 3);
+    assert_eq!(versions[2].restored_from, Some(1));
+}
+
+/// Deleting a state removes it and its versions, and requires the lock when it is locked
+pub async fn delete_state(provider: &dyn TerraformStateProvider) {
+    provider
+        .update_state("network", "", state("l", 1), false)
+        .await
+        .unwrap();
+    provider.lock_state("network", lock("a")).await.unwrap();
+
+    assert_err!(
+        provider.delete_state("network", "b").await,
+        Error::StateLocked
+    );
+    assert!(provider.get_state("network").await.unwrap().is_some());
+
+    provider.delete_state("network", "a").await.unwrap();
+    assert!(provider.get_state("network").await.unwrap().is_none());
+    assert!(provider.get_version("network", 1).await.unwrap().is_none());
+    assert_err!(provider.list_versions("network").await, Error::NotFound);
+    assert!(provider.list_states().await.unwrap().is_empty());
+
+    // the id can be used again afterwards
+    provider
+        .update_state("network", "", state("other", 1), false)
+        .await
+        .unwrap();
+    assert_eq!(provider.list_versions("network").await.unwrap().len(), 1);
+}
+
+/// States and locks are listed in order of their ids
+pub async fn listing(provider: &dyn TerraformStateProvider) {
+    provider
+        .update_state("b", "", state("l", 7), false)
+        .await
+        .unwrap();
+    provider.lock_state("c", lock("x")).await.unwrap();
+    provider
+        .update_state("a", "", state("l", 1), false)
+        .await
+        .unwrap();
+    provider.lock_state("a", lock("y")).await.unwrap();
+
+    let states = provider.list_states().await.unwrap();
+    assert_eq!(
+        states.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(),
+        ["a", "b", "c"]
+    );
+    assert_eq!(states[1].serial, Some(7));
+    assert_eq!(states[1].size, state("l", 7).len() as u64);
+    assert!(states[1].last_modified.is_some());
+    assert!(states[1].lock.is_none());
+    assert_eq!(states[2].serial, None);
+    assert_eq!(states[2].size, 0);
+    assert_eq!(states[2].lock.as_ref().map(|l| l.id.as_str()), Some("x"));
+
+    let locks = provider.list_locks().await.unwrap();
+    assert_eq!(
+        locks
+            .iter()
+            .map(|l| (l.id.as_str(), l.lock.id.as_str()))
+            .collect::<Vec<_>>(),
+        [("a", "y"), ("c", "x")]
+    );
+}
+
+/// Locks and versions of one state don't affect another
+pub async fn states_are_independent(provider: &dyn TerraformStateProvider) {
+    provider.lock_state("a", lock("x")).await.unwrap();
+    provider.lock_state("b", lock("y")).await.unwrap();
+
+    provider
+        .update_state("a", "x", state("one", 1), false)
+        .await
+        .unwrap();
+    provider
+        .update_state("b", "y", state("two", 1), false)
+        .await
+        .unwrap();
+    provider.unlock_state("a", &lock("x")).await.unwrap();
+
+    assert!(provider.get_state("b").await.unwrap().unwrap().is_locked());
+    assert_eq!(
+        provider.get_state("b").await.unwrap().unwrap().data,
+        state("two", 1)
+    );
+    assert_eq!(provider.list_versions("a").await.unwrap().len(), 1);
+}
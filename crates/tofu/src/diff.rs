@@ -0,0 +1,212 @@
+use crate::{
+    error::Result,
+    outputs::{parse_outputs, StateOutput},
+    resources::{parse_instances, ResourceInstance, StateResource},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Shown in place of sensitive values
+pub const SENSITIVE_VALUE: &str = "(sensitive)";
+
+/// The differences between two state documents
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateDiff {
+    /// Resource instances that only exist in the newer state
+    pub added: Vec<StateResource>,
+    /// Resource instances that only exist in the older state
+    pub removed: Vec<StateResource>,
+    /// Resource instances whose attributes changed
+    pub changed: Vec<ResourceChange>,
+    /// Root module outputs that were added, removed or changed
+    pub outputs: Vec<ValueChange>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.outputs.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceChange {
+    pub address: String,
+    pub attributes: Vec<ValueChange>,
+}
+
+/// A single value that differs, `None` if it is missing on one side
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValueChange {
+    /// The path of an attribute, e.g. `tags.Name` or `ingress[0].from_port`, or the name of an output
+    pub path: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// Compare two state documents by resource address and attribute.
+///
+/// The values of sensitive attributes and outputs are replaced with [`SENSITIVE_VALUE`], so a
+/// change to them is visible without revealing either value.
+pub fn diff_states(before: &str, after: &str) -> Result<StateDiff> {
+    let mut before_instances = index_instances(parse_instances(before)?);
+    let after_instances = index_instances(parse_instances(after)?);
+
+    let mut diff = StateDiff::default();
+    for (address, after) in after_instances {
+        let Some(before) = before_instances.remove(&address) else {
+            diff.added.push(after.resource);
+            continue;
+        };
+
+        let mut attributes = diff_values(flatten_attributes(&before), flatten_attributes(&after));
+        let sensitive = before
+            .sensitive_attributes
+            .iter()
+            .chain(&after.sensitive_attributes)
+            .filter_map(sensitive_path)
+            .collect::<Vec<_>>();
+        mask(&mut attributes, &sensitive);
+
+        if !attributes.is_empty() {
+            diff.changed.push(ResourceChange {
+                address,
+                attributes,
+            });
+        }
+    }
+    diff.removed = before_instances
+        .into_values()
+        .map(|instance| instance.resource)
+        .collect();
+
+    let (before_outputs, after_outputs) = (parse_outputs(before)?, parse_outputs(after)?);
+    let sensitive = before_outputs
+        .iter()
+        .chain(&after_outputs)
+        .filter(|(_, output)| output.sensitive)
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    diff.outputs = diff_values(output_values(before_outputs), output_values(after_outputs));
+    mask(&mut diff.outputs, &sensitive);
+
+    Ok(diff)
+}
+
+fn index_instances(instances: Vec<ResourceInstance>) -> BTreeMap<String, ResourceInstance> {
+    instances
+        .into_iter()
+        .map(|instance| (instance.resource.address.clone(), instance))
+        .collect()
+}
+
+/// List the values that differ, ordered by path
+fn diff_values(
+    mut before: BTreeMap<String, Value>,
+    after: BTreeMap<String, Value>,
+) -> Vec<ValueChange> {
+    let mut changes = Vec::new();
+    for (path, after) in after {
+        let before = before.remove(&path);
+        if before.as_ref() != Some(&after) {
+            changes.push(ValueChange {
+                path,
+                before,
+                after: Some(after),
+            });
+        }
+    }
+    changes.extend(before.into_iter().map(|(path, before)| ValueChange {
+        path,
+        before: Some(before),
+        after: None,
+    }));
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    changes
+}
+
+/// Hide the values of changes at, or inside of, one of the `sensitive` paths
+fn mask(changes: &mut [ValueChange], sensitive: &[String]) {
+    for change in changes {
+        let is_sensitive = sensitive.iter().any(|sensitive| {
+            change
+                .path
+                .strip_prefix(sensitive.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+        });
+
+        if is_sensitive {
+            for value in [&mut change.before, &mut change.after]
+                .into_iter()
+                .flatten()
+            {
+                *value = Value::from(SENSITIVE_VALUE);
+            }
+        }
+    }
+}
+
+/// Flatten the attributes of an instance into leaf values by path
+fn flatten_attributes(instance: &ResourceInstance) -> BTreeMap<String, Value> {
+    let mut values = BTreeMap::new();
+    for (name, value) in &instance.attributes {
+        flatten(name.clone(), value, &mut values);
+    }
+
+    values
+}
+
+fn output_values(outputs: BTreeMap<String, StateOutput>) -> BTreeMap<String, Value> {
+    outputs
+        .into_iter()
+        .map(|(name, output)| (name, output.value))
+        .collect()
+}
+
+fn flatten(path: String, value: &Value, values: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                flatten(format!("{path}.{key}"), value, values);
+            }
+        }
+        Value::Array(items) if !items.is_empty() => {
+            for (index, value) in items.iter().enumerate() {
+                flatten(format!("{path}[{index}]"), value, values);
+            }
+        }
+        value => {
+            values.insert(path, value.clone());
+        }
+    }
+}
+
+/// Convert a path from `sensitive_attributes` into the form used by [`flatten`].
+///
+/// Paths are lists of steps such as `{"type": "get_attr", "value": "password"}` or
+/// `{"type": "index", "value": {"type": "number", "value": 0}}`.
+pub(crate) fn sensitive_path(steps: &Value) -> Option<String> {
+    let mut path = String::new();
+    for step in steps.as_array()? {
+        match (step.get("type")?.as_str()?, step.get("value")?) {
+            ("get_attr", Value::String(name)) => {
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(name);
+            }
+            ("index", key) => match key.get("value")? {
+                Value::Number(index) => path.push_str(&format!("[{index}]")),
+                Value::String(key) => path.push_str(&format!(".{key}")),
+                _ => return None,
+            },
+            _ => return None,
+        }
+    }
+
+    (!path.is_empty()).then_some(path)
+}
@@ -0,0 +1,362 @@
+use crate::{
+    error::{Error, Result},
+    sync::StateLocks,
+    validation::validate_update,
+    LockedState, StateSummary, StateVersion, StateVersionInfo, TerraformLock, TerraformState,
+    TerraformStateProvider,
+};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+};
+
+/// The prefix of encrypted state data, followed by `<key id>:<base64 nonce and ciphertext>`
+const PREFIX: &str = "thoenix:v1:";
+const NONCE_LEN: usize = 12;
+
+/// The keys used to encrypt and decrypt state data.
+///
+/// Keys are written one per line, or separated by commas, as `<key id> <base64 encoded 32 byte
+/// key>`, for example a key generated with `head -c 32 /dev/urandom | base64`. The first key is
+/// used to encrypt new data while every key can decrypt, so a key is rotated by adding a new
+/// first line and keeping the old key until every state and version written with it is no
+/// longer needed.
+///
+/// Rotating only re-encrypts the current data of each state, versions keep the key they were
+/// written with, see [`EncryptedState::check_keys`].
+#[derive(Clone)]
+pub struct Keyring {
+    primary: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut ids = self.keys.keys().collect::<Vec<_>>();
+        ids.sort();
+
+        f.debug_struct("Keyring")
+            .field("primary", &self.primary)
+            .field("keys", &ids)
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub fn parse(keys: &str) -> Result<Self> {
+        let mut primary = None;
+        let mut ciphers = HashMap::new();
+
+        for entry in keys
+            .split(['\n', ','])
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (id, key) = entry
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| Error::InvalidKey("expected `<key id> <key>`".to_string()))?;
+            if id.contains(':') {
+                return Err(Error::InvalidKey(format!("key id {id} contains `:`")));
+            }
+
+            let key = BASE64
+                .decode(key.trim())
+                .map_err(|e| Error::InvalidKey(format!("key {id}: {e}")))?;
+            if key.len() != 32 {
+                return Err(Error::InvalidKey(format!("key {id} is not 32 bytes")));
+            }
+
+            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+            if ciphers.insert(id.to_string(), cipher).is_some() {
+                return Err(Error::InvalidKey(format!("key {id} is defined twice")));
+            }
+            primary.get_or_insert_with(|| id.to_string());
+        }
+
+        let primary = primary.ok_or_else(|| Error::InvalidKey("no keys given".to_string()))?;
+
+        Ok(Self {
+            primary,
+            keys: ciphers,
+        })
+    }
+
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let keys = tokio::fs::read_to_string(path).await?;
+
+        Self::parse(&keys)
+    }
+
+    /// The id of the key used for encryption
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    pub fn encrypt(&self, data: &str) -> Result<String> {
+        let cipher = &self.keys[&self.primary];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, data.as_bytes())
+            .map_err(|_| Error::Encryption)?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+
+        Ok(format!(
+            "{PREFIX}{}:{}",
+            self.primary,
+            BASE64.encode(payload)
+        ))
+    }
+
+    /// Decrypt data written by [`Keyring::encrypt`].
+    ///
+    /// Data that is not encrypted is returned as-is so that existing states keep working after
+    /// encryption is enabled.
+    pub fn decrypt(&self, data: &str) -> Result<String> {
+        let Some((key_id, payload)) = parse_encrypted(data) else {
+            return Ok(data.to_string());
+        };
+
+        let cipher = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| Error::UnknownKey(key_id.to_string()))?;
+        let payload = BASE64.decode(payload).map_err(|_| Error::Decryption)?;
+        if payload.len() < NONCE_LEN {
+            return Err(Error::Decryption);
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::Decryption)?;
+
+        String::from_utf8(plaintext).map_err(|_| Error::Decryption)
+    }
+}
+
+/// Split encrypted data into its key id and payload, returning `None` for plaintext
+fn parse_encrypted(data: &str) -> Option<(&str, &str)> {
+    data.strip_prefix(PREFIX)?.split_once(':')
+}
+
+/// The outcome of re-encrypting every state with the primary key
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct KeyRotation {
+    /// States that were re-encrypted
+    pub rotated: Vec<String>,
+    /// States that could not be re-encrypted because they are locked
+    pub locked: Vec<String>,
+    /// The keys still needed to read the states and their history after rotating
+    #[serde(default)]
+    pub usage: KeyUsage,
+}
+
+/// The keys referenced by the stored data of every state and version
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct KeyUsage {
+    /// The number of states and versions encrypted with each key
+    pub keys: BTreeMap<String, usize>,
+    /// The number of states and versions stored unencrypted, written before encryption was enabled
+    pub plaintext: usize,
+}
+
+impl KeyUsage {
+    fn record(&mut self, data: &str) {
+        match parse_encrypted(data) {
+            Some((key_id, _)) => *self.keys.entry(key_id.to_string()).or_default() += 1,
+            None if !data.is_empty() => self.plaintext += 1,
+            None => {}
+        }
+    }
+}
+
+/// Wraps another provider, encrypting state data before it is stored.
+///
+/// Locks and version metadata are stored unencrypted so that they can still be inspected.
+pub struct EncryptedState {
+    inner: Arc<dyn TerraformStateProvider>,
+    keyring: Keyring,
+    locks: StateLocks,
+}
+
+impl std::fmt::Debug for EncryptedState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedState")
+            .field("keyring", &self.keyring)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedState {
+    pub fn new(inner: Arc<dyn TerraformStateProvider>, keyring: Keyring) -> Self {
+        Self {
+            inner,
+            keyring,
+            locks: StateLocks::default(),
+        }
+    }
+
+    /// Re-encrypt the current data of every state that was not written with the primary key.
+    ///
+    /// Each re-encrypted state gains a new version with unchanged contents. Older versions keep
+    /// the key they were written with, and versions written before encryption was enabled stay
+    /// unencrypted, so the returned [`KeyUsage`] tells which keys can't be dropped yet.
+    pub async fn rotate_keys(&self) -> Result<KeyRotation> {
+        let mut rotation = KeyRotation::default();
+
+        for summary in self.inner.list_states().await? {
+            let _guard = self.locks.lock(&summary.id).await;
+            let Some(state) = self.inner.get_state(&summary.id).await? else {
+                continue;
+            };
+            if state.data.is_empty()
+                || parse_encrypted(&state.data).is_some_and(|(id, _)| id == self.keyring.primary)
+            {
+                continue;
+            }
+
+            let data = self.keyring.encrypt(&self.keyring.decrypt(&state.data)?)?;
+            match self.inner.update_state(&summary.id, "", data, true).await {
+                Ok(()) => rotation.rotated.push(summary.id),
+                Err(Error::StateLocked) => rotation.locked.push(summary.id),
+                Err(e) => return Err(e),
+            }
+        }
+        rotation.usage = self.key_usage().await?;
+
+        Ok(rotation)
+    }
+
+    /// Count the states and versions written with each key
+    pub async fn key_usage(&self) -> Result<KeyUsage> {
+        let mut usage = KeyUsage::default();
+
+        for summary in self.inner.list_states().await? {
+            if let Some(state) = self.inner.get_state(&summary.id).await? {
+                usage.record(&state.data);
+            }
+            for info in self.inner.list_versions(&summary.id).await? {
+                if let Some(version) = self.inner.get_version(&summary.id, info.version).await? {
+                    usage.record(&version.data);
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Ensure that every key referenced by a state or version is still in the keyring, so that a
+    /// key isn't dropped while history written with it remains.
+    pub async fn check_keys(&self) -> Result<KeyUsage> {
+        let usage = self.key_usage().await?;
+        if let Some(key_id) = usage
+            .keys
+            .keys()
+            .find(|key_id| !self.keyring.keys.contains_key(*key_id))
+        {
+            return Err(Error::UnknownKey(key_id.clone()));
+        }
+
+        Ok(usage)
+    }
+
+    fn decrypt_state(&self, mut state: TerraformState) -> Result<TerraformState> {
+        // the inner provider recorded the digest of the ciphertext
+        state.verify()?;
+        let data = self.keyring.decrypt(&state.data)?;
+        state.set_data(data);
+
+        Ok(state)
+    }
+}
+
+#[async_trait::async_trait]
+impl TerraformStateProvider for EncryptedState {
+    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>> {
+        self.inner
+            .get_state(id)
+            .await?
+            .map(|state| self.decrypt_state(state))
+            .transpose()
+    }
+
+    async fn update_state(&self, id: &str, lock_id: &str, data: String, force: bool) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        // the inner provider can only see ciphertext, so the update is validated here instead
+        if !force {
+            let current = self.get_state(id).await?.unwrap_or_default();
+            validate_update(&current.data, &data)?;
+        }
+
+        let data = self.keyring.encrypt(&data)?;
+        self.inner.update_state(id, lock_id, data, true).await
+    }
+
+    async fn lock_state(&self, id: &str, lock: TerraformLock) -> Result<()> {
+        self.inner.lock_state(id, lock).await
+    }
+
+    async fn unlock_state(&self, id: &str, lock: &TerraformLock) -> Result<()> {
+        self.inner.unlock_state(id, lock).await
+    }
+
+    async fn force_unlock_state(&self, id: &str) -> Result<TerraformLock> {
+        self.inner.force_unlock_state(id).await
+    }
+
+    async fn list_locks(&self) -> Result<Vec<LockedState>> {
+        self.inner.list_locks().await
+    }
+
+    async fn list_states(&self) -> Result<Vec<StateSummary>> {
+        let mut states = Vec::new();
+        for summary in self.inner.list_states().await? {
+            // size and serial have to be taken from the decrypted data
+            let state = self.get_state(&summary.id).await?.unwrap_or_default();
+            states.push(StateSummary::new(summary.id, &state, summary.last_modified));
+        }
+
+        Ok(states)
+    }
+
+    async fn delete_state(&self, id: &str, lock_id: &str) -> Result<()> {
+        self.inner.delete_state(id, lock_id).await
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersionInfo>> {
+        let mut versions = self.inner.list_versions(id).await?;
+        for version in &mut versions {
+            // digests of the ciphertext can't be checked against anything callers will see
+            version.md5 = None;
+        }
+
+        Ok(versions)
+    }
+
+    async fn get_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>> {
+        let Some(mut version) = self.inner.get_version(id, version).await? else {
+            return Ok(None);
+        };
+        version.verify()?;
+        version.data = self.keyring.decrypt(&version.data)?;
+        version.info.md5 = None;
+
+        Ok(Some(version))
+    }
+
+    async fn restore_version(&self, id: &str, lock_id: &str, version: u64) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        self.inner.restore_version(id, lock_id, version).await
+    }
+}
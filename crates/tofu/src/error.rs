@@ -1,9 +1,46 @@
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Git(#[from] git2::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "s3")]
+    #[error(transparent)]
+    ObjectStore(#[from] object_store::Error),
+    #[cfg(feature = "sqlite")]
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
     #[error("state not found")]
     NotFound,
+    #[error("state version {0} not found")]
+    VersionNotFound(u64),
     #[error("state is locked")]
     StateLocked,
+    #[error("state is not locked")]
+    NotLocked,
+    #[error("invalid state id: {0}")]
+    InvalidId(String),
+    #[error("invalid state data: {0}")]
+    InvalidData(serde_json::Error),
+    #[error("invalid encryption key: {0}")]
+    InvalidKey(String),
+    #[error("state was encrypted with unknown key {0}")]
+    UnknownKey(String),
+    #[error("failed to encrypt state")]
+    Encryption,
+    #[error("failed to decrypt state")]
+    Decryption,
+    #[error("state lineage {found} does not match the stored lineage {expected}")]
+    LineageMismatch { expected: String, found: String },
+    #[error("state serial {found} is not newer than the stored serial {current}")]
+    StaleSerial { current: u64, found: u64 },
+    #[error("stored state does not match its digest")]
+    DigestMismatch,
+    #[error("database schema version {0} is newer than this version of thoenix supports")]
+    UnsupportedSchema(usize),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,367 @@
+use crate::{
+    error::{Error, Result},
+    sync::StateLocks,
+    validation::validate_update,
+    LockedState, StateSummary, StateVersion, StateVersionInfo, TerraformLock, TerraformState,
+    TerraformStateProvider,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
+use tracing::info;
+
+const CURRENT_FILE: &str = "current.json";
+const LOCK_FILE: &str = "lock.json";
+const VERSIONS_DIR: &str = "versions";
+
+/// A state provider that stores each state in its own directory on disk.
+///
+/// The directory of a state is created when the state is first locked or written. The layout for
+/// a state with the id `example` is:
+/// - `<root>/example/current.json` - the latest version of the state, the data as sent by
+///   terraform together with its digest, so that both are replaced by a single rename
+/// - `<root>/example/lock.json` - the current lock, only present while the state is locked
+/// - `<root>/example/versions/<n>.json` - every accepted version of the state
+#[derive(Debug)]
+pub struct FileState {
+    root: PathBuf,
+    lock_ttl: Option<Duration>,
+    locks: StateLocks,
+}
+
+impl FileState {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            lock_ttl: None,
+            locks: StateLocks::default(),
+        }
+    }
+
+    pub fn with_lock_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.lock_ttl = ttl;
+        self
+    }
+
+    /// Determine the directory a state is stored in, rejecting ids that would escape the root
+    fn state_dir(&self, id: &str) -> Result<PathBuf> {
+        if id.is_empty() || id == "." || id == ".." || id.contains(['/', '\\', '\0']) {
+            return Err(Error::InvalidId(id.to_string()));
+        }
+
+        Ok(self.root.join(id))
+    }
+
+    async fn read_lock(&self, dir: &Path) -> Result<Option<TerraformLock>> {
+        match tokio::fs::read(dir.join(LOCK_FILE)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Read the latest version of a state, `None` if it has never been written
+    async fn read_current(&self, dir: &Path) -> Result<Option<StateVersion>> {
+        match tokio::fs::read(dir.join(CURRENT_FILE)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Read the full state from disk, returning `None` if the state has never been written or locked
+    async fn read_state(&self, id: &str) -> Result<Option<TerraformState>> {
+        let dir = self.state_dir(id)?;
+
+        let current = self
+            .read_current(&dir)
+            .await?
+            .map(|version| (version.data, version.info.md5));
+        let lock = self.read_lock(&dir).await?;
+
+        if current.is_none() && lock.is_none() {
+            return Ok(None);
+        }
+
+        let (data, md5) = current.unwrap_or_default();
+        let mut state = TerraformState { lock, data, md5 };
+        if let Some(lock) = state.release_expired_lock(self.lock_ttl) {
+            info!(?lock, "releasing expired lock on state {}", id);
+            self.write_lock(id, None).await?;
+        }
+
+        Ok(Some(state))
+    }
+
+    /// The ids of every state directory, sorted
+    async fn state_ids(&self) -> Result<Vec<String>> {
+        let mut entries = match tokio::fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut ids = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            if let Some(id) = entry.file_name().to_str() {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort();
+
+        Ok(ids)
+    }
+
+    /// Read every version of a state, oldest first
+    async fn read_versions(&self, id: &str) -> Result<Vec<StateVersion>> {
+        let dir = self.state_dir(id)?.join(VERSIONS_DIR);
+
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut versions = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                let data = tokio::fs::read(&path).await?;
+                versions.push(serde_json::from_slice::<StateVersion>(&data)?);
+            }
+        }
+        versions.sort_by_key(|v| v.info.version);
+
+        Ok(versions)
+    }
+
+    /// Record `data` as a new version and make it the current state
+    async fn write_version(
+        &self,
+        id: &str,
+        lock_id: &str,
+        data: String,
+        restored_from: Option<u64>,
+        force: bool,
+    ) -> Result<()> {
+        let state = self.read_state(id).await?.unwrap_or_default();
+        state.check_lock(lock_id)?;
+        if !force {
+            validate_update(&state.data, &data)?;
+        }
+
+        let dir = self.state_dir(id)?;
+        let versions_dir = dir.join(VERSIONS_DIR);
+        tokio::fs::create_dir_all(&versions_dir).await?;
+
+        let number = match self.read_current(&dir).await? {
+            Some(current) => current.info.version + 1,
+            None => self
+                .read_versions(id)
+                .await?
+                .last()
+                .map_or(1, |v| v.info.version + 1),
+        };
+        let version = StateVersion::new(number, state.lock.as_ref(), data, restored_from);
+        let document = serde_json::to_vec(&version)?;
+
+        // the version is written first so the current state is always part of the history
+        write_atomic(&versions_dir.join(format!("{number}.json")), &document).await?;
+        write_atomic(&dir.join(CURRENT_FILE), &document).await
+    }
+
+    /// Write or remove the lock file, cleaning up the directory of states that were never written
+    async fn write_lock(&self, id: &str, lock: Option<&TerraformLock>) -> Result<()> {
+        let dir = self.state_dir(id)?;
+        let path = dir.join(LOCK_FILE);
+
+        match lock {
+            Some(lock) => {
+                tokio::fs::create_dir_all(&dir).await?;
+                write_atomic(&path, &serde_json::to_vec(lock)?).await
+            }
+            None => {
+                remove_if_exists(&path).await?;
+                if !tokio::fs::try_exists(dir.join(CURRENT_FILE)).await? {
+                    // only succeeds if the directory is empty
+                    let _ = tokio::fs::remove_dir(&dir).await;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TerraformStateProvider for FileState {
+    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>> {
+        let _guard = self.locks.lock(id).await;
+
+        self.read_state(id).await
+    }
+
+    async fn update_state(&self, id: &str, lock_id: &str, data: String, force: bool) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        self.write_version(id, lock_id, data, None, force).await
+    }
+
+    async fn lock_state(&self, id: &str, lock: TerraformLock) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let mut state = self.read_state(id).await?.unwrap_or_default();
+        state.lock(lock)?;
+
+        self.write_lock(id, state.lock.as_ref()).await
+    }
+
+    async fn unlock_state(&self, id: &str, lock: &TerraformLock) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let mut state = self.read_state(id).await?.ok_or(Error::NotFound)?;
+        state.unlock(lock)?;
+
+        self.write_lock(id, state.lock.as_ref()).await
+    }
+
+    async fn force_unlock_state(&self, id: &str) -> Result<TerraformLock> {
+        let _guard = self.locks.lock(id).await;
+
+        let mut state = self.read_state(id).await?.ok_or(Error::NotFound)?;
+        let lock = state.force_unlock()?;
+
+        self.write_lock(id, None).await?;
+
+        Ok(lock)
+    }
+
+    async fn list_locks(&self) -> Result<Vec<LockedState>> {
+        let mut locks = Vec::new();
+        for id in self.state_ids().await? {
+            let _guard = self.locks.lock(&id).await;
+            if let Some(lock) = self
+                .read_state(&id)
+                .await?
+                .and_then(|state| state.lock_info().cloned())
+            {
+                locks.push(LockedState { id, lock });
+            }
+        }
+
+        Ok(locks)
+    }
+
+    async fn list_states(&self) -> Result<Vec<StateSummary>> {
+        let mut states = Vec::new();
+        for id in self.state_ids().await? {
+            let _guard = self.locks.lock(&id).await;
+            let Some(state) = self.read_state(&id).await? else {
+                continue;
+            };
+
+            let dir = self.state_dir(&id)?;
+            let last_modified = match tokio::fs::metadata(dir.join(CURRENT_FILE)).await {
+                Ok(metadata) => Some(metadata.modified()?.into()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+
+            states.push(StateSummary::new(id, &state, last_modified));
+        }
+
+        Ok(states)
+    }
+
+    async fn delete_state(&self, id: &str, lock_id: &str) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let state = self.read_state(id).await?.ok_or(Error::NotFound)?;
+        state.check_lock(lock_id)?;
+
+        tokio::fs::remove_dir_all(self.state_dir(id)?).await?;
+
+        Ok(())
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersionInfo>> {
+        let _guard = self.locks.lock(id).await;
+
+        if self.read_state(id).await?.is_none() {
+            return Err(Error::NotFound);
+        }
+
+        let versions = self.read_versions(id).await?;
+
+        Ok(versions.into_iter().map(|v| v.info).collect())
+    }
+
+    async fn get_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>> {
+        let path = self
+            .state_dir(id)?
+            .join(VERSIONS_DIR)
+            .join(format!("{version}.json"));
+
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn restore_version(&self, id: &str, lock_id: &str, version: u64) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let data = self
+            .get_version(id, version)
+            .await?
+            .ok_or(Error::VersionNotFound(version))?
+            .data;
+
+        self.write_version(id, lock_id, data, Some(version), true)
+            .await
+    }
+}
+
+/// Replace the contents of a file without ever leaving a partially written file behind.
+///
+/// The data is written to a temporary file in the same directory, flushed to disk and then
+/// renamed over the destination.
+pub(crate) async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "path has no parent")
+    })?;
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "path has no file name")
+    })?;
+
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp_path = dir.join(tmp_name);
+
+    let mut file = tokio::fs::File::create(&tmp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&tmp_path, path).await?;
+
+    // persist the rename itself
+    tokio::fs::File::open(dir).await?.sync_all().await?;
+
+    Ok(())
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,475 @@
+use crate::{
+    error::{Error, Result},
+    sync::StateLocks,
+    validation::validate_update,
+    LockedState, StateSummary, StateVersion, StateVersionInfo, TerraformLock, TerraformState,
+    TerraformStateProvider,
+};
+use std::{path::PathBuf, time::Duration};
+use tracing::info;
+
+/// The namespace holding every ref written by [`GitState`]. Clients of the repository's git
+/// routes must neither see nor update these refs, as they hold state data and locks.
+pub const REF_NAMESPACE: &str = "refs/thoenix/";
+
+const STATE_REF_PREFIX: &str = "refs/thoenix/state/";
+const LOCK_REF_PREFIX: &str = "refs/thoenix/lock/";
+const STATE_FILE: &str = "terraform.tfstate";
+const VERSION_FILE: &str = "version.json";
+
+/// A state provider that stores state inside of a bare git repository.
+///
+/// A state is created by its first update, every update to a state is recorded as a commit on
+/// `refs/thoenix/state/<id>` containing a `terraform.tfstate` file and a `version.json` file
+/// describing the version, so the full history of a state is available through git.
+/// While a state is locked, `refs/thoenix/lock/<id>` points to a blob containing the lock.
+///
+/// The repository may be one that is also served over git, every ref under [`REF_NAMESPACE`] is
+/// hidden from and refused to clients of the git routes. A `git clone --mirror` through the
+/// server therefore contains no state. Back states up by mirroring the repository from the
+/// server's disk, or by copying them with `thoenix state migrate --history --from git:<repo>`.
+#[derive(Debug)]
+pub struct GitState {
+    repo_path: PathBuf,
+    lock_ttl: Option<Duration>,
+    locks: StateLocks,
+}
+
+impl GitState {
+    pub fn new(repo_path: impl Into<PathBuf>) -> Self {
+        Self {
+            repo_path: repo_path.into(),
+            lock_ttl: None,
+            locks: StateLocks::default(),
+        }
+    }
+
+    pub fn with_lock_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.lock_ttl = ttl;
+        self
+    }
+
+    /// Run `f` against the repository, off of the async workers since libgit2 blocks on disk io
+    async fn with_repo<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&git2::Repository) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let repo_path = self.repo_path.clone();
+
+        tokio::task::spawn_blocking(move || f(&git2::Repository::open_bare(repo_path)?))
+            .await
+            .map_err(|e| Error::Io(e.into()))?
+    }
+
+    /// The ids of every state with a ref under `prefix`
+    async fn list_ids(&self, prefix: &'static str) -> Result<Vec<String>> {
+        self.with_repo(move |repo| {
+            Ok(repo
+                .references_glob(&format!("{prefix}*"))?
+                .filter_map(|r| r.ok())
+                .filter_map(|r| r.name().map(|name| name[prefix.len()..].to_string()))
+                .collect())
+        })
+        .await
+    }
+}
+
+fn read_lock(repo: &git2::Repository, id: &str) -> Result<Option<TerraformLock>> {
+    let reference = match repo.find_reference(&lock_ref(id)?) {
+        Ok(reference) => reference,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let blob = reference.peel_to_blob()?;
+
+    Ok(Some(serde_json::from_slice(blob.content())?))
+}
+
+/// Read the current state, returning `None` if the state has never been written or locked
+fn read_state(
+    repo: &git2::Repository,
+    id: &str,
+    lock_ttl: Option<Duration>,
+) -> Result<Option<TerraformState>> {
+    let (data, md5) = match head_commit(repo, id)? {
+        Some(commit) => {
+            let data = read_data(repo, &commit)?;
+            let md5 = read_version_info(repo, &commit)?.md5;
+
+            (Some(data), md5)
+        }
+        None => (None, None),
+    };
+    let lock = read_lock(repo, id)?;
+
+    if data.is_none() && lock.is_none() {
+        return Ok(None);
+    }
+
+    let mut state = TerraformState {
+        lock,
+        data: data.unwrap_or_default(),
+        md5,
+    };
+    if let Some(lock) = state.release_expired_lock(lock_ttl) {
+        info!(?lock, "releasing expired lock on state {}", id);
+        delete_ref(repo, &lock_ref(id)?)?;
+    }
+
+    Ok(Some(state))
+}
+
+/// Walk the history of a state, newest first, until `f` returns `false`
+///
+/// Only the version metadata of each commit is read, the data of a version has to be loaded with
+/// [`read_data`].
+fn walk_versions(
+    repo: &git2::Repository,
+    id: &str,
+    mut f: impl FnMut(&git2::Commit, StateVersionInfo) -> Result<bool>,
+) -> Result<()> {
+    let mut commit = head_commit(repo, id)?;
+    while let Some(current) = commit {
+        if !f(&current, read_version_info(repo, &current)?)? {
+            break;
+        }
+        commit = current.parents().next();
+    }
+
+    Ok(())
+}
+
+/// Read a single version of a state
+fn read_version(repo: &git2::Repository, id: &str, version: u64) -> Result<Option<StateVersion>> {
+    let mut found = None;
+    walk_versions(repo, id, |commit, info| {
+        // version numbers only ever grow along the history
+        if info.version < version {
+            return Ok(false);
+        }
+        if info.version == version {
+            found = Some(StateVersion {
+                data: read_data(repo, commit)?,
+                info,
+            });
+            return Ok(false);
+        }
+
+        Ok(true)
+    })?;
+
+    Ok(found)
+}
+
+/// Record `data` as a new version and make it the current state
+#[allow(clippy::too_many_arguments)]
+fn write_version(
+    repo: &git2::Repository,
+    id: &str,
+    lock_ttl: Option<Duration>,
+    lock_id: &str,
+    data: String,
+    restored_from: Option<u64>,
+    force: bool,
+) -> Result<()> {
+    let state = read_state(repo, id, lock_ttl)?.unwrap_or_default();
+    state.check_lock(lock_id)?;
+    if !force {
+        validate_update(&state.data, &data)?;
+    }
+
+    // the head commit records the latest version, so the history never has to be walked
+    let number = match head_commit(repo, id)? {
+        Some(commit) => read_version_info(repo, &commit)?.version + 1,
+        None => 1,
+    };
+    let version = StateVersion::new(number, state.lock_info(), data, restored_from);
+
+    let mut message = match restored_from {
+        Some(from) => format!("restore state {id} to version {from}"),
+        None => format!("update state {id}"),
+    };
+    if let Some(lock) = state.lock_info() {
+        message.push_str(&format!(
+            "\n\nOperation: {}\nWho: {}\nLock: {}",
+            lock.operation, lock.who, lock.id
+        ));
+    }
+    commit_state(
+        repo,
+        &state_ref(id)?,
+        version.data.as_bytes(),
+        &version.info,
+        &message,
+    )?;
+
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl TerraformStateProvider for GitState {
+    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>> {
+        let _guard = self.locks.lock(id).await;
+
+        let (id, lock_ttl) = (id.to_string(), self.lock_ttl);
+        self.with_repo(move |repo| read_state(repo, &id, lock_ttl))
+            .await
+    }
+
+    async fn update_state(&self, id: &str, lock_id: &str, data: String, force: bool) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let (id, lock_id, lock_ttl) = (id.to_string(), lock_id.to_string(), self.lock_ttl);
+        self.with_repo(move |repo| write_version(repo, &id, lock_ttl, &lock_id, data, None, force))
+            .await
+    }
+
+    async fn lock_state(&self, id: &str, lock: TerraformLock) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let (id, lock_ttl) = (id.to_string(), self.lock_ttl);
+        self.with_repo(move |repo| {
+            let mut state = read_state(repo, &id, lock_ttl)?.unwrap_or_default();
+            state.lock(lock)?;
+
+            let blob = repo.blob(&serde_json::to_vec(&state.lock_info())?)?;
+            // creating the reference without `force` fails if another client acquired the lock first
+            let result = repo.reference(&lock_ref(&id)?, blob, false, &format!("lock state {id}"));
+            match result {
+                Ok(_) => Ok(()),
+                Err(e) if e.code() == git2::ErrorCode::Exists => Err(Error::StateLocked),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await
+    }
+
+    async fn unlock_state(&self, id: &str, lock: &TerraformLock) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let (id, lock, lock_ttl) = (id.to_string(), lock.clone(), self.lock_ttl);
+        self.with_repo(move |repo| {
+            let mut state = read_state(repo, &id, lock_ttl)?.ok_or(Error::NotFound)?;
+            state.unlock(&lock)?;
+
+            delete_ref(repo, &lock_ref(&id)?)
+        })
+        .await
+    }
+
+    async fn force_unlock_state(&self, id: &str) -> Result<TerraformLock> {
+        let _guard = self.locks.lock(id).await;
+
+        let (id, lock_ttl) = (id.to_string(), self.lock_ttl);
+        self.with_repo(move |repo| {
+            let mut state = read_state(repo, &id, lock_ttl)?.ok_or(Error::NotFound)?;
+            let lock = state.force_unlock()?;
+
+            delete_ref(repo, &lock_ref(&id)?)?;
+
+            Ok(lock)
+        })
+        .await
+    }
+
+    async fn list_locks(&self) -> Result<Vec<LockedState>> {
+        let mut locks = Vec::new();
+        for id in self.list_ids(LOCK_REF_PREFIX).await? {
+            let _guard = self.locks.lock(&id).await;
+
+            let lock_ttl = self.lock_ttl;
+            let locked = self
+                .with_repo(move |repo| {
+                    let lock = read_state(repo, &id, lock_ttl)?
+                        .and_then(|state| state.lock_info().cloned());
+
+                    Ok(lock.map(|lock| LockedState { id, lock }))
+                })
+                .await?;
+            locks.extend(locked);
+        }
+        locks.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(locks)
+    }
+
+    async fn list_states(&self) -> Result<Vec<StateSummary>> {
+        let mut ids = std::collections::BTreeSet::new();
+        for prefix in [STATE_REF_PREFIX, LOCK_REF_PREFIX] {
+            ids.extend(self.list_ids(prefix).await?);
+        }
+
+        let mut states = Vec::new();
+        for id in ids {
+            let _guard = self.locks.lock(&id).await;
+
+            let lock_ttl = self.lock_ttl;
+            let summary = self
+                .with_repo(move |repo| {
+                    let Some(state) = read_state(repo, &id, lock_ttl)? else {
+                        return Ok(None);
+                    };
+
+                    let last_modified = head_commit(repo, &id)?.and_then(|commit| {
+                        chrono::DateTime::from_timestamp(commit.time().seconds(), 0)
+                    });
+
+                    Ok(Some(StateSummary::new(id, &state, last_modified)))
+                })
+                .await?;
+            states.extend(summary);
+        }
+
+        Ok(states)
+    }
+
+    async fn delete_state(&self, id: &str, lock_id: &str) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let (id, lock_id, lock_ttl) = (id.to_string(), lock_id.to_string(), self.lock_ttl);
+        self.with_repo(move |repo| {
+            let state = read_state(repo, &id, lock_ttl)?.ok_or(Error::NotFound)?;
+            state.check_lock(&lock_id)?;
+
+            // the commits stay in the object database until git garbage collects them
+            delete_ref(repo, &state_ref(&id)?)?;
+            delete_ref(repo, &lock_ref(&id)?)?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersionInfo>> {
+        let _guard = self.locks.lock(id).await;
+
+        let (id, lock_ttl) = (id.to_string(), self.lock_ttl);
+        self.with_repo(move |repo| {
+            // a state that has only been locked has no versions yet
+            read_state(repo, &id, lock_ttl)?.ok_or(Error::NotFound)?;
+
+            let mut versions = Vec::new();
+            walk_versions(repo, &id, |_, info| {
+                versions.push(info);
+                Ok(true)
+            })?;
+            versions.reverse();
+
+            Ok(versions)
+        })
+        .await
+    }
+
+    async fn get_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>> {
+        let id = id.to_string();
+        self.with_repo(move |repo| read_version(repo, &id, version))
+            .await
+    }
+
+    async fn restore_version(&self, id: &str, lock_id: &str, version: u64) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let (id, lock_id, lock_ttl) = (id.to_string(), lock_id.to_string(), self.lock_ttl);
+        self.with_repo(move |repo| {
+            let data = read_version(repo, &id, version)?
+                .ok_or(Error::VersionNotFound(version))?
+                .data;
+
+            write_version(repo, &id, lock_ttl, &lock_id, data, Some(version), true)
+        })
+        .await
+    }
+}
+
+fn state_ref(id: &str) -> Result<String> {
+    checked_ref(STATE_REF_PREFIX, id)
+}
+
+fn lock_ref(id: &str) -> Result<String> {
+    checked_ref(LOCK_REF_PREFIX, id)
+}
+
+fn checked_ref(prefix: &str, id: &str) -> Result<String> {
+    let name = format!("{prefix}{id}");
+    if id.is_empty() || id.contains('/') || !git2::Reference::is_valid_name(&name) {
+        return Err(Error::InvalidId(id.to_string()));
+    }
+
+    Ok(name)
+}
+
+/// The commit recording the latest version of a state, if it has ever been written
+fn head_commit<'r>(repo: &'r git2::Repository, id: &str) -> Result<Option<git2::Commit<'r>>> {
+    match repo.find_reference(&state_ref(id)?) {
+        Ok(reference) => Ok(Some(reference.peel_to_commit()?)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the metadata of the version stored in a commit
+fn read_version_info(repo: &git2::Repository, commit: &git2::Commit) -> Result<StateVersionInfo> {
+    let tree = commit.tree()?;
+    let entry = tree.get_name(VERSION_FILE).ok_or_else(|| {
+        git2::Error::from_str(&format!("commit {} has no {VERSION_FILE}", commit.id()))
+    })?;
+    let blob = entry.to_object(repo)?.peel_to_blob()?;
+
+    Ok(serde_json::from_slice(blob.content())?)
+}
+
+/// Read the state data stored in a commit
+fn read_data(repo: &git2::Repository, commit: &git2::Commit) -> Result<String> {
+    let tree = commit.tree()?;
+    let Some(entry) = tree.get_name(STATE_FILE) else {
+        return Ok(String::new());
+    };
+    let blob = entry.to_object(repo)?.peel_to_blob()?;
+
+    Ok(String::from_utf8_lossy(blob.content()).into_owned())
+}
+
+/// Record `data` as the new contents of the state on top of the current commit of `state_ref`
+fn commit_state(
+    repo: &git2::Repository,
+    state_ref: &str,
+    data: &[u8],
+    version: &StateVersionInfo,
+    message: &str,
+) -> Result<git2::Oid> {
+    let parent = match repo.find_reference(state_ref) {
+        Ok(reference) => Some(reference.peel_to_commit()?),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let blob = repo.blob(data)?;
+    let mut tree = repo.treebuilder(None)?;
+    tree.insert(STATE_FILE, blob, git2::FileMode::Blob.into())?;
+    let blob = repo.blob(&serde_json::to_vec_pretty(version)?)?;
+    tree.insert(VERSION_FILE, blob, git2::FileMode::Blob.into())?;
+    let tree = repo.find_tree(tree.write()?)?;
+
+    let signature = git2::Signature::now("thoenix", "thoenix@localhost")?;
+    let parents = parent.iter().collect::<Vec<_>>();
+
+    // libgit2 refuses to move the reference if it no longer points at `parent`
+    let oid = repo.commit(
+        Some(state_ref),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )?;
+
+    Ok(oid)
+}
+
+fn delete_ref(repo: &git2::Repository, name: &str) -> Result<()> {
+    match repo.find_reference(name) {
+        Ok(mut reference) => Ok(reference.delete()?),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -1,19 +1,87 @@
 use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
+#[cfg(feature = "conformance")]
+pub mod conformance;
+pub mod diff;
+pub mod encryption;
 pub mod error;
+pub mod file;
+pub mod git;
+pub mod memory;
+pub mod migration;
+pub mod outputs;
+pub mod resources;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod sync;
+pub mod validation;
+
+pub use encryption::{EncryptedState, Keyring};
+pub use file::FileState;
+pub use git::GitState;
+pub use memory::InMemoryState;
+#[cfg(feature = "s3")]
+pub use s3::S3State;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteState;
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct TerraformState {
     lock: Option<TerraformLock>,
     pub data: String,
+    /// The [`content_md5`] of `data` recorded when it was written, absent for states that have only
+    /// been locked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+}
+
+/// The base64 encoded md5 digest of `data`, as sent by terraform in the `Content-MD5` header
+pub fn content_md5(data: &[u8]) -> String {
+    use base64::Engine;
+    use md5::Digest;
+
+    base64::engine::general_purpose::STANDARD.encode(md5::Md5::digest(data))
 }
 
 impl TerraformState {
+    /// Ensure that the data still matches the digest recorded when it was written
+    pub fn verify(&self) -> Result<()> {
+        match &self.md5 {
+            Some(md5) if *md5 != content_md5(self.data.as_bytes()) => Err(Error::DigestMismatch),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the data, recording its digest
+    pub fn set_data(&mut self, data: String) {
+        self.md5 = Some(content_md5(data.as_bytes()));
+        self.data = data;
+    }
+
     pub fn is_locked(&self) -> bool {
         self.lock.is_some()
     }
 
+    /// The lock currently held on the state, if any
+    pub fn lock_info(&self) -> Option<&TerraformLock> {
+        self.lock.as_ref()
+    }
+
+    /// Release the current lock if it was acquired longer than `ttl` ago, returning the released lock
+    pub fn release_expired_lock(&mut self, ttl: Option<Duration>) -> Option<TerraformLock> {
+        let ttl = ttl?;
+
+        if self.lock.as_ref().is_some_and(|l| l.is_expired(ttl)) {
+            return self.lock.take();
+        }
+
+        None
+    }
+
     pub fn check_lock(&self, id: &str) -> Result<()> {
         if self.lock.as_ref().is_some_and(|l| l.id != id) {
             return Err(Error::StateLocked);
@@ -22,16 +90,26 @@ impl TerraformState {
         Ok(())
     }
 
-    pub fn lock(&mut self, lock: TerraformLock) -> Result<()> {
+    pub fn lock(&mut self, mut lock: TerraformLock) -> Result<()> {
         if self.is_locked() {
             return Err(Error::StateLocked);
         }
 
+        let now = chrono::Utc::now();
+        lock.created.get_or_insert(now);
+        // the ttl counts from the server's clock, a client with a clock that is behind mustn't
+        // be able to hand out a lock that is already expired
+        lock.acquired = Some(now);
         self.lock = Some(lock);
 
         Ok(())
     }
 
+    /// Release the lock regardless of who holds it, returning the released lock
+    pub fn force_unlock(&mut self) -> Result<TerraformLock> {
+        self.lock.take().ok_or(Error::NotLocked)
+    }
+
     pub fn unlock(&mut self, id: &TerraformLock) -> Result<()> {
         if self.lock.as_ref().is_some_and(|l| l.id != id.id) {
             return Err(Error::StateLocked);
@@ -62,75 +140,162 @@ pub struct TerraformLock {
     pub who: String,
     #[serde(rename = "Version")]
     pub version: String,
+    /// When the client created the lock, filled in by the server if the client does not send it
+    #[serde(rename = "Created", default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "Path", default)]
+    pub path: String,
+    /// When the server granted the lock. It is replaced whenever a state is locked, so a value
+    /// sent by the client is never used
+    #[serde(rename = "Acquired", default, skip_serializing_if = "Option::is_none")]
+    pub acquired: Option<chrono::DateTime<chrono::Utc>>,
 }
 
-#[allow(async_fn_in_trait)]
-pub trait TerraformStateProvider {
-    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>>;
-    async fn update_state(&mut self, id: &str, lock_id: &str, data: String) -> Result<()>;
-    async fn lock_state(&mut self, id: &str, lock: TerraformLock) -> Result<()>;
-    async fn unlock_state(&mut self, id: &str, lock: &TerraformLock) -> Result<()>;
+impl TerraformLock {
+    /// Whether the lock was granted longer than `ttl` ago
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        let Some(acquired) = self.acquired else {
+            return false;
+        };
+
+        chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| acquired.checked_add_signed(ttl))
+            .is_some_and(|expires| expires <= chrono::Utc::now())
+    }
 }
 
-/// A state provider that stores state in memory
-#[derive(Debug, Default)]
-pub struct InMemoryState {
-    pub tf_state: std::collections::HashMap<String, TerraformState>,
+/// A state together with the lock currently held on it
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LockedState {
+    pub id: String,
+    pub lock: TerraformLock,
 }
 
-impl InMemoryState {
-    pub fn new() -> Self {
-        Default::default()
-    }
-
-    /// Insert a new state into the provider
-    pub async fn create_state(&mut self, id: String) -> Result<()> {
-        self.tf_state.insert(id, TerraformState::default());
-
-        Ok(())
-    }
-
-    pub async fn expect_not_locked(&self, id: &str) -> Result<()> {
-        let state = self.tf_state.get(id).ok_or(error::Error::NotFound)?;
+/// An overview of a stored state
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StateSummary {
+    pub id: String,
+    /// The size of the state data in bytes
+    pub size: u64,
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// The serial of the state data, if it is a valid state document
+    pub serial: Option<u64>,
+    pub lock: Option<TerraformLock>,
+}
 
-        if state.is_locked() {
-            return Err(error::Error::StateLocked);
+impl StateSummary {
+    pub(crate) fn new(
+        id: String,
+        state: &TerraformState,
+        last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Self {
+        Self {
+            id,
+            size: state.data.len() as u64,
+            last_modified,
+            serial: validation::StateMetadata::parse(&state.data)
+                .ok()
+                .map(|m| m.serial),
+            lock: state.lock.clone(),
         }
-
-        Ok(())
     }
 }
 
-impl TerraformStateProvider for InMemoryState {
-    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>> {
-        let state = self.tf_state.get(id).cloned();
-
-        Ok(state)
-    }
-
-    async fn update_state(&mut self, id: &str, lock_id: &str, data: String) -> Result<()> {
-        // Ensure that the state is not locked
-        let state = self.tf_state.get_mut(id).ok_or(error::Error::NotFound)?;
-        state.check_lock(lock_id)?;
+/// Metadata about an accepted update to a state
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StateVersionInfo {
+    /// The number of the version, starting at 1 for the first update of a state
+    pub version: u64,
+    pub created: chrono::DateTime<chrono::Utc>,
+    /// The lock that was held while the version was written, if any
+    pub lock_id: Option<String>,
+    pub who: Option<String>,
+    pub operation: Option<String>,
+    /// The version this one was copied from when it was created by a rollback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restored_from: Option<u64>,
+    /// The [`content_md5`] of the version's data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+}
 
-        state.data = data;
+/// A previously accepted version of a state
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StateVersion {
+    #[serde(flatten)]
+    pub info: StateVersionInfo,
+    pub data: String,
+}
 
-        Ok(())
+impl StateVersion {
+    /// Record `data` as a new version, attributing it to the lock currently held on the state
+    pub(crate) fn new(
+        version: u64,
+        lock: Option<&TerraformLock>,
+        data: String,
+        restored_from: Option<u64>,
+    ) -> Self {
+        let info = StateVersionInfo {
+            version,
+            created: chrono::Utc::now(),
+            lock_id: lock.map(|l| l.id.clone()),
+            who: lock.map(|l| l.who.clone()),
+            operation: lock.map(|l| l.operation.clone()),
+            restored_from,
+            md5: Some(content_md5(data.as_bytes())),
+        };
+
+        Self { info, data }
     }
 
-    async fn lock_state(&mut self, id: &str, lock: TerraformLock) -> Result<()> {
-        let state = self.tf_state.get_mut(id).ok_or(error::Error::NotFound)?;
-
-        state.lock(lock)?;
-
-        Ok(())
+    /// Ensure that the data still matches the digest recorded when it was written
+    pub fn verify(&self) -> Result<()> {
+        match &self.info.md5 {
+            Some(md5) if *md5 != content_md5(self.data.as_bytes()) => Err(Error::DigestMismatch),
+            _ => Ok(()),
+        }
     }
+}
 
-    async fn unlock_state(&mut self, id: &str, lock: &TerraformLock) -> Result<()> {
-        let state = self.tf_state.get_mut(id).ok_or(error::Error::NotFound)?;
-
-        state.unlock(lock)?;
-
-        Ok(())
-    }
+/// Storage for terraform states and their locks.
+///
+/// Implementations synchronise access to each state internally, so operations on different
+/// states may run concurrently and a provider can be shared as `Arc<dyn TerraformStateProvider>`.
+///
+/// States are never created explicitly. A state exists once it has been locked or written, and a
+/// state that has only been locked has empty data.
+///
+/// Providers can be given a lock ttl with their `with_lock_ttl` builder. A lock that has been
+/// held for longer than the ttl is released the next time its state is read, see
+/// [`TerraformState::release_expired_lock`].
+#[async_trait::async_trait]
+pub trait TerraformStateProvider: Send + Sync {
+    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>>;
+    /// Replace the data of a state.
+    ///
+    /// Unless `force` is set, the new data must be a newer serial of the same lineage as the
+    /// current data, see [`validation::validate_update`].
+    async fn update_state(&self, id: &str, lock_id: &str, data: String, force: bool) -> Result<()>;
+    async fn lock_state(&self, id: &str, lock: TerraformLock) -> Result<()>;
+    async fn unlock_state(&self, id: &str, lock: &TerraformLock) -> Result<()>;
+    /// Release the lock on a state no matter who holds it, returning the released lock
+    async fn force_unlock_state(&self, id: &str) -> Result<TerraformLock>;
+    /// List every state that is currently locked, ordered by id
+    async fn list_locks(&self) -> Result<Vec<LockedState>>;
+    /// List every state in the provider, ordered by id
+    async fn list_states(&self) -> Result<Vec<StateSummary>>;
+    /// Remove a state and all of its versions.
+    ///
+    /// A locked state can only be deleted by the holder of the lock.
+    async fn delete_state(&self, id: &str, lock_id: &str) -> Result<()>;
+
+    /// List every version of a state, oldest first
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersionInfo>>;
+    async fn get_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>>;
+    /// Make the data of an older version the current state by recording it as a new version.
+    ///
+    /// The data isn't validated against the current state, as rolling back intentionally
+    /// writes an older serial.
+    async fn restore_version(&self, id: &str, lock_id: &str, version: u64) -> Result<()>;
 }
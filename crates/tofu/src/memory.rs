@@ -0,0 +1,225 @@
+use crate::{
+    error::{Error, Result},
+    validation::validate_update,
+    LockedState, StateSummary, StateVersion, StateVersionInfo, TerraformLock, TerraformState,
+    TerraformStateProvider,
+};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// A state together with every version written to it
+#[derive(Debug, Default, Clone)]
+struct StoredState {
+    state: TerraformState,
+    versions: Vec<StateVersion>,
+}
+
+/// A state provider that stores state in memory.
+///
+/// Every operation completes without waiting, so a single mutex around all states is held only
+/// briefly and never blocks other states for long.
+#[derive(Debug, Default)]
+pub struct InMemoryState {
+    states: Mutex<HashMap<String, StoredState>>,
+    /// How long a lock may be held before it is released automatically
+    lock_ttl: Option<Duration>,
+}
+
+impl InMemoryState {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_lock_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.lock_ttl = ttl;
+        self
+    }
+
+    pub async fn expect_not_locked(&self, id: &str) -> Result<()> {
+        let state = self.get_state(id).await?.ok_or(Error::NotFound)?;
+
+        if state.is_locked() {
+            return Err(Error::StateLocked);
+        }
+
+        Ok(())
+    }
+
+    /// Run `f` against a stored state after releasing its lock if it has expired
+    fn with_state<T>(&self, id: &str, f: impl FnOnce(&mut StoredState) -> Result<T>) -> Result<T> {
+        let mut states = self.states.lock().expect("state map poisoned");
+        let stored = states.get_mut(id).ok_or(Error::NotFound)?;
+        stored.state.release_expired_lock(self.lock_ttl);
+
+        f(stored)
+    }
+
+    /// Run `f` against a stored state, inserting an empty state if it does not exist yet.
+    ///
+    /// States that end up without any data or lock are removed again afterwards.
+    fn with_new_state<T>(
+        &self,
+        id: &str,
+        f: impl FnOnce(&mut StoredState) -> Result<T>,
+    ) -> Result<T> {
+        let mut states = self.states.lock().expect("state map poisoned");
+        let stored = states.entry(id.to_string()).or_default();
+        stored.state.release_expired_lock(self.lock_ttl);
+
+        let result = f(stored);
+        if stored.versions.is_empty() && !stored.state.is_locked() {
+            states.remove(id);
+        }
+
+        result
+    }
+
+    /// Forget a state that was locked but never written
+    fn remove_if_unused(&self, id: &str) {
+        let mut states = self.states.lock().expect("state map poisoned");
+        if states
+            .get(id)
+            .is_some_and(|stored| stored.versions.is_empty() && !stored.state.is_locked())
+        {
+            states.remove(id);
+        }
+    }
+
+    fn write_version(
+        &self,
+        id: &str,
+        lock_id: &str,
+        data: String,
+        restored_from: Option<u64>,
+        force: bool,
+    ) -> Result<()> {
+        self.with_new_state(id, |stored| {
+            stored.state.check_lock(lock_id)?;
+            if !force {
+                validate_update(&stored.state.data, &data)?;
+            }
+
+            let number = stored.versions.last().map_or(1, |v| v.info.version + 1);
+            stored.versions.push(StateVersion::new(
+                number,
+                stored.state.lock_info(),
+                data.clone(),
+                restored_from,
+            ));
+            stored.state.set_data(data);
+
+            Ok(())
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl TerraformStateProvider for InMemoryState {
+    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>> {
+        match self.with_state(id, |stored| Ok(stored.state.clone())) {
+            Ok(state) => Ok(Some(state)),
+            Err(Error::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn update_state(&self, id: &str, lock_id: &str, data: String, force: bool) -> Result<()> {
+        self.write_version(id, lock_id, data, None, force)
+    }
+
+    async fn lock_state(&self, id: &str, lock: TerraformLock) -> Result<()> {
+        self.with_new_state(id, |stored| stored.state.lock(lock))
+    }
+
+    async fn unlock_state(&self, id: &str, lock: &TerraformLock) -> Result<()> {
+        self.with_state(id, |stored| stored.state.unlock(lock))?;
+        self.remove_if_unused(id);
+
+        Ok(())
+    }
+
+    async fn force_unlock_state(&self, id: &str) -> Result<TerraformLock> {
+        let lock = self.with_state(id, |stored| stored.state.force_unlock())?;
+        self.remove_if_unused(id);
+
+        Ok(lock)
+    }
+
+    async fn list_locks(&self) -> Result<Vec<LockedState>> {
+        let mut states = self.states.lock().expect("state map poisoned");
+
+        let mut locks = states
+            .iter_mut()
+            .filter_map(|(id, stored)| {
+                stored.state.release_expired_lock(self.lock_ttl);
+
+                let lock = stored.state.lock_info()?.clone();
+                Some(LockedState {
+                    id: id.clone(),
+                    lock,
+                })
+            })
+            .collect::<Vec<_>>();
+        locks.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(locks)
+    }
+
+    async fn list_states(&self) -> Result<Vec<StateSummary>> {
+        let mut states = self.states.lock().expect("state map poisoned");
+
+        let mut summaries = states
+            .iter_mut()
+            .map(|(id, stored)| {
+                stored.state.release_expired_lock(self.lock_ttl);
+
+                let last_modified = stored.versions.last().map(|v| v.info.created);
+                StateSummary::new(id.clone(), &stored.state, last_modified)
+            })
+            .collect::<Vec<_>>();
+        summaries.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(summaries)
+    }
+
+    async fn delete_state(&self, id: &str, lock_id: &str) -> Result<()> {
+        let mut states = self.states.lock().expect("state map poisoned");
+        let stored = states.get_mut(id).ok_or(Error::NotFound)?;
+        stored.state.release_expired_lock(self.lock_ttl);
+        stored.state.check_lock(lock_id)?;
+
+        states.remove(id);
+
+        Ok(())
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersionInfo>> {
+        self.with_state(id, |stored| {
+            Ok(stored.versions.iter().map(|v| v.info.clone()).collect())
+        })
+    }
+
+    async fn get_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>> {
+        let version = self.with_state(id, |stored| {
+            Ok(stored
+                .versions
+                .iter()
+                .find(|v| v.info.version == version)
+                .cloned())
+        });
+
+        match version {
+            Err(Error::NotFound) => Ok(None),
+            version => version,
+        }
+    }
+
+    async fn restore_version(&self, id: &str, lock_id: &str, version: u64) -> Result<()> {
+        let data = self
+            .get_version(id, version)
+            .await?
+            .ok_or(Error::VersionNotFound(version))?
+            .data;
+
+        self.write_version(id, lock_id, data, Some(version), true)
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{
+    error::{Error, Result},
+    TerraformLock, TerraformStateProvider,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// The lock id held on a source state while it is copied
+const MIGRATION_LOCK_ID: &str = "thoenix-migration";
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MigrationOptions {
+    /// Copy every version of a state instead of only its current data
+    pub history: bool,
+    /// Only report what would be migrated
+    pub dry_run: bool,
+}
+
+/// A state that was, or would be, copied
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MigratedState {
+    pub id: String,
+    /// The number of versions written to the destination
+    pub versions: usize,
+}
+
+/// A state that could not be copied
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct FailedState {
+    pub id: String,
+    pub error: String,
+}
+
+/// What happened to each state of the source provider
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MigrationReport {
+    pub migrated: Vec<MigratedState>,
+    /// States that are locked in the source or destination and were left alone
+    pub locked: Vec<String>,
+    /// States that already exist in the destination and were left alone
+    pub existing: Vec<String>,
+    /// States that could not be copied, anything written for them was removed again
+    #[serde(default)]
+    pub failed: Vec<FailedState>,
+}
+
+/// Copy every state from one provider to another.
+///
+/// Each source state is locked while it is copied, so states that are in use are reported as
+/// locked instead of being copied halfway through a change. States that already exist in the
+/// destination are never overwritten. Copied data is written as-is, so states encrypted at rest
+/// must be read with the same keys afterwards. A state that fails to copy is removed from the
+/// destination again and reported, so that a later run doesn't mistake it for an existing state.
+///
+/// When copying history, versions keep their order but are attributed to the migration instead
+/// of the lock holder that originally wrote them.
+pub async fn migrate(
+    from: &dyn TerraformStateProvider,
+    to: &dyn TerraformStateProvider,
+    options: MigrationOptions,
+) -> Result<MigrationReport> {
+    let mut report = MigrationReport::default();
+
+    for summary in from.list_states().await? {
+        let id = summary.id;
+        if summary.lock.is_some() {
+            report.locked.push(id);
+            continue;
+        }
+        if to.get_state(&id).await?.is_some() {
+            report.existing.push(id);
+            continue;
+        }
+
+        if options.dry_run {
+            let versions = if options.history {
+                from.list_versions(&id).await?.len()
+            } else {
+                1
+            };
+            report.migrated.push(MigratedState { id, versions });
+            continue;
+        }
+
+        match from.lock_state(&id, migration_lock()).await {
+            Ok(()) => {}
+            Err(Error::StateLocked) => {
+                report.locked.push(id);
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        let copied = copy_state(from, to, &id, options.history).await;
+        from.unlock_state(&id, &migration_lock()).await?;
+
+        match copied {
+            Ok(versions) => report.migrated.push(MigratedState { id, versions }),
+            Err(Error::StateLocked) => report.locked.push(id),
+            Err(e) => report.failed.push(FailedState {
+                id,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Copy a single state whose source is locked by the migration, returning the number of versions written
+async fn copy_state(
+    from: &dyn TerraformStateProvider,
+    to: &dyn TerraformStateProvider,
+    id: &str,
+    history: bool,
+) -> Result<usize> {
+    // nothing else may write to the destination while it only holds part of the history
+    to.lock_state(id, migration_lock()).await?;
+
+    match write_versions(from, to, id, history).await {
+        Ok(versions) => {
+            to.unlock_state(id, &migration_lock()).await?;
+            Ok(versions)
+        }
+        Err(e) => {
+            if let Err(cleanup) = to.delete_state(id, MIGRATION_LOCK_ID).await {
+                warn!(%cleanup, "failed to remove partially copied state {}", id);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Write the versions of a state to the destination, which is locked by the migration
+async fn write_versions(
+    from: &dyn TerraformStateProvider,
+    to: &dyn TerraformStateProvider,
+    id: &str,
+    history: bool,
+) -> Result<usize> {
+    let mut versions = Vec::new();
+    if history {
+        for info in from.list_versions(id).await? {
+            if let Some(version) = from.get_version(id, info.version).await? {
+                versions.push(version.data);
+            }
+        }
+    }
+    let current = from.get_state(id).await?.ok_or(Error::NotFound)?.data;
+    if versions.last() != Some(&current) {
+        versions.push(current);
+    }
+
+    // versions were accepted by the source already, including rollbacks to older serials
+    for data in &versions {
+        to.update_state(id, MIGRATION_LOCK_ID, data.clone(), true)
+            .await?;
+    }
+
+    Ok(versions.len())
+}
+
+fn migration_lock() -> TerraformLock {
+    TerraformLock {
+        id: MIGRATION_LOCK_ID.to_string(),
+        operation: "migrate".to_string(),
+        info: "copying the state to another provider".to_string(),
+        who: "thoenix".to_string(),
+        version: String::new(),
+        created: None,
+        path: String::new(),
+        acquired: None,
+    }
+}
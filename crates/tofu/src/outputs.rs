@@ -0,0 +1,33 @@
+use crate::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// An output of the root module, as stored in a state document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateOutput {
+    pub value: serde_json::Value,
+    /// The terraform type of the value, e.g. `"string"` or `["list", "string"]`
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<serde_json::Value>,
+    #[serde(default)]
+    pub sensitive: bool,
+}
+
+#[derive(Deserialize)]
+struct StateOutputs {
+    #[serde(default)]
+    outputs: BTreeMap<String, StateOutput>,
+}
+
+/// Read the root module outputs of a state document, ordered by name.
+///
+/// A state that has not been written yet has no outputs.
+pub fn parse_outputs(data: &str) -> Result<BTreeMap<String, StateOutput>> {
+    if data.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let state: StateOutputs = serde_json::from_str(data).map_err(Error::InvalidData)?;
+
+    Ok(state.outputs)
+}
@@ -0,0 +1,129 @@
+use crate::{
+    diff::{sensitive_path, SENSITIVE_VALUE},
+    error::{Error, Result},
+};
+use serde::{Deserialize, Serialize};
+
+/// A single resource instance recorded in a state document
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StateResource {
+    /// The full address of the instance, e.g. `module.network.aws_subnet.private[0]`
+    pub address: String,
+    /// `managed` for resources and `data` for data sources
+    pub mode: String,
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub name: String,
+    /// The module containing the resource, absent for the root module
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    /// The provider configuration, e.g. `provider["registry.terraform.io/hashicorp/aws"]`
+    pub provider: String,
+    /// The `id` attribute of the instance, if it has one. A sensitive id is replaced with
+    /// [`SENSITIVE_VALUE`]
+    pub id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StateDocument {
+    #[serde(default)]
+    resources: Vec<ResourceDocument>,
+}
+
+#[derive(Deserialize)]
+struct ResourceDocument {
+    module: Option<String>,
+    mode: String,
+    #[serde(rename = "type")]
+    resource_type: String,
+    name: String,
+    provider: String,
+    #[serde(default)]
+    instances: Vec<InstanceDocument>,
+}
+
+#[derive(Deserialize)]
+struct InstanceDocument {
+    index_key: Option<serde_json::Value>,
+    #[serde(default)]
+    attributes: serde_json::Map<String, serde_json::Value>,
+    /// Paths to sensitive values inside of `attributes`
+    #[serde(default)]
+    sensitive_attributes: Vec<serde_json::Value>,
+}
+
+/// A resource instance together with its attributes
+pub(crate) struct ResourceInstance {
+    pub(crate) resource: StateResource,
+    pub(crate) attributes: serde_json::Map<String, serde_json::Value>,
+    pub(crate) sensitive_attributes: Vec<serde_json::Value>,
+}
+
+/// List every resource instance in a state document, in the order they are stored.
+///
+/// A state that has not been written yet has no resources.
+pub fn parse_resources(data: &str) -> Result<Vec<StateResource>> {
+    Ok(parse_instances(data)?
+        .into_iter()
+        .map(|instance| instance.resource)
+        .collect())
+}
+
+pub(crate) fn parse_instances(data: &str) -> Result<Vec<ResourceInstance>> {
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let state: StateDocument = serde_json::from_str(data).map_err(Error::InvalidData)?;
+
+    let mut instances = Vec::new();
+    for resource in state.resources {
+        let mut address = String::new();
+        if let Some(module) = &resource.module {
+            address.push_str(module);
+            address.push('.');
+        }
+        if resource.mode == "data" {
+            address.push_str("data.");
+        }
+        address.push_str(&format!("{}.{}", resource.resource_type, resource.name));
+
+        for instance in resource.instances {
+            let address = match &instance.index_key {
+                Some(serde_json::Value::String(key)) => format!("{address}[{key:?}]"),
+                Some(key) => format!("{address}[{key}]"),
+                None => address.clone(),
+            };
+            let id = match instance.attributes.get("id") {
+                Some(serde_json::Value::String(id)) => Some(id.clone()),
+                Some(serde_json::Value::Number(id)) => Some(id.to_string()),
+                _ => None,
+            };
+            let id_is_sensitive = instance
+                .sensitive_attributes
+                .iter()
+                .any(|path| sensitive_path(path).as_deref() == Some("id"));
+            let id = if id_is_sensitive {
+                id.map(|_| SENSITIVE_VALUE.to_string())
+            } else {
+                id
+            };
+
+            instances.push(ResourceInstance {
+                resource: StateResource {
+                    address,
+                    mode: resource.mode.clone(),
+                    resource_type: resource.resource_type.clone(),
+                    name: resource.name.clone(),
+                    module: resource.module.clone(),
+                    provider: resource.provider.clone(),
+                    id,
+                },
+                attributes: instance.attributes,
+                sensitive_attributes: instance.sensitive_attributes,
+            });
+        }
+    }
+
+    Ok(instances)
+}
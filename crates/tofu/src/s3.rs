@@ -0,0 +1,380 @@
+use crate::{
+    error::{Error, Result},
+    sync::StateLocks,
+    validation::validate_update,
+    LockedState, StateSummary, StateVersion, StateVersionInfo, TerraformLock, TerraformState,
+    TerraformStateProvider,
+};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use object_store::{path::Path, ObjectStore, PutMode, PutOptions, PutPayload, UpdateVersion};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+const STATE_FILE: &str = "state.json";
+const VERSIONS_DIR: &str = "versions";
+
+/// How to connect to an S3-compatible bucket.
+///
+/// Credentials are read from the usual `AWS_*` environment variables.
+#[derive(Debug, Default, Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    /// The url of an S3-compatible service such as minio, instead of AWS
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    /// A path inside of the bucket to store states under
+    pub prefix: Option<String>,
+}
+
+/// The object holding a state, replaced as a whole by every change to the state
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct StoredState {
+    #[serde(flatten)]
+    state: TerraformState,
+    /// The number of the latest version, 0 if the state has never been written
+    version: u64,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+impl StoredState {
+    /// Whether the state has been written or locked
+    fn exists(&self) -> bool {
+        self.version > 0 || self.state.is_locked()
+    }
+}
+
+/// A state provider that stores state in an S3-compatible object store.
+///
+/// Each state is kept as `<prefix>/<id>/state.json` together with its lock, and every version as
+/// `<prefix>/<id>/versions/<n>.json`. Changes to `state.json` are conditional writes against the
+/// object's etag, so two servers sharing a bucket can't both acquire a lock or overwrite each
+/// other's updates. The store must support conditional puts, e.g. minio, R2 or AWS S3.
+#[derive(Debug)]
+pub struct S3State {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+    lock_ttl: Option<Duration>,
+    locks: StateLocks,
+}
+
+impl S3State {
+    pub fn new(store: Arc<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            prefix: Path::default(),
+            lock_ttl: None,
+            locks: StateLocks::default(),
+        }
+    }
+
+    /// Connect to an S3-compatible bucket
+    pub fn connect(config: S3Config) -> Result<Self> {
+        let mut builder = object_store::aws::AmazonS3Builder::from_env()
+            .with_bucket_name(config.bucket)
+            .with_conditional_put(object_store::aws::S3ConditionalPut::ETagMatch);
+        if let Some(endpoint) = config.endpoint {
+            builder = builder
+                .with_allow_http(endpoint.starts_with("http://"))
+                .with_endpoint(endpoint);
+        }
+        if let Some(region) = config.region {
+            builder = builder.with_region(region);
+        }
+
+        let state = Self::new(Arc::new(builder.build()?));
+        match config.prefix {
+            Some(prefix) => state.with_prefix(&prefix),
+            None => Ok(state),
+        }
+    }
+
+    /// Store states under `prefix` instead of the root of the store
+    pub fn with_prefix(mut self, prefix: &str) -> Result<Self> {
+        self.prefix = Path::parse(prefix).map_err(object_store::Error::from)?;
+        Ok(self)
+    }
+
+    pub fn with_lock_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.lock_ttl = ttl;
+        self
+    }
+
+    fn state_dir(&self, id: &str) -> Result<Path> {
+        // ids are used as a single path segment, which must not need any escaping
+        let valid =
+            Path::parse(id).is_ok_and(|path| path.as_ref() == id && path.parts().count() == 1);
+        if !valid || id.contains('/') {
+            return Err(Error::InvalidId(id.to_string()));
+        }
+
+        Ok(self.prefix.child(id))
+    }
+
+    fn version_path(&self, id: &str, version: u64) -> Result<Path> {
+        Ok(self
+            .state_dir(id)?
+            .child(VERSIONS_DIR)
+            .child(format!("{version}.json")))
+    }
+
+    /// Read the object holding a state together with the version to make conditional writes against
+    async fn read(&self, id: &str) -> Result<Option<(StoredState, UpdateVersion)>> {
+        let path = self.state_dir(id)?.child(STATE_FILE);
+        let result = match self.store.get(&path).await {
+            Ok(result) => result,
+            Err(object_store::Error::NotFound { .. }) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let version = UpdateVersion {
+            e_tag: result.meta.e_tag.clone(),
+            version: result.meta.version.clone(),
+        };
+
+        let mut stored: StoredState = serde_json::from_slice(&result.bytes().await?)?;
+        stored.state.release_expired_lock(self.lock_ttl);
+
+        Ok(Some((stored, version)))
+    }
+
+    /// Read a state, returning `None` if it has never been written or locked
+    async fn read_state(&self, id: &str) -> Result<Option<StoredState>> {
+        Ok(self
+            .read(id)
+            .await?
+            .map(|(stored, _)| stored)
+            .filter(StoredState::exists))
+    }
+
+    /// Apply `f` to a state and write it back if nothing else changed it in the meantime,
+    /// retrying with the newer state otherwise.
+    ///
+    /// Unless `create` is set, the state must already exist.
+    async fn modify<T>(
+        &self,
+        id: &str,
+        create: bool,
+        mut f: impl FnMut(&mut StoredState) -> Result<T>,
+    ) -> Result<T> {
+        let path = self.state_dir(id)?.child(STATE_FILE);
+
+        loop {
+            let (mut stored, mode) = match self.read(id).await? {
+                Some((stored, version)) => (stored, PutMode::Update(version)),
+                None => (StoredState::default(), PutMode::Create),
+            };
+            if !create && !stored.exists() {
+                return Err(Error::NotFound);
+            }
+
+            let result = f(&mut stored)?;
+
+            let payload = PutPayload::from(serde_json::to_vec(&stored)?);
+            match self
+                .store
+                .put_opts(&path, payload, PutOptions::from(mode))
+                .await
+            {
+                Ok(_) => return Ok(result),
+                Err(object_store::Error::AlreadyExists { .. })
+                | Err(object_store::Error::Precondition { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn write_version(
+        &self,
+        id: &str,
+        lock_id: &str,
+        data: String,
+        restored_from: Option<u64>,
+        force: bool,
+    ) -> Result<()> {
+        let version = self
+            .modify(id, true, |stored| {
+                stored.state.check_lock(lock_id)?;
+                if !force {
+                    validate_update(&stored.state.data, &data)?;
+                }
+
+                let version = StateVersion::new(
+                    stored.version + 1,
+                    stored.state.lock_info(),
+                    data.clone(),
+                    restored_from,
+                );
+                stored.version = version.info.version;
+                stored.last_modified = Some(version.info.created);
+                stored.state.set_data(data.clone());
+
+                Ok(version)
+            })
+            .await?;
+
+        // only the writer that claimed this version number in `state.json` gets here
+        let path = self.version_path(id, version.info.version)?;
+        self.store
+            .put(&path, PutPayload::from(serde_json::to_vec(&version)?))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn read_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>> {
+        let path = self.version_path(id, version)?;
+        match self.store.get(&path).await {
+            Ok(result) => Ok(Some(serde_json::from_slice(&result.bytes().await?)?)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The ids of every state in the store, ordered by id
+    async fn state_ids(&self) -> Result<Vec<String>> {
+        let listing = self.store.list_with_delimiter(Some(&self.prefix)).await?;
+
+        let mut ids = listing
+            .common_prefixes
+            .iter()
+            .filter_map(|dir| dir.filename().map(str::to_string))
+            .collect::<Vec<_>>();
+        ids.sort();
+
+        Ok(ids)
+    }
+}
+
+#[async_trait::async_trait]
+impl TerraformStateProvider for S3State {
+    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>> {
+        let _guard = self.locks.lock(id).await;
+
+        Ok(self.read_state(id).await?.map(|stored| stored.state))
+    }
+
+    async fn update_state(&self, id: &str, lock_id: &str, data: String, force: bool) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        self.write_version(id, lock_id, data, None, force).await
+    }
+
+    async fn lock_state(&self, id: &str, lock: TerraformLock) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        self.modify(id, true, |stored| stored.state.lock(lock.clone()))
+            .await
+    }
+
+    async fn unlock_state(&self, id: &str, lock: &TerraformLock) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        self.modify(id, false, |stored| stored.state.unlock(lock))
+            .await
+    }
+
+    async fn force_unlock_state(&self, id: &str) -> Result<TerraformLock> {
+        let _guard = self.locks.lock(id).await;
+
+        self.modify(id, false, |stored| stored.state.force_unlock())
+            .await
+    }
+
+    async fn list_locks(&self) -> Result<Vec<LockedState>> {
+        let mut locks = Vec::new();
+        for id in self.state_ids().await? {
+            let _guard = self.locks.lock(&id).await;
+            if let Some(lock) = self
+                .read_state(&id)
+                .await?
+                .and_then(|stored| stored.state.lock_info().cloned())
+            {
+                locks.push(LockedState { id, lock });
+            }
+        }
+
+        Ok(locks)
+    }
+
+    async fn list_states(&self) -> Result<Vec<StateSummary>> {
+        let mut states = Vec::new();
+        for id in self.state_ids().await? {
+            let _guard = self.locks.lock(&id).await;
+            if let Some(stored) = self.read_state(&id).await? {
+                states.push(StateSummary::new(id, &stored.state, stored.last_modified));
+            }
+        }
+
+        Ok(states)
+    }
+
+    async fn delete_state(&self, id: &str, lock_id: &str) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let (stored, version) = self
+            .read(id)
+            .await?
+            .filter(|(stored, _)| stored.exists())
+            .ok_or(Error::NotFound)?;
+        stored.state.check_lock(lock_id)?;
+
+        // forget the state first so that nothing can lock or write it while it is removed, the
+        // write fails if the state was locked or written since it was read
+        let path = self.state_dir(id)?.child(STATE_FILE);
+        let payload = PutPayload::from(serde_json::to_vec(&StoredState::default())?);
+        let options = PutOptions::from(PutMode::Update(version));
+        match self.store.put_opts(&path, payload, options).await {
+            Ok(_) => {}
+            Err(object_store::Error::Precondition { .. }) => return Err(Error::StateLocked),
+            Err(e) => return Err(e.into()),
+        }
+
+        let dir = self.state_dir(id)?;
+        let objects = self
+            .store
+            .list(Some(&dir))
+            .map_ok(|meta| meta.location)
+            .try_collect::<Vec<_>>()
+            .await?;
+        for path in objects {
+            self.store.delete(&path).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersionInfo>> {
+        let _guard = self.locks.lock(id).await;
+
+        let stored = self.read_state(id).await?.ok_or(Error::NotFound)?;
+
+        let mut versions = Vec::new();
+        for version in 1..=stored.version {
+            // a version is missing if the server stopped between updating the state and writing it
+            if let Some(version) = self.read_version(id, version).await? {
+                versions.push(version.info);
+            }
+        }
+
+        Ok(versions)
+    }
+
+    async fn get_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>> {
+        let _guard = self.locks.lock(id).await;
+
+        self.read_version(id, version).await
+    }
+
+    async fn restore_version(&self, id: &str, lock_id: &str, version: u64) -> Result<()> {
+        let _guard = self.locks.lock(id).await;
+
+        let data = self
+            .read_version(id, version)
+            .await?
+            .ok_or(Error::VersionNotFound(version))?
+            .data;
+
+        self.write_version(id, lock_id, data, Some(version), true)
+            .await
+    }
+}
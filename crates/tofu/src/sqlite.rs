@@ -0,0 +1,420 @@
+use crate::{
+    error::{Error, Result},
+    validation::validate_update,
+    LockedState, StateSummary, StateVersion, StateVersionInfo, TerraformLock, TerraformState,
+    TerraformStateProvider,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::{named_params, OptionalExtension, Transaction, TransactionBehavior};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The schema migrations, applied in order. The number of applied migrations is tracked in
+/// sqlite's `user_version`, so new migrations must only ever be appended.
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE states (
+        id TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL DEFAULT '',
+        lock TEXT,
+        version INTEGER NOT NULL DEFAULT 0,
+        last_modified TEXT,
+        md5 TEXT
+    );
+
+    CREATE TABLE versions (
+        state_id TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        created TEXT NOT NULL,
+        lock_id TEXT,
+        who TEXT,
+        operation TEXT,
+        restored_from INTEGER,
+        md5 TEXT,
+        data TEXT NOT NULL,
+        PRIMARY KEY (state_id, version)
+    );
+"#];
+
+/// A state together with the number of its latest version
+#[derive(Debug, Default)]
+struct StoredState {
+    state: TerraformState,
+    version: u64,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+/// A state provider that stores state in a sqlite database.
+///
+/// Every operation runs in its own immediate transaction, so checking a lock and acting on it
+/// can't be interleaved with another writer, even one in a different process. Transactions run on
+/// tokio's blocking threads, as they may wait for another process to release the database.
+#[derive(Debug)]
+pub struct SqliteState {
+    connection: Arc<Mutex<rusqlite::Connection>>,
+    lock_ttl: Option<Duration>,
+}
+
+impl SqliteState {
+    /// Open the database at `path`, creating it and applying any pending migrations
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.busy_timeout(Duration::from_secs(5))?;
+
+        Self::with_connection(connection)
+    }
+
+    /// Open a database that only lives as long as the provider
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(rusqlite::Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut connection: rusqlite::Connection) -> Result<Self> {
+        migrate(&mut connection)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+            lock_ttl: None,
+        })
+    }
+
+    pub fn with_lock_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.lock_ttl = ttl;
+        self
+    }
+
+    /// Run `f` inside of a transaction, committing it if `f` succeeds
+    async fn transaction<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Transaction) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let connection = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().expect("sqlite connection poisoned");
+            let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let result = f(&tx)?;
+            tx.commit()?;
+
+            Ok(result)
+        })
+        .await
+        .map_err(|e| Error::Io(e.into()))?
+    }
+}
+
+/// Read a state, releasing its lock if it has expired
+fn read(tx: &Transaction, id: &str, lock_ttl: Option<Duration>) -> Result<Option<StoredState>> {
+    let row = tx
+        .query_row(
+            "SELECT data, lock, version, last_modified, md5 FROM states WHERE id = ?1",
+            [id],
+            |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, u64>(2)?,
+                    row.get::<_, Option<DateTime<Utc>>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            },
+        )
+        .optional()?;
+    let Some((data, lock, version, last_modified, md5)) = row else {
+        return Ok(None);
+    };
+
+    let mut stored = StoredState {
+        state: TerraformState {
+            lock: lock.as_deref().map(serde_json::from_str).transpose()?,
+            data,
+            md5,
+        },
+        version,
+        last_modified,
+    };
+    if stored.state.release_expired_lock(lock_ttl).is_some() {
+        write(tx, id, &stored)?;
+
+        // a state that was only locked is gone once its lock expires
+        if stored.version == 0 {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(stored))
+}
+
+fn write_version(
+    tx: &Transaction,
+    id: &str,
+    lock_ttl: Option<Duration>,
+    lock_id: &str,
+    data: String,
+    restored_from: Option<u64>,
+    force: bool,
+) -> Result<()> {
+    let mut stored = read(tx, id, lock_ttl)?.unwrap_or_default();
+    stored.state.check_lock(lock_id)?;
+    if !force {
+        validate_update(&stored.state.data, &data)?;
+    }
+
+    let version = StateVersion::new(
+        stored.version + 1,
+        stored.state.lock_info(),
+        data,
+        restored_from,
+    );
+    tx.execute(
+        "INSERT INTO versions
+                (state_id, version, created, lock_id, who, operation, restored_from, md5, data)
+             VALUES
+                (:state_id, :version, :created, :lock_id, :who, :operation, :restored_from, :md5,
+                 :data)",
+        named_params! {
+            ":state_id": id,
+            ":version": version.info.version,
+            ":created": version.info.created,
+            ":lock_id": version.info.lock_id,
+            ":who": version.info.who,
+            ":operation": version.info.operation,
+            ":restored_from": version.info.restored_from,
+            ":md5": version.info.md5,
+            ":data": version.data,
+        },
+    )?;
+
+    stored.version = version.info.version;
+    stored.last_modified = Some(version.info.created);
+    stored.state.set_data(version.data);
+    write(tx, id, &stored)
+}
+
+/// Read every state, ordered by id
+fn read_all(tx: &Transaction, lock_ttl: Option<Duration>) -> Result<Vec<(String, StoredState)>> {
+    let ids = tx
+        .prepare("SELECT id FROM states ORDER BY id")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut states = Vec::with_capacity(ids.len());
+    for id in ids {
+        if let Some(stored) = read(tx, &id, lock_ttl)? {
+            states.push((id, stored));
+        }
+    }
+
+    Ok(states)
+}
+
+#[async_trait::async_trait]
+impl TerraformStateProvider for SqliteState {
+    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>> {
+        let (id, lock_ttl) = (id.to_string(), self.lock_ttl);
+        self.transaction(move |tx| Ok(read(tx, &id, lock_ttl)?.map(|stored| stored.state)))
+            .await
+    }
+
+    async fn update_state(&self, id: &str, lock_id: &str, data: String, force: bool) -> Result<()> {
+        let (id, lock_id, lock_ttl) = (id.to_string(), lock_id.to_string(), self.lock_ttl);
+        self.transaction(move |tx| write_version(tx, &id, lock_ttl, &lock_id, data, None, force))
+            .await
+    }
+
+    async fn lock_state(&self, id: &str, lock: TerraformLock) -> Result<()> {
+        let (id, lock_ttl) = (id.to_string(), self.lock_ttl);
+        self.transaction(move |tx| {
+            let mut stored = read(tx, &id, lock_ttl)?.unwrap_or_default();
+            stored.state.lock(lock)?;
+
+            write(tx, &id, &stored)
+        })
+        .await
+    }
+
+    async fn unlock_state(&self, id: &str, lock: &TerraformLock) -> Result<()> {
+        let (id, lock, lock_ttl) = (id.to_string(), lock.clone(), self.lock_ttl);
+        self.transaction(move |tx| {
+            let mut stored = read(tx, &id, lock_ttl)?.ok_or(Error::NotFound)?;
+            stored.state.unlock(&lock)?;
+
+            write(tx, &id, &stored)
+        })
+        .await
+    }
+
+    async fn force_unlock_state(&self, id: &str) -> Result<TerraformLock> {
+        let (id, lock_ttl) = (id.to_string(), self.lock_ttl);
+        self.transaction(move |tx| {
+            let mut stored = read(tx, &id, lock_ttl)?.ok_or(Error::NotFound)?;
+            let lock = stored.state.force_unlock()?;
+            write(tx, &id, &stored)?;
+
+            Ok(lock)
+        })
+        .await
+    }
+
+    async fn list_locks(&self) -> Result<Vec<LockedState>> {
+        let lock_ttl = self.lock_ttl;
+        self.transaction(move |tx| {
+            Ok(read_all(tx, lock_ttl)?
+                .into_iter()
+                .filter_map(|(id, stored)| {
+                    let lock = stored.state.lock?;
+                    Some(LockedState { id, lock })
+                })
+                .collect())
+        })
+        .await
+    }
+
+    async fn list_states(&self) -> Result<Vec<StateSummary>> {
+        let lock_ttl = self.lock_ttl;
+        self.transaction(move |tx| {
+            Ok(read_all(tx, lock_ttl)?
+                .into_iter()
+                .map(|(id, stored)| StateSummary::new(id, &stored.state, stored.last_modified))
+                .collect())
+        })
+        .await
+    }
+
+    async fn delete_state(&self, id: &str, lock_id: &str) -> Result<()> {
+        let (id, lock_id, lock_ttl) = (id.to_string(), lock_id.to_string(), self.lock_ttl);
+        self.transaction(move |tx| {
+            let stored = read(tx, &id, lock_ttl)?.ok_or(Error::NotFound)?;
+            stored.state.check_lock(&lock_id)?;
+
+            tx.execute("DELETE FROM versions WHERE state_id = ?1", [&id])?;
+            tx.execute("DELETE FROM states WHERE id = ?1", [&id])?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersionInfo>> {
+        let (id, lock_ttl) = (id.to_string(), self.lock_ttl);
+        self.transaction(move |tx| {
+            read(tx, &id, lock_ttl)?.ok_or(Error::NotFound)?;
+
+            let versions = tx
+                .prepare(
+                    "SELECT version, created, lock_id, who, operation, restored_from, md5
+                     FROM versions WHERE state_id = ?1 ORDER BY version",
+                )?
+                .query_map([&id], read_version_info)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            Ok(versions)
+        })
+        .await
+    }
+
+    async fn get_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>> {
+        let id = id.to_string();
+        self.transaction(move |tx| read_version(tx, &id, version))
+            .await
+    }
+
+    async fn restore_version(&self, id: &str, lock_id: &str, version: u64) -> Result<()> {
+        let (id, lock_id, lock_ttl) = (id.to_string(), lock_id.to_string(), self.lock_ttl);
+        self.transaction(move |tx| {
+            let data = read_version(tx, &id, version)?
+                .ok_or(Error::VersionNotFound(version))?
+                .data;
+
+            write_version(tx, &id, lock_ttl, &lock_id, data, Some(version), true)
+        })
+        .await
+    }
+}
+
+/// Bring the schema up to date with [`MIGRATIONS`]
+fn migrate(connection: &mut rusqlite::Connection) -> Result<()> {
+    let tx = connection.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+
+    let applied: usize = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if applied > MIGRATIONS.len() {
+        return Err(Error::UnsupportedSchema(applied));
+    }
+
+    for (number, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", number + 1)?;
+    }
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Store a state, removing it if it has neither been written nor locked
+fn write(tx: &Transaction, id: &str, stored: &StoredState) -> Result<()> {
+    if stored.version == 0 && !stored.state.is_locked() {
+        tx.execute("DELETE FROM states WHERE id = ?1", [id])?;
+        return Ok(());
+    }
+
+    let lock = stored
+        .state
+        .lock_info()
+        .map(serde_json::to_string)
+        .transpose()?;
+    tx.execute(
+        "INSERT INTO states (id, data, lock, version, last_modified, md5)
+         VALUES (:id, :data, :lock, :version, :last_modified, :md5)
+         ON CONFLICT (id) DO UPDATE SET
+            data = excluded.data,
+            lock = excluded.lock,
+            version = excluded.version,
+            last_modified = excluded.last_modified,
+            md5 = excluded.md5",
+        named_params! {
+            ":id": id,
+            ":data": stored.state.data,
+            ":md5": stored.state.md5,
+            ":lock": lock,
+            ":version": stored.version,
+            ":last_modified": stored.last_modified,
+        },
+    )?;
+
+    Ok(())
+}
+
+fn read_version(tx: &Transaction, id: &str, version: u64) -> Result<Option<StateVersion>> {
+    let version = tx
+        .query_row(
+            "SELECT version, created, lock_id, who, operation, restored_from, md5, data
+             FROM versions WHERE state_id = ?1 AND version = ?2",
+            rusqlite::params![id, version],
+            |row| {
+                Ok(StateVersion {
+                    info: read_version_info(row)?,
+                    data: row.get(7)?,
+                })
+            },
+        )
+        .optional()?;
+
+    Ok(version)
+}
+
+fn read_version_info(row: &rusqlite::Row) -> rusqlite::Result<StateVersionInfo> {
+    Ok(StateVersionInfo {
+        version: row.get(0)?,
+        created: row.get(1)?,
+        lock_id: row.get(2)?,
+        who: row.get(3)?,
+        operation: row.get(4)?,
+        restored_from: row.get(5)?,
+        md5: row.get(6)?,
+    })
+}
@@ -0,0 +1,22 @@
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Hands out one mutex per state so that operations on different states never wait on each other
+#[derive(Debug, Default)]
+pub(crate) struct StateLocks {
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl StateLocks {
+    /// Wait for exclusive access to a state, which is held until the guard is dropped
+    pub(crate) async fn lock(&self, id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.locks.lock().expect("state lock map poisoned");
+            // forget the mutexes of states that nobody is holding or waiting on
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(id.to_string()).or_default().clone()
+        };
+
+        lock.lock_owned().await
+    }
+}
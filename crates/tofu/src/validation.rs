@@ -0,0 +1,48 @@
+use crate::error::{Error, Result};
+use serde::Deserialize;
+
+/// The fields of a terraform state document used to detect conflicting writes
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+pub struct StateMetadata {
+    pub serial: u64,
+    pub lineage: String,
+}
+
+impl StateMetadata {
+    pub fn parse(data: &str) -> Result<Self> {
+        serde_json::from_str(data).map_err(Error::InvalidData)
+    }
+}
+
+/// Ensure that `new` may replace `current` as the contents of a state.
+///
+/// The new state must belong to the same lineage as the current state and carry a higher serial.
+/// Writing the exact same data again is accepted so that retried requests do not fail. When
+/// nothing has been written yet, or the current data is not a state document, any valid state is
+/// accepted.
+pub fn validate_update(current: &str, new: &str) -> Result<()> {
+    let new_metadata = StateMetadata::parse(new)?;
+
+    if current.is_empty() || current == new {
+        return Ok(());
+    }
+    let Ok(current) = StateMetadata::parse(current) else {
+        return Ok(());
+    };
+
+    if current.lineage != new_metadata.lineage {
+        return Err(Error::LineageMismatch {
+            expected: current.lineage,
+            found: new_metadata.lineage,
+        });
+    }
+
+    if new_metadata.serial <= current.serial {
+        return Err(Error::StaleSerial {
+            current: current.serial,
+            found: new_metadata.serial,
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! Runs the conformance suite against every provider in the crate.
+use std::{sync::Arc, time::Duration};
+use tempfile::TempDir;
+use thoenix_tofu::{EncryptedState, Keyring};
+
+mod memory {
+    thoenix_tofu::conformance_tests!(|ttl| (
+        (),
+        thoenix_tofu::InMemoryState::new().with_lock_ttl(ttl)
+    ));
+}
+
+mod file {
+    use super::*;
+
+    fn setup(ttl: Option<Duration>) -> (TempDir, thoenix_tofu::FileState) {
+        let dir = tempfile::tempdir().unwrap();
+        let provider = thoenix_tofu::FileState::new(dir.path().join("tf-state")).with_lock_ttl(ttl);
+
+        (dir, provider)
+    }
+
+    thoenix_tofu::conformance_tests!(setup);
+}
+
+mod git {
+    use super::*;
+
+    fn setup(ttl: Option<Duration>) -> (TempDir, thoenix_tofu::GitState) {
+        let dir = tempfile::tempdir().unwrap();
+        git2::Repository::init_bare(dir.path()).unwrap();
+        let provider = thoenix_tofu::GitState::new(dir.path()).with_lock_ttl(ttl);
+
+        (dir, provider)
+    }
+
+    thoenix_tofu::conformance_tests!(setup);
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    thoenix_tofu::conformance_tests!(|ttl| {
+        let provider = thoenix_tofu::SqliteState::open_in_memory().unwrap();
+
+        ((), provider.with_lock_ttl(ttl))
+    });
+}
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use super::*;
+
+    fn setup(ttl: Option<Duration>) -> ((), thoenix_tofu::S3State) {
+        let store = Arc::new(object_store::memory::InMemory::new());
+
+        ((), thoenix_tofu::S3State::new(store).with_lock_ttl(ttl))
+    }
+
+    thoenix_tofu::conformance_tests!(setup);
+}
+
+mod encrypted {
+    use super::*;
+
+    fn setup(ttl: Option<Duration>) -> ((), EncryptedState) {
+        let keys = Keyring::parse("test AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=").unwrap();
+        let inner = Arc::new(thoenix_tofu::InMemoryState::new().with_lock_ttl(ttl));
+
+        ((), EncryptedState::new(inner, keys))
+    }
+
+    thoenix_tofu::conformance_tests!(setup);
+}
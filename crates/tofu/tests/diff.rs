@@ -0,0 +1,132 @@
+use serde_json::{json, Value};
+use thoenix_tofu::diff::{diff_states, ValueChange, SENSITIVE_VALUE};
+
+fn state(resources: Value, outputs: Value) -> String {
+    json!({
+        "version": 4,
+        "serial": 1,
+        "lineage": "test",
+        "outputs": outputs,
+        "resources": resources,
+    })
+    .to_string()
+}
+
+fn instance(resource_type: &str, name: &str, attributes: Value, sensitive: Value) -> Value {
+    json!({
+        "mode": "managed",
+        "type": resource_type,
+        "name": name,
+        "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+        "instances": [{ "attributes": attributes, "sensitive_attributes": sensitive }],
+    })
+}
+
+fn change(path: &str, before: Option<Value>, after: Option<Value>) -> ValueChange {
+    ValueChange {
+        path: path.to_string(),
+        before,
+        after,
+    }
+}
+
+#[test]
+fn identical_states_have_no_changes() {
+    let data = state(
+        json!([instance(
+            "aws_vpc",
+            "main",
+            json!({"id": "vpc-1"}),
+            json!([])
+        )]),
+        json!({}),
+    );
+
+    assert!(diff_states(&data, &data).unwrap().is_empty());
+    assert!(diff_states("", "").unwrap().is_empty());
+}
+
+#[test]
+fn reports_added_removed_and_changed_resources() {
+    let before = state(
+        json!([
+            instance(
+                "aws_vpc",
+                "main",
+                json!({"id": "vpc-1", "tags": {"Name": "a"}, "cidr": "10.0.0.0/16"}),
+                json!([])
+            ),
+            instance("aws_subnet", "old", json!({"id": "subnet-1"}), json!([])),
+        ]),
+        json!({}),
+    );
+    let after = state(
+        json!([
+            instance(
+                "aws_vpc",
+                "main",
+                json!({"id": "vpc-1", "tags": {"Name": "b", "Env": "prod"}}),
+                json!([])
+            ),
+            instance("aws_subnet", "new", json!({"id": "subnet-2"}), json!([])),
+        ]),
+        json!({}),
+    );
+
+    let diff = diff_states(&before, &after).unwrap();
+
+    assert_eq!(diff.added.len(), 1);
+    assert_eq!(diff.added[0].address, "aws_subnet.new");
+    assert_eq!(diff.removed.len(), 1);
+    assert_eq!(diff.removed[0].id.as_deref(), Some("subnet-1"));
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].address, "aws_vpc.main");
+    assert_eq!(
+        diff.changed[0].attributes,
+        [
+            change("cidr", Some(json!("10.0.0.0/16")), None),
+            change("tags.Env", None, Some(json!("prod"))),
+            change("tags.Name", Some(json!("a")), Some(json!("b"))),
+        ]
+    );
+}
+
+#[test]
+fn masks_sensitive_values() {
+    let sensitive = json!([
+        [{"type": "get_attr", "value": "password"}],
+        [{"type": "get_attr", "value": "keys"}, {"type": "index", "value": {"type": "number", "value": 1}}],
+    ]);
+    let before = state(
+        json!([instance(
+            "aws_db_instance",
+            "db",
+            json!({"password": "old", "keys": ["a", "b"], "port": 5432}),
+            sensitive.clone()
+        )]),
+        json!({"secret": {"value": "old", "type": "string", "sensitive": true}}),
+    );
+    let after = state(
+        json!([instance(
+            "aws_db_instance",
+            "db",
+            json!({"password": "new", "keys": ["c", "d"], "port": 5432}),
+            sensitive
+        )]),
+        json!({"secret": {"value": "new", "type": "string", "sensitive": true}}),
+    );
+
+    let diff = diff_states(&before, &after).unwrap();
+    let masked = || Some(json!(SENSITIVE_VALUE));
+
+    assert_eq!(
+        diff.changed[0].attributes,
+        [
+            change("keys[0]", Some(json!("a")), Some(json!("c"))),
+            change("keys[1]", masked(), masked()),
+            change("password", masked(), masked()),
+        ]
+    );
+    assert_eq!(diff.outputs, [change("secret", masked(), masked())]);
+    assert!(!serde_json::to_string(&diff).unwrap().contains("new"));
+}
@@ -0,0 +1,57 @@
+use std::sync::Arc;
+use thoenix_tofu::{
+    conformance::state, error::Error, EncryptedState, InMemoryState, Keyring,
+    TerraformStateProvider,
+};
+
+const OLD_KEY: &str = "old AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+const NEW_KEY: &str = "new AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+fn encrypted(inner: &Arc<InMemoryState>, keys: &[&str]) -> EncryptedState {
+    EncryptedState::new(inner.clone(), Keyring::parse(&keys.join("\n")).unwrap())
+}
+
+#[tokio::test]
+async fn rotation_reports_keys_used_by_history() {
+    let inner = Arc::new(InMemoryState::new());
+    inner
+        .update_state("network", "", state("n", 1), false)
+        .await
+        .unwrap();
+    encrypted(&inner, &[OLD_KEY])
+        .update_state("network", "", state("n", 2), false)
+        .await
+        .unwrap();
+
+    let rotation = encrypted(&inner, &[NEW_KEY, OLD_KEY])
+        .rotate_keys()
+        .await
+        .unwrap();
+
+    assert_eq!(rotation.rotated, ["network"]);
+    // the current state and the version recording the rotation
+    assert_eq!(rotation.usage.keys["new"], 2);
+    assert_eq!(rotation.usage.keys["old"], 1);
+    assert_eq!(rotation.usage.plaintext, 1);
+}
+
+#[tokio::test]
+async fn refuses_to_drop_keys_used_by_history() {
+    let inner = Arc::new(InMemoryState::new());
+    encrypted(&inner, &[OLD_KEY])
+        .update_state("network", "", state("n", 1), false)
+        .await
+        .unwrap();
+    encrypted(&inner, &[NEW_KEY, OLD_KEY])
+        .rotate_keys()
+        .await
+        .unwrap();
+
+    let result = encrypted(&inner, &[NEW_KEY]).check_keys().await;
+
+    assert!(matches!(result, Err(Error::UnknownKey(key)) if key == "old"));
+    assert!(encrypted(&inner, &[NEW_KEY, OLD_KEY])
+        .check_keys()
+        .await
+        .is_ok());
+}
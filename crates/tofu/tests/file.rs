@@ -0,0 +1,18 @@
+use thoenix_tofu::{conformance::state, content_md5, FileState, TerraformStateProvider};
+
+#[tokio::test]
+async fn stores_the_digest_with_the_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let provider = FileState::new(dir.path());
+    provider
+        .update_state("network", "", state("n", 1), false)
+        .await
+        .unwrap();
+
+    let current: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(dir.path().join("network/current.json")).unwrap())
+            .unwrap();
+
+    assert_eq!(current["data"], state("n", 1));
+    assert_eq!(current["md5"], content_md5(state("n", 1).as_bytes()));
+}
@@ -0,0 +1,209 @@
+use thoenix_tofu::{
+    conformance::{lock, state},
+    error::{Error, Result},
+    migration::{migrate, MigratedState, MigrationOptions},
+    InMemoryState, LockedState, StateSummary, StateVersion, StateVersionInfo, TerraformLock,
+    TerraformState, TerraformStateProvider,
+};
+
+async fn source() -> InMemoryState {
+    let from = InMemoryState::new();
+    from.update_state("network", "", state("n", 1), false)
+        .await
+        .unwrap();
+    from.update_state("network", "", state("n", 2), false)
+        .await
+        .unwrap();
+    from.update_state("dns", "", state("d", 1), false)
+        .await
+        .unwrap();
+
+    from
+}
+
+#[tokio::test]
+async fn copies_current_data() {
+    let (from, to) = (source().await, InMemoryState::new());
+
+    let report = migrate(&from, &to, MigrationOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        report.migrated,
+        [
+            MigratedState {
+                id: "dns".to_string(),
+                versions: 1
+            },
+            MigratedState {
+                id: "network".to_string(),
+                versions: 1
+            },
+        ]
+    );
+    let copied = to.get_state("network").await.unwrap().unwrap();
+    assert_eq!(copied.data, state("n", 2));
+    assert!(!copied.is_locked());
+    assert_eq!(to.list_versions("network").await.unwrap().len(), 1);
+    assert!(!from
+        .get_state("network")
+        .await
+        .unwrap()
+        .unwrap()
+        .is_locked());
+}
+
+#[tokio::test]
+async fn copies_history() {
+    let (from, to) = (source().await, InMemoryState::new());
+    from.restore_version("network", "", 1).await.unwrap();
+
+    let options = MigrationOptions {
+        history: true,
+        ..Default::default()
+    };
+    migrate(&from, &to, options).await.unwrap();
+
+    for version in 1..=3 {
+        assert_eq!(
+            to.get_version("network", version)
+                .await
+                .unwrap()
+                .unwrap()
+                .data,
+            from.get_version("network", version)
+                .await
+                .unwrap()
+                .unwrap()
+                .data
+        );
+    }
+    assert!(to.get_version("network", 4).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn skips_locked_and_existing_states() {
+    let (from, to) = (source().await, InMemoryState::new());
+    from.lock_state("network", lock("a")).await.unwrap();
+    from.lock_state("empty", lock("a")).await.unwrap();
+    to.update_state("dns", "", state("other", 5), false)
+        .await
+        .unwrap();
+
+    let report = migrate(&from, &to, MigrationOptions::default())
+        .await
+        .unwrap();
+
+    assert!(report.migrated.is_empty());
+    assert_eq!(report.locked, ["empty", "network"]);
+    assert_eq!(report.existing, ["dns"]);
+    assert!(to.get_state("network").await.unwrap().is_none());
+    assert_eq!(
+        to.get_state("dns").await.unwrap().unwrap().data,
+        state("other", 5)
+    );
+}
+
+#[tokio::test]
+async fn dry_run_writes_nothing() {
+    let (from, to) = (source().await, InMemoryState::new());
+
+    let options = MigrationOptions {
+        history: true,
+        dry_run: true,
+    };
+    let report = migrate(&from, &to, options).await.unwrap();
+
+    assert_eq!(report.migrated.len(), 2);
+    assert_eq!(report.migrated[1].versions, 2);
+    assert!(to.list_states().await.unwrap().is_empty());
+}
+
+/// A destination that fails to store the given data
+struct FailingState {
+    inner: InMemoryState,
+    fail_on: String,
+}
+
+#[async_trait::async_trait]
+impl TerraformStateProvider for FailingState {
+    async fn get_state(&self, id: &str) -> Result<Option<TerraformState>> {
+        self.inner.get_state(id).await
+    }
+
+    async fn update_state(&self, id: &str, lock_id: &str, data: String, force: bool) -> Result<()> {
+        if data == self.fail_on {
+            return Err(Error::Io(std::io::Error::other("disk full")));
+        }
+
+        self.inner.update_state(id, lock_id, data, force).await
+    }
+
+    async fn lock_state(&self, id: &str, lock: TerraformLock) -> Result<()> {
+        self.inner.lock_state(id, lock).await
+    }
+
+    async fn unlock_state(&self, id: &str, lock: &TerraformLock) -> Result<()> {
+        self.inner.unlock_state(id, lock).await
+    }
+
+    async fn force_unlock_state(&self, id: &str) -> Result<TerraformLock> {
+        self.inner.force_unlock_state(id).await
+    }
+
+    async fn list_locks(&self) -> Result<Vec<LockedState>> {
+        self.inner.list_locks().await
+    }
+
+    async fn list_states(&self) -> Result<Vec<StateSummary>> {
+        self.inner.list_states().await
+    }
+
+    async fn delete_state(&self, id: &str, lock_id: &str) -> Result<()> {
+        self.inner.delete_state(id, lock_id).await
+    }
+
+    async fn list_versions(&self, id: &str) -> Result<Vec<StateVersionInfo>> {
+        self.inner.list_versions(id).await
+    }
+
+    async fn get_version(&self, id: &str, version: u64) -> Result<Option<StateVersion>> {
+        self.inner.get_version(id, version).await
+    }
+
+    async fn restore_version(&self, id: &str, lock_id: &str, version: u64) -> Result<()> {
+        self.inner.restore_version(id, lock_id, version).await
+    }
+}
+
+#[tokio::test]
+async fn removes_partially_copied_states() {
+    let from = source().await;
+    let to = FailingState {
+        inner: InMemoryState::new(),
+        fail_on: state("n", 2),
+    };
+
+    let options = MigrationOptions {
+        history: true,
+        ..Default::default()
+    };
+    let report = migrate(&from, &to, options).await.unwrap();
+
+    assert_eq!(report.migrated.len(), 1);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].id, "network");
+    assert!(to.get_state("network").await.unwrap().is_none());
+    assert!(!from
+        .get_state("network")
+        .await
+        .unwrap()
+        .unwrap()
+        .is_locked());
+
+    // a later run copies the state instead of skipping it as existing
+    let report = migrate(&from, &to.inner, options).await.unwrap();
+    assert_eq!(report.migrated[0].id, "network");
+    assert_eq!(report.existing, ["dns"]);
+}
@@ -0,0 +1,51 @@
+use serde_json::json;
+use thoenix_tofu::outputs::parse_outputs;
+
+#[test]
+fn parses_root_module_outputs() {
+    let data = json!({
+        "version": 4,
+        "serial": 1,
+        "lineage": "test",
+        "outputs": {
+            "vpc_id": { "value": "vpc-1", "type": "string" },
+            "subnets": { "value": ["a", "b"], "type": ["list", "string"] },
+            "db_password": { "value": "hunter2", "type": "string", "sensitive": true },
+        },
+        "resources": [],
+    })
+    .to_string();
+
+    let outputs = parse_outputs(&data).unwrap();
+
+    assert_eq!(
+        outputs.keys().collect::<Vec<_>>(),
+        ["db_password", "subnets", "vpc_id"]
+    );
+    assert_eq!(outputs["vpc_id"].value, "vpc-1");
+    assert!(!outputs["vpc_id"].sensitive);
+    assert_eq!(outputs["subnets"].value, json!(["a", "b"]));
+    assert_eq!(
+        outputs["subnets"].value_type,
+        Some(json!(["list", "string"]))
+    );
+    assert!(outputs["db_password"].sensitive);
+
+    let public = outputs
+        .into_iter()
+        .filter(|(_, output)| !output.sensitive)
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    assert_eq!(public, ["subnets", "vpc_id"]);
+}
+
+#[test]
+fn states_without_outputs_have_none() {
+    assert!(parse_outputs("").unwrap().is_empty());
+    assert!(
+        parse_outputs(r#"{"version":4,"serial":1,"lineage":"test"}"#)
+            .unwrap()
+            .is_empty()
+    );
+    assert!(parse_outputs("{").is_err());
+}
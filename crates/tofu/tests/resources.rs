@@ -0,0 +1,91 @@
+use serde_json::{json, Value};
+use thoenix_tofu::{diff::SENSITIVE_VALUE, resources::parse_resources};
+
+fn state(resources: Value) -> String {
+    json!({
+        "version": 4,
+        "serial": 1,
+        "lineage": "test",
+        "resources": resources,
+    })
+    .to_string()
+}
+
+#[test]
+fn lists_every_instance_with_its_address() {
+    let data = state(json!([
+        {
+            "mode": "managed",
+            "type": "aws_vpc",
+            "name": "main",
+            "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+            "instances": [{ "attributes": { "id": "vpc-1" } }],
+        },
+        {
+            "module": "module.network",
+            "mode": "managed",
+            "type": "aws_subnet",
+            "name": "private",
+            "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+            "instances": [
+                { "index_key": 0, "attributes": { "id": "subnet-1" } },
+                { "index_key": 1, "attributes": { "id": "subnet-2" } },
+            ],
+        },
+        {
+            "module": "module.dns[\"prod\"]",
+            "mode": "data",
+            "type": "aws_route53_zone",
+            "name": "zone",
+            "provider": "provider[\"registry.terraform.io/hashicorp/aws\"]",
+            "instances": [{ "index_key": "example.com", "attributes": { "id": 42 } }],
+        },
+    ]));
+
+    let resources = parse_resources(&data).unwrap();
+
+    let addresses = resources
+        .iter()
+        .map(|r| r.address.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        addresses,
+        [
+            "aws_vpc.main",
+            "module.network.aws_subnet.private[0]",
+            "module.network.aws_subnet.private[1]",
+            "module.dns[\"prod\"].data.aws_route53_zone.zone[\"example.com\"]",
+        ]
+    );
+    assert_eq!(resources[0].module, None);
+    assert_eq!(resources[1].module.as_deref(), Some("module.network"));
+    assert_eq!(resources[2].id.as_deref(), Some("subnet-2"));
+    assert_eq!(resources[3].mode, "data");
+    assert_eq!(resources[3].resource_type, "aws_route53_zone");
+    assert_eq!(resources[3].id.as_deref(), Some("42"));
+}
+
+#[test]
+fn masks_sensitive_ids() {
+    let data = state(json!([{
+        "mode": "managed",
+        "type": "random_password",
+        "name": "db",
+        "provider": "provider[\"registry.terraform.io/hashicorp/random\"]",
+        "instances": [{
+            "attributes": { "id": "hunter2", "result": "hunter2" },
+            "sensitive_attributes": [[{ "type": "get_attr", "value": "id" }]],
+        }],
+    }]));
+
+    let resources = parse_resources(&data).unwrap();
+
+    assert_eq!(resources[0].id.as_deref(), Some(SENSITIVE_VALUE));
+}
+
+#[test]
+fn unwritten_states_have_no_resources() {
+    assert!(parse_resources("").unwrap().is_empty());
+    assert!(parse_resources(&state(json!([]))).unwrap().is_empty());
+    assert!(parse_resources("{").is_err());
+}
@@ -0,0 +1,134 @@
+//! Runs the s3 provider against an in-memory object store, which supports the same conditional
+//! writes as minio or S3 itself.
+#![cfg(feature = "s3")]
+
+use object_store::{memory::InMemory, ObjectStore};
+use std::sync::Arc;
+use thoenix_tofu::{error::Error, S3State, TerraformLock, TerraformStateProvider};
+
+fn lock(id: &str) -> TerraformLock {
+    TerraformLock {
+        id: id.to_string(),
+        operation: "OperationTypeApply".to_string(),
+        info: String::new(),
+        who: "test@localhost".to_string(),
+        version: "1.6.0".to_string(),
+        created: None,
+        path: String::new(),
+        acquired: None,
+    }
+}
+
+fn state(serial: u64) -> String {
+    format!(r#"{{"version":4,"serial":{serial},"lineage":"test"}}"#)
+}
+
+/// Two providers sharing a store, like two servers sharing a bucket
+fn providers() -> (S3State, S3State) {
+    let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+
+    (S3State::new(store.clone()), S3State::new(store))
+}
+
+#[tokio::test]
+async fn lock_is_shared_between_servers() {
+    let (a, b) = providers();
+
+    a.lock_state("network", lock("a")).await.unwrap();
+    assert!(matches!(
+        b.lock_state("network", lock("b")).await,
+        Err(Error::StateLocked)
+    ));
+    assert!(matches!(
+        b.update_state("network", "b", state(1), false).await,
+        Err(Error::StateLocked)
+    ));
+
+    b.update_state("network", "a", state(1), false)
+        .await
+        .unwrap();
+    a.unlock_state("network", &lock("a")).await.unwrap();
+    b.lock_state("network", lock("b")).await.unwrap();
+
+    let current = a.get_state("network").await.unwrap().unwrap();
+    assert_eq!(current.data, state(1));
+    assert_eq!(current.lock_info().unwrap().id, "b");
+}
+
+#[tokio::test]
+async fn concurrent_locks_have_one_winner() {
+    let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+
+    let attempts = (0..16).map(|n| {
+        let provider = S3State::new(store.clone());
+        tokio::spawn(async move { provider.lock_state("network", lock(&n.to_string())).await })
+    });
+    let results = futures::future::join_all(attempts).await;
+
+    let acquired = results
+        .into_iter()
+        .filter(|r| matches!(r, Ok(Ok(()))))
+        .count();
+    assert_eq!(acquired, 1);
+}
+
+#[tokio::test]
+async fn concurrent_updates_keep_every_version() {
+    let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+
+    let updates = (1..=8).map(|n| {
+        let provider = S3State::new(store.clone());
+        tokio::spawn(async move { provider.update_state("network", "", state(n), true).await })
+    });
+    for result in futures::future::join_all(updates).await {
+        result.unwrap().unwrap();
+    }
+
+    let versions = S3State::new(store).list_versions("network").await.unwrap();
+    let numbers = versions.iter().map(|v| v.version).collect::<Vec<_>>();
+    assert_eq!(numbers, (1..=8).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn unlocking_an_unwritten_state_removes_it() {
+    let (a, _) = providers();
+
+    a.lock_state("network", lock("a")).await.unwrap();
+    assert_eq!(a.list_states().await.unwrap().len(), 1);
+
+    a.unlock_state("network", &lock("a")).await.unwrap();
+    assert!(a.get_state("network").await.unwrap().is_none());
+    assert!(a.list_states().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn delete_removes_versions() {
+    let (a, b) = providers();
+
+    a.update_state("network", "", state(1), false)
+        .await
+        .unwrap();
+    a.update_state("network", "", state(2), false)
+        .await
+        .unwrap();
+    b.delete_state("network", "").await.unwrap();
+
+    assert!(a.get_state("network").await.unwrap().is_none());
+    assert!(a.get_version("network", 1).await.unwrap().is_none());
+    assert!(matches!(
+        a.list_versions("network").await,
+        Err(Error::NotFound)
+    ));
+}
+
+#[tokio::test]
+async fn rejects_ids_that_are_not_a_single_path_segment() {
+    let (a, _) = providers();
+
+    for id in ["", "a/b", ".."] {
+        assert!(
+            matches!(a.get_state(id).await, Err(Error::InvalidId(_))),
+            "{id:?} was accepted"
+        );
+    }
+}